@@ -0,0 +1,217 @@
+//! Painting terrains, picking the tile for each cell from a tileset's
+//! terrain sets so the edges between terrains line up by themselves.
+//!
+//! The terrain of a cell isn't stored anywhere: it's worked out from the
+//! tile in it, so maps still hold plain tile indices and cells painted by
+//! hand take part like any other. Cells whose tile isn't in the set are
+//! left alone.
+use history::History;
+use map::{Map, MapLayer};
+use tileset::TerrainSet;
+
+/// The corners of a cell, top-left first going clockwise, as offsets from
+/// its top-left corner.
+const CORNERS: [(i32, i32); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
+
+/// The cells around a cell, starting above it and going clockwise, in the
+/// order of the bits of a blob mask.
+const AROUND: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1),
+                                 (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+/// Paint `terrain` from the terrain set `set` into a cell of a layer,
+/// fixing up the cells around it to match. Returns Err if there's no such
+/// layer, set or terrain.
+pub fn paint(map: &mut Map, history: &mut History, layer: &str, set: &str,
+             terrain: &str, cell: (i32, i32)) -> Result<(), ()> {
+    let tiles = match map.layer(layer) {
+        Some(l) => match l.tileset().terrain_set(set) {
+            Some(set) if set.terrains().contains(&terrain) =>
+                pick(l, set, terrain, cell),
+            _ => return Err(()),
+        },
+        None => return Err(()),
+    };
+    for (cell, tile) in tiles {
+        history.paint(map, layer, cell, tile).ok();
+    }
+    Ok(())
+}
+
+/// The tiles to paint to put `terrain` in a cell, and the cells to paint
+/// them into.
+fn pick(layer: &MapLayer, set: &TerrainSet, terrain: &str, cell: (i32, i32))
+        -> Vec<((i32, i32), u32)> {
+    match *set {
+        TerrainSet::Wang { ref tiles, .. } =>
+            pick_wang(layer, tiles, terrain, cell),
+        TerrainSet::Blob { ref tiles, .. } => pick_blob(layer, tiles, cell),
+    }
+}
+
+fn pick_wang(layer: &MapLayer, tiles: &[(u32, [String; 4])], terrain: &str,
+             (x, y): (i32, i32)) -> Vec<((i32, i32), u32)> {
+    let corners_of = |tile: u32| tiles.iter()
+        .find(|&&(t, _)| t == tile)
+        .map(|&(_, ref corners)| corners);
+
+    let mut result = Vec::new();
+    for dy in -1..2 {
+        for dx in -1..2 {
+            let at = (x + dx, y + dy);
+            let mut wanted: Vec<&str> = if (dx, dy) == (0, 0) {
+                vec![terrain; 4]
+            } else {
+                match corners_of(layer.tile(at)) {
+                    Some(corners) => corners.iter().map(|c| &c[..]).collect(),
+                    None => continue,
+                }
+            };
+            // Corners shared with the painted cell take on its terrain
+            for (i, &(cx, cy)) in CORNERS.iter().enumerate() {
+                let (vx, vy) = (dx + cx, dy + cy);
+                if vx >= 0 && vx <= 1 && vy >= 0 && vy <= 1 {
+                    wanted[i] = terrain;
+                }
+            }
+            // The tile matching the most corners, the first if it's a tie
+            let best = tiles.iter().rev().max_by_key(|&&(_, ref corners)| {
+                corners.iter().zip(&wanted).filter(|&(a, b)| a == b).count()
+            });
+            if let Some(&(tile, _)) = best {
+                result.push((at, tile));
+            }
+        }
+    }
+    result
+}
+
+fn pick_blob(layer: &MapLayer, tiles: &[(u32, u8)], (x, y): (i32, i32))
+        -> Vec<((i32, i32), u32)> {
+    let painted = |at: (i32, i32)| {
+        at == (x, y) || tiles.iter().any(|&(t, _)| t == layer.tile(at))
+    };
+
+    let mut result = Vec::new();
+    for dy in -1..2 {
+        for dx in -1..2 {
+            let at = (x + dx, y + dy);
+            if !painted(at) {
+                continue;
+            }
+            let mut mask = 0u8;
+            for (bit, &(ax, ay)) in AROUND.iter().enumerate() {
+                if painted((at.0 + ax, at.1 + ay)) {
+                    mask |= 1 << bit;
+                }
+            }
+            // Corners only count if both cells beside them do
+            for corner in &[1, 3, 5, 7] {
+                let sides = (1 << (corner - 1)) | (1 << ((corner + 1) % 8));
+                if mask & sides != sides {
+                    mask &= !(1 << corner);
+                }
+            }
+            // The tile for the nearest mask, the first if it's a tie
+            let best = tiles.iter().min_by_key(|&&(_, m)| {
+                (m ^ mask).count_ones()
+            });
+            if let Some(&(tile, _)) = best {
+                result.push((at, tile));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+fn test_map(set: TerrainSet, fill: u32) -> Map {
+    use tileset::Tileset;
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.add_terrain_set(set);
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset, 4, vec![fill; 16]));
+    map
+}
+
+/// Every combination of grass and water corners, numbered by which corners
+/// are water: 1 for the top-left, 2 for the top-right and so on.
+#[cfg(test)]
+fn shore() -> TerrainSet {
+    let corner = |tile: u32, i: u32| {
+        if tile & (1 << i) != 0 { "water" } else { "grass" }.to_string()
+    };
+    TerrainSet::Wang {
+        name: "shore".into(),
+        tiles: (0..16).map(|t| {
+            (t, [corner(t, 0), corner(t, 1), corner(t, 2), corner(t, 3)])
+        }).collect(),
+    }
+}
+
+#[test]
+fn wang_terrains_fix_up_their_neighbours() {
+    let mut map = test_map(shore(), 0);
+    let mut history = History::new(10);
+    paint(&mut map, &mut history, "ground", "shore", "water", (1, 1))
+        .unwrap();
+    assert_eq!(map.layer("ground").unwrap().tiles(),
+               vec![4, 12,  8, 0,
+                    6, 15,  9, 0,
+                    2,  3,  1, 0,
+                    0,  0,  0, 0]);
+
+    // A second cell of water joins up with the first
+    paint(&mut map, &mut history, "ground", "shore", "water", (2, 1))
+        .unwrap();
+    assert_eq!(map.layer("ground").unwrap().tiles(),
+               vec![4, 12, 12,  8,
+                    6, 15, 15,  9,
+                    2,  3,  3,  1,
+                    0,  0,  0,  0]);
+
+    // Painting grass back takes the water away again
+    paint(&mut map, &mut history, "ground", "shore", "grass", (2, 1))
+        .unwrap();
+    paint(&mut map, &mut history, "ground", "shore", "grass", (1, 1))
+        .unwrap();
+    assert_eq!(map.layer("ground").unwrap().tiles(), vec![0; 16]);
+}
+
+#[test]
+fn blob_terrains_follow_the_cells_around() {
+    // A tile for every mask, numbered by its mask, plus 300 for the ground
+    let set = TerrainSet::Blob {
+        name: "cliffs".into(),
+        terrain: "cliff".into(),
+        tiles: (0..256).map(|m| (m, m as u8)).collect(),
+    };
+    let mut map = test_map(set, 300);
+    let mut history = History::new(10);
+    paint(&mut map, &mut history, "ground", "cliffs", "cliff", (1, 1))
+        .unwrap();
+    assert_eq!(map.layer("ground").unwrap().tile((1, 1)), 0);
+
+    paint(&mut map, &mut history, "ground", "cliffs", "cliff", (2, 1))
+        .unwrap();
+    paint(&mut map, &mut history, "ground", "cliffs", "cliff", (2, 2))
+        .unwrap();
+    let layer = map.layer("ground").unwrap();
+    // East; west and south; north only, since the corner to its north-west
+    // doesn't count without a cell to its west
+    assert_eq!(layer.tile((1, 1)), 4);
+    assert_eq!(layer.tile((2, 1)), 16 | 64);
+    assert_eq!(layer.tile((2, 2)), 1);
+    assert_eq!(layer.tile((3, 3)), 300);
+}
+
+#[test]
+fn painting_an_unknown_terrain_is_an_error() {
+    let mut map = test_map(shore(), 0);
+    let mut history = History::new(10);
+    assert!(paint(&mut map, &mut history, "ground", "shore", "lava", (0, 0))
+        .is_err());
+    assert!(paint(&mut map, &mut history, "ground", "sand", "water", (0, 0))
+        .is_err());
+    assert!(paint(&mut map, &mut history, "sky", "shore", "water", (0, 0))
+        .is_err());
+}
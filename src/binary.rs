@@ -0,0 +1,571 @@
+//! A compact binary encoding of maps, for big maps where the JSON format
+//! gets slow and bulky. Maps are stored in it when their file name ends in
+//! `.tmap`.
+//!
+//! All numbers are little endian. A file is laid out as:
+//!
+//!   - a header: the magic bytes `TMAP`, the format version (u32), the
+//!     number of tile layers (u32), and the offset and length (u32 each) of
+//!     the object layers
+//!   - a table with an entry for each tile layer, bottom first: its name,
+//!     z-index (i32), visibility (u8), tileset asset, tile width and height
+//!     (u32 each), bounds (i32 x and y, u32 width and height in tiles), and
+//!     number of chunks (u32), followed by an entry for each chunk: its
+//!     coordinates (i32 each), and the compression (u8), offset and length
+//!     (u32 each) of its tiles
+//!   - the data: the tiles of each chunk, one u32 per cell, compressed, and
+//!     the object layers, as deflated JSON, since they're small and
+//!     free-form
+//!
+//! Offsets count from the start of the data. Strings are a u16 length
+//! followed by that many bytes of UTF-8.
+//!
+//! Version 1 stored each layer as a single grid with its corner at the
+//! origin: the table had its width and height where the bounds are now,
+//! and the compression, offset and length of all its tiles in place of
+//! the chunks. Offsets counted from the start of the file.
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use map::{Bounds, CHUNK_SIZE, Map, MapLayer, ObjectLayer, REACH,
+          chunk_bounds};
+use serde_json;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::{Read, Write, self};
+use std::usize;
+use tileset::Tileset;
+
+/// The file extension that picks this format.
+pub const EXTENSION: &'static str = "tmap";
+
+const MAGIC: &'static [u8] = b"TMAP";
+
+/// The version of the binary format this build writes.
+const VERSION: u32 = 2;
+
+/// The most the object layers may inflate to, in bytes.
+const MAX_OBJECTS: usize = 16 << 20;
+
+// How a layer's tiles are compressed
+const RAW: u8 = 0;
+const RUN_LENGTH: u8 = 1;
+const DEFLATE: u8 = 2;
+
+fn invalid<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("binary map: {}", message.into()))
+}
+
+fn out_of_reach(layer: &str) -> io::Error {
+    invalid(format!("layer `{}` reaches further than {} cells from the \
+                     origin", layer, REACH))
+}
+
+fn put_u8(out: &mut Vec<u8>, n: u8) {
+    out.push(n);
+}
+
+fn put_u16(out: &mut Vec<u8>, n: u16) {
+    out.push(n as u8);
+    out.push((n >> 8) as u8);
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    for i in 0..4 {
+        out.push((n >> (i * 8)) as u8);
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    if s.len() > ::std::u16::MAX as usize {
+        return Err(invalid(format!("a {} byte string is too long", s.len())));
+    }
+    put_u16(out, s.len() as u16);
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// Reads values back out of an encoded map.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "binary map: file is truncated"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = try!(self.take(2));
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(le_u32(try!(self.take(4))))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(try!(self.u32()) as i32)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = try!(self.u16()) as usize;
+        String::from_utf8(try!(self.take(len)).to_vec())
+            .map_err(|_| invalid("a string is not valid UTF-8"))
+    }
+
+    /// The `len` bytes at `offset` from `base`.
+    fn section(&self, base: usize, offset: u32, len: u32)
+            -> io::Result<&'a [u8]> {
+        let (offset, len) = (base + offset as usize, len as usize);
+        if offset > self.bytes.len() || self.bytes.len() - offset < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "binary map: file is truncated"));
+        }
+        Ok(&self.bytes[offset..offset + len])
+    }
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Inflate at most `limit` bytes; any more is an error, rather than
+/// trusting a file that claims more than it should.
+fn inflate(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(DeflateDecoder::new(bytes).take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| invalid(format!("bad compressed data: {}", e))));
+    if out.len() > limit {
+        return Err(invalid(format!(
+            "compressed data inflates to more than {} bytes", limit)));
+    }
+    Ok(out)
+}
+
+/// Runs of identical tiles, as (count, tile) pairs.
+fn run_length_encode(tiles: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < tiles.len() {
+        let run = tiles[i..].iter().take_while(|&&t| t == tiles[i]).count();
+        put_u32(&mut out, run as u32);
+        put_u32(&mut out, tiles[i]);
+        i += run;
+    }
+    out
+}
+
+/// Decode runs of tiles, failing as soon as they add up to more than
+/// `expected`.
+fn run_length_decode(bytes: &[u8], expected: usize) -> io::Result<Vec<u32>> {
+    if bytes.len() % 8 != 0 {
+        return Err(invalid("run-length data is not a whole number of runs"));
+    }
+    let mut tiles = Vec::new();
+    for run in bytes.chunks(8) {
+        let (count, tile) = (le_u32(&run[..4]), le_u32(&run[4..]));
+        if count as u64 > (expected - tiles.len()) as u64 {
+            return Err(invalid(format!(
+                "run-length data has more than {} tiles", expected)));
+        }
+        tiles.extend(::std::iter::repeat(tile).take(count as usize));
+    }
+    Ok(tiles)
+}
+
+/// Compress tiles whichever way comes out smallest.
+fn compress(tiles: &[u32]) -> (u8, Vec<u8>) {
+    let mut raw = Vec::with_capacity(tiles.len() * 4);
+    for &t in tiles {
+        put_u32(&mut raw, t);
+    }
+    let candidates = vec![
+        (RUN_LENGTH, run_length_encode(tiles)),
+        (DEFLATE, deflate(&raw)),
+        (RAW, raw),
+    ];
+    candidates.into_iter().min_by_key(|c| c.1.len()).unwrap()
+}
+
+/// Decompress tile data that should hold `expected` tiles. It's an error
+/// if there are more, found before they're all decompressed.
+fn decompress(compression: u8, bytes: &[u8], expected: usize)
+        -> io::Result<Vec<u32>> {
+    let limit = expected.saturating_mul(4);
+    let raw = match compression {
+        RAW => bytes.to_vec(),
+        RUN_LENGTH => return run_length_decode(bytes, expected),
+        DEFLATE => try!(inflate(bytes, limit)),
+        other => return Err(invalid(format!(
+            "unknown tile compression {}", other))),
+    };
+    if raw.len() % 4 != 0 {
+        return Err(invalid("tile data is not a whole number of tiles"));
+    }
+    if raw.len() > limit {
+        return Err(invalid(format!(
+            "tile data has more than {} tiles", expected)));
+    }
+    Ok(raw.chunks(4).map(le_u32).collect())
+}
+
+/// Encode a map in the binary format.
+pub fn encode(map: &Map) -> io::Result<Vec<u8>> {
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    for l in map.layers() {
+        let layer = l.layer();
+        let (tile_w, tile_h) = layer.tileset().tile_size();
+        let bounds = layer.bounds();
+        try!(put_str(&mut table, l.name()));
+        put_u32(&mut table, l.z_index() as u32);
+        put_u8(&mut table, l.visible() as u8);
+        try!(put_str(&mut table, layer.tileset().asset()));
+        put_u32(&mut table, tile_w);
+        put_u32(&mut table, tile_h);
+        put_u32(&mut table, bounds.x as u32);
+        put_u32(&mut table, bounds.y as u32);
+        put_u32(&mut table, bounds.width);
+        put_u32(&mut table, bounds.height);
+        put_u32(&mut table, layer.chunks().len() as u32);
+        for (&(x, y), tiles) in layer.chunks() {
+            let (compression, bytes) = compress(tiles);
+            put_u32(&mut table, x as u32);
+            put_u32(&mut table, y as u32);
+            put_u8(&mut table, compression);
+            put_u32(&mut table, data.len() as u32);
+            put_u32(&mut table, bytes.len() as u32);
+            data.extend_from_slice(&bytes);
+        }
+    }
+
+    let objects = deflate(&try!(serde_json::to_vec(map.object_layers())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))));
+    let objects_at = data.len();
+    data.extend_from_slice(&objects);
+
+    let mut out = Vec::with_capacity(MAGIC.len() + 4 * 4 + table.len() +
+                                     data.len());
+    out.extend_from_slice(MAGIC);
+    put_u32(&mut out, VERSION);
+    put_u32(&mut out, map.layers().len() as u32);
+    put_u32(&mut out, objects_at as u32);
+    put_u32(&mut out, objects.len() as u32);
+    out.extend_from_slice(&table);
+    out.extend_from_slice(&data);
+    Ok(out)
+}
+
+/// Where a layer's tiles are, as read from the table. The data can't be
+/// found until the end of the table is.
+enum Stored {
+    // Version 1: (width, height, compression, offset, length)
+    Grid(u32, u32, u8, u32, u32),
+    // (bounds, [(coordinates, compression, offset, length)])
+    Chunks(Bounds, Vec<((i32, i32), u8, u32, u32)>),
+}
+
+/// Decode a map from the binary format.
+pub fn decode(bytes: &[u8]) -> io::Result<Map> {
+    let mut r = Reader { bytes: bytes, pos: 0 };
+    if try!(r.take(MAGIC.len())) != MAGIC {
+        return Err(invalid("not a binary map"));
+    }
+    let version = try!(r.u32());
+    if version > VERSION {
+        return Err(invalid(format!(
+            "map is in version {} of the format, but this build only \
+             understands versions up to {}; it was probably saved by a newer \
+             build", version, VERSION)));
+    }
+
+    let count = try!(r.u32());
+    let (objects_at, objects_len) = (try!(r.u32()), try!(r.u32()));
+
+    let mut layers = Vec::new();
+    for _ in 0..count {
+        let name = try!(r.string());
+        let z_index = try!(r.i32());
+        let visible = try!(r.u8()) != 0;
+        let asset = try!(r.string());
+        let tile_size = (try!(r.u32()), try!(r.u32()));
+        let stored = if version < 2 {
+            let (width, height) = (try!(r.u32()), try!(r.u32()));
+            Stored::Grid(width, height, try!(r.u8()), try!(r.u32()),
+                         try!(r.u32()))
+        } else {
+            let bounds = Bounds::new(try!(r.i32()), try!(r.i32()),
+                                     try!(r.u32()), try!(r.u32()));
+            let mut chunks = Vec::new();
+            for _ in 0..try!(r.u32()) {
+                chunks.push(((try!(r.i32()), try!(r.i32())), try!(r.u8()),
+                             try!(r.u32()), try!(r.u32())));
+            }
+            Stored::Chunks(bounds, chunks)
+        };
+        layers.push((name, z_index, visible, asset, tile_size, stored));
+    }
+    let base = if version < 2 { 0 } else { r.pos };
+
+    let mut map = Map::new();
+    for (name, z_index, visible, asset, tile_size, stored) in layers {
+        let tileset = try!(Tileset::load_or_strip(&asset, tile_size));
+        let layer = match stored {
+            Stored::Grid(width, height, compression, at, len) => {
+                if !Bounds::new(0, 0, width, height).within_reach() {
+                    return Err(out_of_reach(&name));
+                }
+                let cells = width as u64 * height as u64;
+                let tiles = try!(decompress(compression,
+                                            try!(r.section(base, at, len)),
+                                            min(cells, usize::MAX as u64)
+                                                as usize));
+                if tiles.len() as u64 != cells {
+                    return Err(invalid(format!(
+                        "layer `{}` has {} tiles but should be {}x{}",
+                        name, tiles.len(), width, height)));
+                }
+                MapLayer::new(tileset, width, tiles)
+            },
+            Stored::Chunks(bounds, chunks) => {
+                if !bounds.within_reach() {
+                    return Err(out_of_reach(&name));
+                }
+                let mut tiles = BTreeMap::new();
+                for (key, compression, at, len) in chunks {
+                    if !chunk_bounds(key).within_reach() {
+                        return Err(out_of_reach(&name));
+                    }
+                    let chunk = try!(decompress(
+                        compression, try!(r.section(base, at, len)),
+                        (CHUNK_SIZE * CHUNK_SIZE) as usize));
+                    if chunk.len() != (CHUNK_SIZE * CHUNK_SIZE) as usize {
+                        return Err(invalid(format!(
+                            "chunk {},{} of layer `{}` has {} tiles rather \
+                             than {}", key.0, key.1, name, chunk.len(),
+                            CHUNK_SIZE * CHUNK_SIZE)));
+                    }
+                    tiles.insert(key, chunk);
+                }
+                MapLayer::from_chunks(tileset, bounds, tiles)
+            },
+        };
+        map.push(&name, z_index, layer);
+        if !visible {
+            map.hide_top();
+        }
+    }
+
+    let objects = try!(r.section(base, objects_at, objects_len));
+    let objects: Vec<ObjectLayer> = try!(serde_json::from_slice(
+        &try!(inflate(objects, MAX_OBJECTS))).map_err(|e| invalid(
+            e.to_string())));
+    for layer in objects {
+        map.push_objects(layer);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+use map::EMPTY;
+#[cfg(test)]
+use map::Object;
+
+#[cfg(test)]
+fn test_map() -> Map {
+    let tileset = Tileset::strip("grass", (16, 16));
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset.clone(), 4,
+        (0..32).map(|i| i * 7 % 5).collect()));
+    map.push("overhead", 1, MapLayer::new(tileset.clone(), 4,
+        vec![EMPTY; 32]));
+    map.set_visible("overhead", false).unwrap();
+    map.layer_mut("ground").unwrap().set_px((-100, -300), 12);
+    map.push_objects(ObjectLayer {
+        name: "things".into(),
+        objects: vec![Object {
+            name: "starman".into(),
+            kind: "npc".into(),
+            x: -20,
+            y: 8,
+            width: 16,
+            height: 24,
+            properties: BTreeMap::new(),
+        }],
+    });
+    map
+}
+
+#[test]
+fn binary_round_trips() {
+    let map = test_map();
+    let bytes = encode(&map).unwrap();
+    assert_eq!(&bytes[..4], b"TMAP");
+    assert_eq!(decode(&bytes).unwrap().serialize(), map.serialize());
+}
+
+#[test]
+fn version_1_still_loads() {
+    // A 2x1 grid of raw tiles, then no objects
+    let objects = deflate(b"[]");
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"TMAP");
+    for &n in &[1, 1, 73, objects.len() as u32] {
+        put_u32(&mut bytes, n);
+    }
+    put_str(&mut bytes, "ground").unwrap();
+    put_u32(&mut bytes, -1i32 as u32);
+    put_u8(&mut bytes, 1);
+    put_str(&mut bytes, "grass").unwrap();
+    for &n in &[16, 16, 2, 1] {
+        put_u32(&mut bytes, n);
+    }
+    put_u8(&mut bytes, RAW);
+    put_u32(&mut bytes, 65);
+    put_u32(&mut bytes, 8);
+    assert_eq!(bytes.len(), 65);
+    put_u32(&mut bytes, 3);
+    put_u32(&mut bytes, 4);
+    bytes.extend_from_slice(&objects);
+
+    let mut expected = Map::new();
+    expected.push("ground", -1, MapLayer::new(Tileset::strip("grass", (16, 16)),
+                                              2, vec![3, 4]));
+    assert_eq!(decode(&bytes).unwrap().serialize(), expected.serialize());
+}
+
+#[test]
+fn compression_picks_the_smallest() {
+    let uniform = vec![EMPTY; 1000];
+    let (compression, data) = compress(&uniform);
+    assert_eq!((compression, data.len()), (RUN_LENGTH, 8));
+
+    // Nothing beats four bytes for a single tile
+    let single = vec![0x12345678];
+    assert_eq!(compress(&single).0, RAW);
+
+    // A repeating pattern has no runs, but deflates well
+    let pattern: Vec<u32> = (0..1000).map(|i| i % 7).collect();
+    assert_eq!(compress(&pattern).0, DEFLATE);
+
+    for tiles in &[uniform, single, pattern] {
+        let (compression, data) = compress(tiles);
+        assert_eq!(&decompress(compression, &data, tiles.len()).unwrap(),
+                   tiles);
+    }
+}
+
+#[test]
+fn decompressing_stops_at_the_expected_size() {
+    // A single run claiming four billion tiles
+    let mut runs = Vec::new();
+    put_u32(&mut runs, 0xffffffff);
+    put_u32(&mut runs, 7);
+    let e = decompress(RUN_LENGTH, &runs, 16).err().unwrap();
+    assert!(e.to_string().contains("more than 16 tiles"), "{}", e);
+
+    let zeros = vec![0; 1000];
+    let (compression, data) = compress(&zeros);
+    assert!(decompress(compression, &data, 999).is_err());
+    let mut raw = Vec::new();
+    put_u32(&mut raw, 0);
+    put_u32(&mut raw, 0);
+    assert!(decompress(DEFLATE, &deflate(&raw), 1).is_err());
+    assert!(decompress(RAW, &raw, 1).is_err());
+}
+
+#[test]
+fn bad_files_are_errors() {
+    let bytes = encode(&test_map()).unwrap();
+
+    let e = decode(&bytes[..bytes.len() - 1]).err().unwrap();
+    assert!(e.to_string().contains("truncated"), "{}", e);
+
+    let e = decode(b"PNG!").err().unwrap();
+    assert!(e.to_string().contains("not a binary map"), "{}", e);
+
+    let mut newer = bytes.clone();
+    newer[4] = VERSION as u8 + 1;
+    let e = decode(&newer).err().unwrap();
+    assert!(e.to_string().contains("only understands versions up to"),
+            "{}", e);
+}
+
+/// Compare the binary format with JSON on a big overworld-sized map. Run it
+/// with `cargo test --release -- --ignored --nocapture benchmark`.
+#[test]
+#[ignore]
+fn benchmark_binary_against_json() {
+    use std::time::Instant;
+
+    fn millis(start: Instant) -> f64 {
+        let d = start.elapsed();
+        d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+    }
+
+    // Four 512x512 layers: varied ground, and sparser layers over it
+    let (w, h) = (512, 512);
+    let tileset = Tileset::strip("assets/cotp", (16, 16));
+    let mut map = Map::new();
+    let cell = |i: u32, density: u32| {
+        let hash = i.wrapping_mul(2654435761) >> 16;
+        if hash % 100 < density { hash % 72 } else { EMPTY }
+    };
+    for (n, &(name, density)) in [("ground", 100), ("detail", 30),
+                                  ("decoration", 10), ("overhead", 3)]
+                                 .iter().enumerate() {
+        let tiles = (0..w * h).map(|i| cell(i + n as u32, density)).collect();
+        map.push(name, n as i32 - 1, MapLayer::new(tileset.clone(), w, tiles));
+    }
+
+    let runs = 5;
+    let start = Instant::now();
+    let mut json = String::new();
+    for _ in 0..runs {
+        json = map.serialize();
+    }
+    let json_save = millis(start) / runs as f64;
+    let start = Instant::now();
+    for _ in 0..runs {
+        Map::deserialize(&json).unwrap();
+    }
+    let json_load = millis(start) / runs as f64;
+
+    let start = Instant::now();
+    let mut binary = Vec::new();
+    for _ in 0..runs {
+        binary = encode(&map).unwrap();
+    }
+    let binary_save = millis(start) / runs as f64;
+    let start = Instant::now();
+    for _ in 0..runs {
+        decode(&binary).unwrap();
+    }
+    let binary_load = millis(start) / runs as f64;
+
+    println!("{} layers of {}x{} tiles:", map.layers().len(), w, h);
+    println!("JSON:\t{:>9} bytes\tsave: {:.2} ms\tload: {:.2} ms",
+             json.len(), json_save, json_load);
+    println!("Binary:\t{:>9} bytes\tsave: {:.2} ms\tload: {:.2} ms",
+             binary.len(), binary_save, binary_load);
+}
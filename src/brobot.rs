@@ -1,9 +1,11 @@
 use carboxyl::{Signal, Stream};
+use collision::{Aabb, CollisionMap};
 use event::IOEvent;
 use scene::Tile;
 use sdl2::keyboard::Keycode;
+use tileset::Tileset;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Direction {
     Down,
     Left,
@@ -49,13 +51,34 @@ fn samsara(impulse: Impulse, event: IOEvent) -> Impulse {
     }
 }
 
-/// Create a visible sprite that moves according to player input. The output
-/// is a pair of signals, one containing position, and one containing the
-/// visible presentation of the sprite.
+/// The part of a `w` by `h` sprite at `(x, y)` that collides: only its
+/// feet, so it can stand with its head in front of a wall, the way top-down
+/// games usually fake depth.
+/// XXX: hardcoded for sprites of roughly Porky's proportions
+pub fn feet((x, y): (f32, f32), w: u32, h: u32) -> Aabb {
+    Aabb::new(x + 2.0, y + h as f32 - 8.0, w as f32 - 4.0, 8.0)
+}
+
+/// What moves the sprite: time passing, or being picked up and put down
+/// somewhere else.
+#[derive(Copy, Clone)]
+enum Step {
+    Walk(f32),
+    Warp((f32, f32)),
+}
+
+/// Create a visible sprite that moves according to player input, stopping
+/// against the solid cells of `collision`. Anything sent down `warps` puts
+/// it straight at that position, facing that way if there is one, until
+/// the player turns it. The output is a pair of signals, one containing
+/// position, and one containing the visible presentation of the sprite.
 pub fn controlled_sprite(asset: &str, w: u32, h: u32, x: i32, y: i32,
             keyboard: Stream<IOEvent>,
             time: Signal<f32>,
-            time_delta: Stream<f32>) -> (Signal<(f32, f32)>, Signal<Tile>) {
+            time_delta: Stream<f32>,
+            collision: Signal<CollisionMap>,
+            warps: Stream<((f32, f32), Option<Direction>)>)
+            -> (Signal<(f32, f32)>, Signal<Tile>) {
     // First, transform keyboard events into a time-varying impulse signal
     let impulse = keyboard.fold(Impulse::nirvana(), samsara);
 
@@ -79,7 +102,17 @@ pub fn controlled_sprite(asset: &str, w: u32, h: u32, x: i32, y: i32,
             _ => Direction::Down  // XXX: should have Direction::Mu
         }
     }
-    let direction = lift!(decisiveness, &whimsy, &impulse);
+
+    // A warp can turn us to face a given way, until the next key does
+    let facing = keyboard.map(|_| None)
+        .merge(&warps.map(|(_, facing)| facing))
+        .hold(None);
+    let direction = lift!(|whimsy, impulse, facing| {
+        match facing {
+            Some(facing) => facing,
+            None => decisiveness(whimsy, impulse),
+        }
+    }, &whimsy, &impulse, &facing);
 
     // Now, actual position can be represented as a cyclic signal
     // folding impulse over time.
@@ -91,22 +124,30 @@ pub fn controlled_sprite(asset: &str, w: u32, h: u32, x: i32, y: i32,
 
     let position = {
         let impulse = impulse.clone();
-        time_delta.fold(initial_position, move |pos, dt| {
+        let steps = time_delta.map(Step::Walk)
+            .merge(&warps.map(|(to, _)| Step::Warp(to)));
+        steps.fold(initial_position, move |pos, step| {
+            let dt = match step {
+                Step::Walk(dt) => dt,
+                Step::Warp(to) => return to,
+            };
             let impulse = impulse.sample();
-            let (mut x, mut y) = pos;
+            let (mut dx, mut dy) = (0.0, 0.0);
             if impulse.left {
-                x -= dt * speed;
+                dx -= dt * speed;
             }
             if impulse.right {
-                x += dt * speed;
+                dx += dt * speed;
             }
             if impulse.up {
-                y -= dt * speed;
+                dy -= dt * speed;
             }
             if impulse.down {
-                y += dt * speed;
+                dy += dt * speed;
             }
-            (x, y)
+            let from = feet(pos, w, h);
+            let to = collision.sample().sweep(from, (dx, dy));
+            (pos.0 + to.x - from.x, pos.1 + to.y - from.y)
         })
     };
 
@@ -115,7 +156,7 @@ pub fn controlled_sprite(asset: &str, w: u32, h: u32, x: i32, y: i32,
     let render = {
         // XXX: figure out the units here, this is clearly not steps per sec
         let steps_per_second = 16.0;
-        let asset = asset.to_string();
+        let frames = Tileset::strip(asset, (w, h));
         lift!(move |time, impulse, direction, position| {
             let mut frame = match direction {
                 Direction::Down => 0,
@@ -131,7 +172,7 @@ pub fn controlled_sprite(asset: &str, w: u32, h: u32, x: i32, y: i32,
                 }
             }
             let (x, y) = position;
-            Tile::new(&asset, frame, w, h, x as i32, y as i32)
+            Tile::new(&frames, frame, x as i32, y as i32)
         },
         &time, &impulse, &direction, &position)
     };
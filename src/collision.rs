@@ -0,0 +1,206 @@
+//! Collision between moving boxes and the solid tiles of a map.
+use map::Bounds;
+use std::collections::HashSet;
+
+/// An axis-aligned box, in world pixels.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Aabb {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Aabb {
+    pub fn new(x: f32, y: f32, w: f32, h: f32) -> Aabb {
+        Aabb { x: x, y: y, w: w, h: h }
+    }
+
+    /// Whether the two boxes share any area; touching edges don't count.
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        self.x < other.x + other.w && other.x < self.x + self.w &&
+            self.y < other.y + other.h && other.y < self.y + self.h
+    }
+}
+
+/// Which cells of a tile grid block movement. Everything outside the
+/// bounds of the grid is solid, so nothing can leave the map.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CollisionMap {
+    tile_w: u32,
+    tile_h: u32,
+    bounds: Bounds,
+    // Only the solid cells, since most of a map is usually open
+    solid: HashSet<(i32, i32)>,
+}
+
+impl CollisionMap {
+    pub fn new((tw, th): (u32, u32), bounds: Bounds,
+               solid: HashSet<(i32, i32)>) -> CollisionMap {
+        CollisionMap {
+            tile_w: tw,
+            tile_h: th,
+            bounds: bounds,
+            solid: solid,
+        }
+    }
+
+    /// Make every cell that is solid in `other` solid in this map too, and
+    /// grow the bounds to cover both. Both maps must have the same tile
+    /// size, which maps check for when they're loaded.
+    pub fn merge(&mut self, other: &CollisionMap) {
+        assert_eq!((self.tile_w, self.tile_h), (other.tile_w, other.tile_h));
+        self.bounds = self.bounds.union(&other.bounds);
+        self.solid.extend(other.solid.iter().cloned());
+    }
+
+    /// The width and height of a cell, in pixels.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (self.tile_w, self.tile_h)
+    }
+
+    /// Whether the cell at column `x`, row `y` blocks movement.
+    pub fn is_solid(&self, cell: (i32, i32)) -> bool {
+        !self.bounds.contains(cell) || self.solid.contains(&cell)
+    }
+
+    /// Move a box by `(dx, dy)`, stopping it against any solid cells in the
+    /// way. The axes are resolved separately, x first, so a box pushed
+    /// diagonally into a wall slides along it.
+    ///
+    /// Cells the box already overlaps are ignored, so a box that finds a
+    /// wall built on top of it can still walk out.
+    pub fn sweep(&self, aabb: Aabb, (dx, dy): (f32, f32)) -> Aabb {
+        let tw = self.tile_w as f32;
+        let th = self.tile_h as f32;
+
+        let x = {
+            let rows = span(aabb.y, aabb.h, th);
+            sweep_axis(aabb.x, aabb.w, dx, tw, |col| {
+                rows.clone().any(|row| self.is_solid((col, row)))
+            })
+        };
+        let y = {
+            let cols = span(x, aabb.w, tw);
+            sweep_axis(aabb.y, aabb.h, dy, th, |row| {
+                cols.clone().any(|col| self.is_solid((col, row)))
+            })
+        };
+
+        Aabb { x: x, y: y, ..aabb }
+    }
+}
+
+/// The range of cells of size `size` overlapped by the interval starting at
+/// `pos` with length `len`.
+fn span(pos: f32, len: f32, size: f32) -> ::std::ops::Range<i32> {
+    (pos / size).floor() as i32..((pos + len) / size).ceil() as i32
+}
+
+/// Move the interval starting at `pos` with length `len` by `delta` along
+/// one axis, visiting each line of cells it enters in order and stopping at
+/// the first one for which `blocked` is true. Returns the new start.
+fn sweep_axis<F>(pos: f32, len: f32, delta: f32, size: f32, blocked: F)
+        -> f32 where F: Fn(i32) -> bool {
+    if delta > 0.0 {
+        let first = ((pos + len) / size).ceil() as i32;
+        let last = ((pos + len + delta) / size).ceil() as i32;
+        for cell in first..last {
+            if blocked(cell) {
+                return cell as f32 * size - len;
+            }
+        }
+    } else if delta < 0.0 {
+        let first = (pos / size).floor() as i32;
+        let last = ((pos + delta) / size).floor() as i32;
+        for cell in (last..first).rev() {
+            if blocked(cell) {
+                return (cell + 1) as f32 * size;
+            }
+        }
+    }
+    pos + delta
+}
+
+#[cfg(test)]
+fn test_map() -> CollisionMap {
+    // A 4x4 room of 16px cells with one pillar in it:
+    //
+    //   . . . .
+    //   . # . .
+    //   . . . .
+    //   . . . .
+    let solid = vec![(1, 1)].into_iter().collect();
+    CollisionMap::new((16, 16), Bounds::new(0, 0, 4, 4), solid)
+}
+
+#[test]
+fn off_map_is_solid() {
+    let map = test_map();
+    assert!(!map.is_solid((0, 0)));
+    assert!(map.is_solid((1, 1)));
+    assert!(map.is_solid((-1, 0)));
+    assert!(map.is_solid((0, 4)));
+    assert!(map.is_solid((4, 0)));
+}
+
+#[test]
+fn sweep_moves_freely_in_open_space() {
+    let map = test_map();
+    let moved = map.sweep(Aabb::new(36.0, 40.0, 8.0, 8.0), (3.5, 2.0));
+    assert_eq!(moved, Aabb::new(39.5, 42.0, 8.0, 8.0));
+}
+
+#[test]
+fn sweep_stops_against_walls() {
+    let map = test_map();
+
+    // Walking right into the pillar from the left
+    let moved = map.sweep(Aabb::new(2.0, 20.0, 8.0, 8.0), (20.0, 0.0));
+    assert_eq!(moved, Aabb::new(8.0, 20.0, 8.0, 8.0));
+
+    // Walking up into it from below
+    let moved = map.sweep(Aabb::new(20.0, 40.0, 8.0, 8.0), (0.0, -20.0));
+    assert_eq!(moved, Aabb::new(20.0, 32.0, 8.0, 8.0));
+
+    // Walking left off the edge of the map
+    let moved = map.sweep(Aabb::new(3.0, 40.0, 8.0, 8.0), (-5.0, 0.0));
+    assert_eq!(moved, Aabb::new(0.0, 40.0, 8.0, 8.0));
+}
+
+#[test]
+fn sweep_does_not_tunnel() {
+    let map = test_map();
+    // One big step that would land clean past the pillar
+    let moved = map.sweep(Aabb::new(0.0, 20.0, 8.0, 8.0), (40.0, 0.0));
+    assert_eq!(moved, Aabb::new(8.0, 20.0, 8.0, 8.0));
+}
+
+#[test]
+fn sweep_slides_along_walls() {
+    let map = test_map();
+    // Pushing diagonally into the pillar's left face keeps moving down
+    let moved = map.sweep(Aabb::new(6.0, 16.0, 8.0, 8.0), (4.0, 4.0));
+    assert_eq!(moved, Aabb::new(8.0, 20.0, 8.0, 8.0));
+}
+
+#[test]
+fn sweep_ignores_cells_already_overlapped() {
+    let map = test_map();
+    let moved = map.sweep(Aabb::new(20.0, 20.0, 8.0, 8.0), (-6.0, 0.0));
+    assert_eq!(moved, Aabb::new(14.0, 20.0, 8.0, 8.0));
+}
+
+#[test]
+fn merge_covers_both_maps() {
+    let mut map = test_map();
+    let west = vec![(-2, 0)].into_iter().collect();
+    map.merge(&CollisionMap::new((16, 16), Bounds::new(-2, 0, 2, 1), west));
+
+    assert!(map.is_solid((1, 1)));
+    assert!(map.is_solid((-2, 0)));
+    assert!(!map.is_solid((-1, 0)));
+    // The union of the bounds is a rectangle, so this gap is open too
+    assert!(!map.is_solid((-1, 3)));
+    assert!(map.is_solid((-3, 0)));
+}
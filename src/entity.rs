@@ -0,0 +1,135 @@
+//! Entities: what the objects placed on a map stand for in the game.
+//!
+//! Objects of these kinds, in any of a map's object layers, are entities;
+//! others, like warps, are left to whatever knows about them.
+//!
+//! - `npc`: someone standing there, drawn with the image named by its
+//!   `sprite` property
+//! - `item`: something lying there, also drawn with its `sprite`
+//! - `trigger`: an unseen area that sets off its `event` (its name, if it
+//!   doesn't have one) when walked into
+//! - `sign`: shows its `text` while the player stands on it
+//!
+//! Sprites stand on the bottom edge of their object, centered along it.
+//! XXX: nothing is solid, and items can't be picked up yet.
+use collision::Aabb;
+use map::{Map, Object};
+use renderer::{HPos, VPos};
+use scene::{Sprite, sprite};
+use std::collections::BTreeMap;
+
+/// The kinds of object that are entities, in the order the editor offers
+/// them.
+pub const KINDS: [&'static str; 4] = ["npc", "item", "trigger", "sign"];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Kind {
+    Npc { sprite: Option<String> },
+    Item { sprite: Option<String> },
+    Trigger { event: String },
+    Sign { text: String },
+}
+
+/// Something in the game made from an object on the map.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entity {
+    pub name: String,
+    /// Where it is, in pixels
+    pub area: Aabb,
+    pub kind: Kind,
+}
+
+impl Entity {
+    /// The entity an object stands for, or None if it isn't one of the
+    /// KINDS.
+    pub fn from_object(object: &Object) -> Option<Entity> {
+        let property = |name: &str| object.properties.get(name).cloned();
+        let kind = match &object.kind[..] {
+            "npc" => Kind::Npc { sprite: property("sprite") },
+            "item" => Kind::Item { sprite: property("sprite") },
+            "trigger" => Kind::Trigger {
+                event: property("event").unwrap_or(object.name.clone()),
+            },
+            "sign" => Kind::Sign {
+                text: property("text").unwrap_or(String::new()),
+            },
+            _ => return None,
+        };
+        Some(Entity {
+            name: object.name.clone(),
+            area: Aabb::new(object.x as f32, object.y as f32,
+                            object.width as f32, object.height as f32),
+            kind: kind,
+        })
+    }
+
+    /// How the entity looks, if it can be seen at all.
+    pub fn sprite(&self) -> Option<Sprite> {
+        match self.kind {
+            Kind::Npc { sprite: Some(ref name) } |
+            Kind::Item { sprite: Some(ref name) } => {
+                let Aabb { x, y, w, h } = self.area;
+                Some(sprite(name, HPos::Center((x + w / 2.0) as i32),
+                            VPos::Bottom((y + h) as i32)))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Every entity on a map.
+pub fn entities(map: &Map) -> Vec<Entity> {
+    map.object_layers().iter()
+        .flat_map(|l| l.objects.iter().filter_map(Entity::from_object))
+        .collect()
+}
+
+/// The entities that `feet`, in pixels, overlap.
+pub fn touching(entities: &[Entity], feet: Aabb) -> Vec<&Entity> {
+    entities.iter().filter(|e| e.area.overlaps(&feet)).collect()
+}
+
+/// A new object of one of the KINDS for the editor to place, with nothing
+/// in its properties yet.
+pub fn new_object(kind: &str, name: &str, (x, y): (i32, i32),
+                  (w, h): (u32, u32)) -> Object {
+    Object {
+        name: name.into(),
+        kind: kind.into(),
+        x: x,
+        y: y,
+        width: w,
+        height: h,
+        properties: BTreeMap::new(),
+    }
+}
+
+#[test]
+fn entities_are_made_from_objects() {
+    use map::ObjectLayer;
+    let mut starman = new_object("npc", "starman", (181, 95), (38, 59));
+    starman.properties.insert("sprite".into(), "assets/starmanjr".into());
+    let mut sign = new_object("sign", "sign", (0, 0), (16, 16));
+    sign.properties.insert("text".into(), "Onett".into());
+    let mut door = new_object("warp", "door", (0, 16), (16, 16));
+    door.properties.insert("map".into(), "house.json".into());
+    let mut map = Map::new();
+    map.push_objects(ObjectLayer {
+        name: "objects".into(),
+        objects: vec![starman, door, sign,
+                      new_object("trigger", "ambush", (32, 0), (8, 8))],
+    });
+
+    let entities = entities(&map);
+    assert_eq!(entities.iter().map(|e| e.kind.clone()).collect::<Vec<_>>(),
+               vec![Kind::Npc { sprite: Some("assets/starmanjr".into()) },
+                    Kind::Sign { text: "Onett".into() },
+                    Kind::Trigger { event: "ambush".into() }]);
+    assert_eq!(entities[0].sprite(), Some(sprite("assets/starmanjr",
+        HPos::Center(200), VPos::Bottom(154))));
+    assert_eq!(entities[1].sprite(), None);
+
+    let touched = touching(&entities, Aabb::new(12.0, 4.0, 24.0, 8.0));
+    assert_eq!(touched.iter().map(|e| &e.name[..]).collect::<Vec<_>>(),
+               vec!["sign", "ambush"]);
+}
@@ -0,0 +1,477 @@
+//! Undo and redo for the map editor.
+//!
+//! Edits to a map go through a History, which remembers what each one
+//! changed. Edits made between `begin` and `end`, like every cell a stroke
+//! of the brush passes over, or every cell of a fill, are undone and redone
+//! as one step.
+use map::{Bounds, Map, Object, ObjectLayer};
+use std::collections::VecDeque;
+
+/// One thing an edit did to a map, with enough to do it again or take it
+/// back.
+#[derive(Clone, PartialEq, Debug)]
+enum Change {
+    /// A cell of a layer was painted. The layer's bounds from before are
+    /// kept, since painting outside them grows them.
+    Cell {
+        layer: String,
+        cell: (i32, i32),
+        before: u32,
+        after: u32,
+        bounds: Bounds,
+    },
+    /// A layer was shown or hidden.
+    Visible {
+        layer: String,
+        visible: bool,
+    },
+    /// An object was added to an object layer (with no `before`), taken
+    /// out of it (with no `after`), or changed. `created` is whether the
+    /// layer was made to hold it, so undoing takes the layer out again.
+    Object {
+        layer: String,
+        index: usize,
+        before: Option<Object>,
+        after: Option<Object>,
+        created: bool,
+    },
+}
+
+/// Swap the object at `index` of an object layer from `old` to `new`,
+/// making the layer if there isn't one yet.
+fn swap_object(map: &mut Map, layer: &str, index: usize,
+               old: &Option<Object>, new: &Option<Object>) {
+    if map.object_layer_mut(layer).is_none() {
+        map.push_objects(ObjectLayer {
+            name: layer.into(),
+            objects: Vec::new(),
+        });
+    }
+    let objects = &mut map.object_layer_mut(layer).unwrap().objects;
+    match (old.is_some(), new.clone()) {
+        (false, Some(new)) => objects.insert(index, new),
+        (true, Some(new)) => objects[index] = new,
+        (true, None) => {
+            objects.remove(index);
+        },
+        (false, None) => {},
+    }
+}
+
+impl Change {
+    fn redo(&self, map: &mut Map) {
+        match *self {
+            Change::Cell { ref layer, cell, after, .. } => {
+                if let Some(layer) = map.layer_mut(layer) {
+                    layer.set_cell(cell, after);
+                }
+            },
+            Change::Visible { ref layer, visible } => {
+                map.set_visible(layer, visible).ok();
+            },
+            Change::Object { ref layer, index, ref before, ref after,
+                             .. } => {
+                swap_object(map, layer, index, before, after);
+            },
+        }
+    }
+
+    fn undo(&self, map: &mut Map) {
+        match *self {
+            Change::Cell { ref layer, cell, before, bounds, .. } => {
+                if let Some(layer) = map.layer_mut(layer) {
+                    layer.set_cell(cell, before);
+                    if layer.bounds() != bounds {
+                        layer.shrink_to(bounds);
+                    }
+                }
+            },
+            Change::Visible { ref layer, visible } => {
+                map.set_visible(layer, !visible).ok();
+            },
+            Change::Object { ref layer, index, ref before, ref after,
+                             created } => {
+                swap_object(map, layer, index, after, before);
+                if created {
+                    map.remove_objects(layer);
+                }
+            },
+        }
+    }
+}
+
+/// The edits made to a map, most recent last, and the ones undone since.
+pub struct History {
+    done: VecDeque<Vec<Change>>,
+    undone: Vec<Vec<Change>>,
+    // How many steps are kept; the oldest are forgotten past this
+    limit: usize,
+    // The step being made, between begin() and end()
+    current: Option<Vec<Change>>,
+    // How many edits, undos and redos have been made, and how many there
+    // had been when the map was last saved
+    edits: usize,
+    saved: usize,
+}
+
+impl History {
+    /// An empty history that keeps up to `limit` steps.
+    pub fn new(limit: usize) -> History {
+        History {
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            limit: limit,
+            current: None,
+            edits: 0,
+            saved: 0,
+        }
+    }
+
+    /// Whether the map has been changed since it was last saved, or
+    /// since the history began. Undoing back to the saved map still
+    /// counts as a change.
+    pub fn unsaved(&self) -> bool {
+        self.edits != self.saved
+    }
+
+    /// How many edits, undos and redos have been made. This goes up
+    /// whenever the map is changed through the history.
+    pub fn edits(&self) -> usize {
+        self.edits
+    }
+
+    /// Note that the map as it stands has been saved.
+    pub fn mark_saved(&mut self) {
+        self.saved = self.edits;
+    }
+
+    /// Start grouping edits into one step, until `end`.
+    pub fn begin(&mut self) {
+        if self.current.is_none() {
+            self.current = Some(Vec::new());
+        }
+    }
+
+    /// Finish the step started by `begin`. Steps that didn't change
+    /// anything aren't kept.
+    pub fn end(&mut self) {
+        match self.current.take() {
+            Some(ref changes) if changes.is_empty() => {},
+            Some(changes) => self.push(changes),
+            None => {},
+        }
+    }
+
+    fn push(&mut self, changes: Vec<Change>) {
+        self.done.push_back(changes);
+        while self.done.len() > self.limit {
+            self.done.pop_front();
+        }
+        self.undone.clear();
+    }
+
+    /// Make a change and remember it, as part of the current step if one
+    /// has been begun, or as a step of its own otherwise.
+    fn record(&mut self, map: &mut Map, change: Change) {
+        change.redo(map);
+        self.edits += 1;
+        match self.current {
+            Some(ref mut changes) => changes.push(change),
+            None => self.push(vec![change]),
+        }
+    }
+
+    /// Paint `tile` into the cell at column `x`, row `y` of a layer.
+    /// Returns Err if there is no such layer.
+    pub fn paint(&mut self, map: &mut Map, layer: &str, cell: (i32, i32),
+                 tile: u32) -> Result<(), ()> {
+        let (before, bounds) = match map.layer(layer) {
+            Some(l) => (l.tile(cell), l.bounds()),
+            None => return Err(()),
+        };
+        // Strokes pass over the same cell many times; only the first counts
+        if before != tile {
+            self.record(map, Change::Cell {
+                layer: layer.into(),
+                cell: cell,
+                before: before,
+                after: tile,
+                bounds: bounds,
+            });
+        }
+        Ok(())
+    }
+
+    /// Show or hide a layer. Returns Err if there is no such layer.
+    pub fn set_visible(&mut self, map: &mut Map, layer: &str, visible: bool)
+            -> Result<(), ()> {
+        let before = match map.layers().iter().find(|l| l.name() == layer) {
+            Some(l) => l.visible(),
+            None => return Err(()),
+        };
+        if before != visible {
+            self.record(map, Change::Visible {
+                layer: layer.into(),
+                visible: visible,
+            });
+        }
+        Ok(())
+    }
+
+    /// Add an object to the end of an object layer, making the layer if
+    /// there isn't one. Returns the object's index in the layer.
+    pub fn add_object(&mut self, map: &mut Map, layer: &str, object: Object)
+            -> usize {
+        let (index, created) = match map.object_layer(layer) {
+            Some(l) => (l.objects.len(), false),
+            None => (0, true),
+        };
+        self.record(map, Change::Object {
+            layer: layer.into(),
+            index: index,
+            before: None,
+            after: Some(object),
+            created: created,
+        });
+        index
+    }
+
+    fn object(map: &Map, layer: &str, index: usize) -> Option<Object> {
+        map.object_layer(layer)
+            .and_then(|l| l.objects.get(index))
+            .cloned()
+    }
+
+    /// Move the object at `index` of an object layer so its top-left
+    /// corner is at `to`, in pixels. Returns Err if there's no such object.
+    pub fn move_object(&mut self, map: &mut Map, layer: &str, index: usize,
+                       to: (i32, i32)) -> Result<(), ()> {
+        let before = match History::object(map, layer, index) {
+            Some(object) => object,
+            None => return Err(()),
+        };
+        if (before.x, before.y) != to {
+            let after = Object { x: to.0, y: to.1, ..before.clone() };
+            self.record(map, Change::Object {
+                layer: layer.into(),
+                index: index,
+                before: Some(before),
+                after: Some(after),
+                created: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// Take the object at `index` out of an object layer. Returns Err if
+    /// there's no such object.
+    pub fn remove_object(&mut self, map: &mut Map, layer: &str,
+                         index: usize) -> Result<(), ()> {
+        let before = match History::object(map, layer, index) {
+            Some(object) => object,
+            None => return Err(()),
+        };
+        self.record(map, Change::Object {
+            layer: layer.into(),
+            index: index,
+            before: Some(before),
+            after: None,
+            created: false,
+        });
+        Ok(())
+    }
+
+    /// Take back the most recent step, ending it first if it's still being
+    /// made. Returns false if there was nothing to undo.
+    pub fn undo(&mut self, map: &mut Map) -> bool {
+        self.end();
+        match self.done.pop_back() {
+            Some(changes) => {
+                for change in changes.iter().rev() {
+                    change.undo(map);
+                }
+                self.edits += 1;
+                self.undone.push(changes);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Make the most recently undone step again. Returns false if there
+    /// was nothing to redo.
+    pub fn redo(&mut self, map: &mut Map) -> bool {
+        self.end();
+        match self.undone.pop() {
+            Some(changes) => {
+                for change in &changes {
+                    change.redo(map);
+                }
+                self.edits += 1;
+                self.done.push_back(changes);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_map() -> Map {
+    use map::MapLayer;
+    use tileset::Tileset;
+    let mut map = Map::new();
+    let tileset = Tileset::strip("foobar", (16, 16));
+    map.push("ground", -1, MapLayer::new(tileset, 4, vec![0; 16]));
+    map
+}
+
+#[cfg(test)]
+fn tiles(map: &Map) -> Vec<u32> {
+    map.layer("ground").unwrap().tiles()
+}
+
+#[test]
+fn a_stroke_is_undone_as_one_step() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let before = tiles(&map);
+
+    history.begin();
+    for x in 0..4 {
+        history.paint(&mut map, "ground", (x, 0), 3).unwrap();
+        // Going back over a cell in the same stroke changes nothing
+        history.paint(&mut map, "ground", (x, 0), 3).unwrap();
+    }
+    history.end();
+    let after = tiles(&map);
+    assert_eq!(&after[..4], &[3, 3, 3, 3]);
+
+    assert!(history.undo(&mut map));
+    assert_eq!(tiles(&map), before);
+    assert!(!history.undo(&mut map));
+
+    assert!(history.redo(&mut map));
+    assert_eq!(tiles(&map), after);
+    assert!(!history.redo(&mut map));
+}
+
+#[test]
+fn undoing_a_paint_outside_the_layer_shrinks_it_back() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let bounds = map.layer("ground").unwrap().bounds();
+    let chunks = map.layer("ground").unwrap().chunks().clone();
+
+    history.paint(&mut map, "ground", (-5, 7), 2).unwrap();
+    assert!(map.layer("ground").unwrap().bounds() != bounds);
+
+    history.undo(&mut map);
+    let layer = map.layer("ground").unwrap();
+    assert_eq!(layer.bounds(), bounds);
+    assert_eq!(layer.tile((-5, 7)), ::map::EMPTY);
+    assert_eq!(layer.tile_count(), 16);
+    // Nor is the chunk it made kept around, empty
+    assert_eq!(layer.chunks(), &chunks);
+}
+
+#[test]
+fn new_edits_clear_the_redo_steps() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    history.paint(&mut map, "ground", (0, 0), 1).unwrap();
+    history.undo(&mut map);
+    history.paint(&mut map, "ground", (1, 0), 1).unwrap();
+    assert!(!history.redo(&mut map));
+    assert_eq!(&tiles(&map)[..2], &[0, 1]);
+}
+
+#[test]
+fn history_is_bounded() {
+    let mut map = test_map();
+    let mut history = History::new(2);
+    for x in 0..4 {
+        history.paint(&mut map, "ground", (x, 0), 5).unwrap();
+    }
+    assert!(history.undo(&mut map));
+    assert!(history.undo(&mut map));
+    assert!(!history.undo(&mut map));
+    assert_eq!(&tiles(&map)[..4], &[5, 5, 0, 0]);
+}
+
+#[test]
+fn layer_changes_are_undone() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    history.set_visible(&mut map, "ground", false).unwrap();
+    assert!(!map.layers()[0].visible());
+    history.undo(&mut map);
+    assert!(map.layers()[0].visible());
+    history.redo(&mut map);
+    assert!(!map.layers()[0].visible());
+
+    assert_eq!(history.set_visible(&mut map, "sky", false), Err(()));
+    assert_eq!(history.paint(&mut map, "sky", (0, 0), 1), Err(()));
+}
+
+#[test]
+fn object_edits_are_undone() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let sign = Object {
+        name: "sign".into(),
+        kind: "sign".into(),
+        x: 0,
+        y: 0,
+        width: 16,
+        height: 16,
+        properties: Default::default(),
+    };
+    let objects = |map: &Map| -> Vec<(i32, i32)> {
+        map.object_layers().iter()
+            .flat_map(|l| l.objects.iter().map(|o| (o.x, o.y)))
+            .collect()
+    };
+
+    assert_eq!(history.add_object(&mut map, "things", sign.clone()), 0);
+    assert_eq!(history.add_object(&mut map, "things", sign), 1);
+    history.begin();
+    history.move_object(&mut map, "things", 1, (8, 8)).unwrap();
+    history.move_object(&mut map, "things", 1, (16, 8)).unwrap();
+    history.end();
+    history.remove_object(&mut map, "things", 0).unwrap();
+    assert_eq!(objects(&map), vec![(16, 8)]);
+
+    history.undo(&mut map);
+    assert_eq!(objects(&map), vec![(0, 0), (16, 8)]);
+    history.undo(&mut map);
+    assert_eq!(objects(&map), vec![(0, 0), (0, 0)]);
+    history.undo(&mut map);
+    history.undo(&mut map);
+    assert_eq!(objects(&map), vec![]);
+    // The layer was made for the first object, so it goes with it
+    assert!(map.object_layer("things").is_none());
+    history.redo(&mut map);
+    assert_eq!(objects(&map), vec![(0, 0)]);
+
+    assert_eq!(history.move_object(&mut map, "things", 3, (0, 0)), Err(()));
+    assert_eq!(history.remove_object(&mut map, "stuff", 0), Err(()));
+}
+
+#[test]
+fn only_edits_since_the_last_save_are_unsaved() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    assert!(!history.unsaved());
+
+    history.paint(&mut map, "ground", (0, 0), 1).unwrap();
+    assert!(history.unsaved());
+    history.mark_saved();
+    assert!(!history.unsaved());
+
+    // Painting a cell the same again changes nothing
+    history.paint(&mut map, "ground", (0, 0), 1).unwrap();
+    assert!(!history.unsaved());
+    history.undo(&mut map);
+    assert!(history.unsaved());
+}
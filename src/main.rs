@@ -1,44 +1,124 @@
+extern crate base64;
 #[macro_use(lift)]
 extern crate carboxyl;
 extern crate conv;
+extern crate flate2;
 extern crate num;
-extern crate rustc_serialize;
+extern crate png;
+extern crate rusttype;
 extern crate sdl2;
 extern crate sdl2_image;
 extern crate sdl2_ttf;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 extern crate snes_spc;
 extern crate time;
+extern crate xml;
 
 use carboxyl::Sink;
 use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, LCTRLMOD, LSHIFTMOD, RCTRLMOD, RSHIFTMOD};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
+use sdl2::render::BlendMode;
 use std::cmp::{min, max};
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 
 mod audio;
+mod autotile;
+mod binary;
 mod brobot;
+mod collision;
+mod entity;
 mod event;
+mod history;
 mod map;
+mod overview;
+mod path;
 mod physics;
 mod ratio;
 mod renderer;
 mod scene;
+// The software renderer is only used offscreen, by tests and to export maps
+// as PNGs, so the game doesn't need all of it.
+#[allow(dead_code)]
+mod software;
+#[cfg(test)]
+mod snapshot;
+mod stamp;
 mod textbox;
+mod tiled;
 mod tilepicker;
+mod tileset;
+mod tools;
+mod warp;
 
 use audio::{SpcPlayer, Mixer};
-use brobot::controlled_sprite;
+use brobot::{controlled_sprite, feet};
 use event::{IOEvent, translate_event};
-use map::MapLayer;
-use ratio::{Ratio, Scalable};
-use renderer::{RenderContext, HPos, VPos};
-use scene::{Scene, sprite, text};
+use history::History;
+use map::{Map, MapLayer};
+use overview::Minimap;
+use path::Pathfinder;
+use ratio::Ratio;
+use renderer::RenderContext;
+use scene::{Anchor, Layer, Rectangle, Scene, View, text};
 use textbox::Textbox;
 use tilepicker::TilePicker;
+use tileset::Tileset;
+use tools::{Painter, Tool};
+
+/// What goes into a frame from outside the reactive network: the map and
+/// the editor, which change in place rather than through signals.
+#[derive(Clone)]
+struct Outside {
+    // The map layers on screen, in the world's view: those at z-index 0 or
+    // below go under everything standing on the map, the rest over it.
+    // Each is drawn whole, animated tiles and all, before the next.
+    ground: Layer,
+    overhead: Layer,
+    // Whatever stands on the map, y-sorted; the hero is added to it
+    world: Layer,
+    // Outlines of objects and the way to the picked one, shown with the GUI
+    marks: Vec<Rectangle>,
+    // The text of the sign being read
+    reading: Option<String>,
+    minimap: Layer,
+    gui: Layer,
+    output_size: (u32, u32),
+}
+
+impl Outside {
+    fn new() -> Outside {
+        Outside {
+            ground: Layer::new("ground", View::identity()),
+            overhead: Layer::new("overhead", View::identity()),
+            world: Layer::y_sorted("world", View::identity()),
+            marks: Vec::new(),
+            reading: None,
+            minimap: Layer::new("minimap", View::identity()),
+            gui: Layer::new("gui", View::identity()),
+            output_size: (0, 0),
+        }
+    }
+}
 
 fn main() {
+    // `tarnished export-png <map> <png> [scale]` draws a whole map into a
+    // PNG, without opening a window, for looking over outside the game
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|a| &a[..]) == Some("export-png") {
+        process::exit(export_png(&args[2..]));
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let video = sdl_context.video().unwrap();
     let ttf = sdl2_ttf::init().unwrap();
@@ -53,6 +133,7 @@ fn main() {
 
     let mut renderer = window.renderer()
         .accelerated()
+        .target_texture()
         //.present_vsync()
         .build()
         .unwrap();
@@ -80,20 +161,74 @@ fn main() {
     audio.resume();
 
     // Draw some stuff
-    let starman = sprite("assets/starmanjr",
-        HPos::Center(200), VPos::Center(125));
     let textbox = Textbox::new("assets/box",
         Rect::new(12, 12, 32, 16));
     let hello = text("$0.00", "assets/orangekid", 30, 18);
 
-    let mut map = MapLayer::from_file("assets/map.json")
-        .unwrap_or(MapLayer::new("assets/cotp", (16, 16), 25, vec![0;25*16]));
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let mut map_path = PathBuf::from("assets/map.json");
+    let mut map = Map::from_file(&map_path).unwrap_or_else(|_| {
+        let mut map = Map::new();
+        map.push("ground", -1, MapLayer::new(tileset, 25, vec![0;25*16]));
+        map
+    });
+    // The doors out of the map
+    let mut warps = load_warps(&map, &map_path);
+    // Warps only go off when walked into, not when standing in one, e.g.
+    // after coming out of the door back
+    let mut in_warp = true;
+    // The transition into the map, and when it started
+    let mut transition = None;
+
+    // What the map's objects stand for. They're worked out again, along
+    // with the warps, whenever the map is edited, since the editor can
+    // move them around.
+    let mut entities = entity::entities(&map);
+    let mut objects_edits = 0;
+    // The triggers the hero is standing in, which only go off again once
+    // left, and the text of the sign being read
+    let mut triggered = Vec::new();
+    let mut reading = None;
+
+    // The layer of the map the editor paints into
+    // XXX: there's no way to pick another one yet
+    let editing = "ground";
 
     // XXX: note that this widget is rendered in unscaled space, so its width
     // of 960 is actually the full window width. Soon these different spaces
     // should be managed more cleanly.
-    let mut tilepicker = TilePicker::new("assets/cotp", 16, 16, 0, 0, 960, 66);
+    let mut tilepicker = TilePicker::new(
+        map.layer(editing).unwrap().tileset().clone(), 0, 0, 960, 66);
     let mut painting = false;
+    let mut picking = false;
+
+    // Prefabs anyone can paint with, picked with the number keys. A library
+    // that's there but can't be read is never saved over, so nobody's
+    // stamps are lost.
+    let stamps_path = "assets/stamps.json";
+    let (mut stamps, stamps_loaded) = match stamp::load_library(stamps_path) {
+        Ok(stamps) => (stamps, true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            (BTreeMap::new(), true)
+        },
+        Err(e) => {
+            println!("Couldn't load {}: {}", stamps_path, e);
+            (BTreeMap::new(), false)
+        },
+    };
+
+    // Every edit goes through here, so it can be undone. Each use of a tool,
+    // from mouse down to mouse up, is one step.
+    let mut history = History::new(100);
+    let mut painter = Painter::new();
+
+    // Shows the way the hero would walk to the object picked in the
+    // editor, to check it can be got to
+    let mut pathfinder = Pathfinder::new(true);
+    // It's only looked for again when the hero or the object moves to
+    // another cell, or the map is edited
+    let mut way = Vec::new();
+    let mut way_to = None;
 
     // The one sink for all SDL events.
     let sdl_sink = Sink::new();
@@ -112,9 +247,18 @@ fn main() {
     // ...the current time comes out here.
     let time = delta_sink.stream().fold(0.0, |a, b| a + b);
 
+    // The map as far as collision is concerned. The editor mutates the map
+    // directly, so it pushes a fresh collision map in here after every edit.
+    let collision_sink = Sink::new();
+    let collision = collision_sink.stream().hold(map.collision_map());
+
+    // Warping puts the hero somewhere else, and the camera follows
+    let warp_sink = Sink::new();
+
     let (hero_pos, hero_display) = controlled_sprite(
         "assets/porky", 16, 24, 85, 100,
-        keyboard_stream.clone(), time.clone(), delta_sink.stream());
+        keyboard_stream.clone(), time.clone(), delta_sink.stream(),
+        collision, warp_sink.stream());
 
     // A Stream consisting of just key-down events
     // XXX: temporary, just used by scale and show_gui signals
@@ -136,23 +280,76 @@ fn main() {
         }
     });
 
-    // Screen position is determined by hero position and scale
-    // XXX: (Also by screen size, but we'll move this to a signal as well later
-    // XXX: This should be broken up more; scaling and screen size both
-    // should probably be introduced in a separate lift.
-    let (screen_w, screen_h) = renderer.window().unwrap().size();
-    let screen_pos = lift!(move |scale, (hero_x, hero_y)| {
-        let screen_w = (Ratio::from_integer(screen_w) / scale).to_integer();
-        let screen_h = (Ratio::from_integer(screen_h) / scale).to_integer();
-        (hero_x as i32 - (screen_w/2) as i32 + 8,
-         hero_y as i32 - (screen_h/2) as i32 + 12)
+    // The world view keeps the center of the hero sprite pinned to the
+    // center of the screen, whatever its size.
+    let world_view = lift!(|scale, (hero_x, hero_y)| {
+        View::new(scale, Anchor::Center,
+                  (hero_x as i32 + 8, hero_y as i32 + 12))
     }, &scale_signal, &hero_pos);
 
+    // The HUD is drawn at the same scale as the world, but stays put.
+    let hud = scale_signal.map(move |scale| {
+        let mut hud = Layer::new("hud",
+            View::new(scale, Anchor::TopLeft, (0, 0)));
+        hud.add_all(textbox.render(), 1);
+        hud.add(hello.clone(), 2);
+        hud
+    });
+
     // show_gui is a simple boolean signal that switches on pressing 'F'
     let show_gui = keydown_stream
         .filter(|k| *k == Keycode::F)
         .fold(false, |t, _| !t );
 
+    // The minimap marks the middle of the hero sprite, and is hidden and
+    // shown again with 'M'
+    let minimap = Minimap::new(
+        hero_pos.map(|(x, y)| (x as i32 + 8, y as i32 + 12)), (160, 120));
+    let show_minimap = keydown_stream
+        .filter(|k| *k == Keycode::M)
+        .fold(true, |t, _| !t );
+
+    // The map and the editor are changed in place rather than through
+    // signals, so what they draw is sent in here every frame...
+    let outside_sink = Sink::new();
+    let outside = outside_sink.stream().hold(Outside::new());
+
+    // ...and the whole frame is put together from it and the rest.
+    let toggles = lift!(|gui, minimap| (gui, minimap),
+                        &show_gui, &show_minimap);
+    let scene = lift!(|hero, hud, (show_gui, show_minimap), outside| {
+        let mut scene = Scene::new();
+        let Outside { ground, overhead, mut world, marks, reading, minimap,
+                      gui, output_size } = outside;
+        let view = world.view();
+        world.add(hero, 0);
+        scene.push(ground);
+        scene.push(world);
+        scene.push(overhead);
+        if show_gui {
+            let mut editor = Layer::new("marks", view);
+            editor.add_all(marks, 0);
+            scene.push(editor);
+        }
+
+        let mut hud = hud;
+        if let Some(words) = reading {
+            let (w, h) = output_size;
+            let (x, y) = hud.view()
+                .unproject((w as i32 / 2, h as i32 - 64), output_size);
+            hud.add(text(&words, "assets/orangekid", x, y), 2);
+        }
+        scene.push(hud);
+
+        if show_minimap {
+            scene.push(minimap);
+        }
+        if show_gui {
+            scene.push(gui);
+        }
+        scene
+    }, &hero_display, &hud, &toggles, &outside);
+
     // Game loop control
     let mut curtime = time::precise_time_ns();
     let mut accumulator = 0u64;
@@ -165,6 +362,9 @@ fn main() {
     let mut render_time_max = 0u64;
     let mut present_time = 0u64;
     let mut present_time_max = 0u64;
+    let mut chunks_drawn = 0u64;
+    let mut chunks_drawn_max = 0u64;
+    let mut chunks_culled = 0u64;
     let mut frames = 0u64;
     let start = time::precise_time_ns();
 
@@ -173,8 +373,8 @@ fn main() {
 
         // XXX: We have to explicitly transform by viewport,
         // eventually UI should be part of the scene (?)
-        let scale = scale_signal.sample();
-        let (screen_x, screen_y) = screen_pos.sample();
+        let view = world_view.sample();
+        let output_size = renderer.output_size().unwrap();
 
         // Add rendering delta to accumulator
         // XXX: need to clean this up and factor out rendering/integration
@@ -187,9 +387,7 @@ fn main() {
         while accumulator >= dt {
 
             let transform_to_world = |x: i32, y: i32| {
-                let x = x.scale(scale.recip()) + screen_x;
-                let y = y.scale(scale.recip()) + screen_y;
-                (x, y)
+                view.unproject((x, y), output_size)
             };
 
             for event in sdl_context.event_pump().unwrap().poll_iter() {
@@ -198,21 +396,112 @@ fn main() {
                     Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
                         break 'mainloop
                     },
+                    Event::KeyDown {keycode: Some(Keycode::Z), keymod, ..}
+                        if keymod.intersects(LCTRLMOD | RCTRLMOD) => {
+                        let changed =
+                            if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                                history.redo(&mut map)
+                            } else {
+                                history.undo(&mut map)
+                            };
+                        if changed {
+                            collision_sink.send(map.collision_map());
+                            painter.deselect();
+                        }
+                    },
+                    Event::KeyDown {keycode: Some(Keycode::S), keymod, ..}
+                        if keymod.intersects(LCTRLMOD | RCTRLMOD) => {
+                        // Keep the stamp being painted with for later; it
+                        // can be given a better name in the file
+                        if !stamps_loaded {
+                            println!("Not saving over {}, which couldn't \
+                                      be loaded", stamps_path);
+                        } else if let Some(stamp) = painter.stamp() {
+                            let name = stamp::unused_name(&stamps);
+                            stamps.insert(name.clone(), stamp.clone());
+                            match stamp::save_library(stamps_path, &stamps) {
+                                Ok(()) => println!("Saved `{}` to {}",
+                                                   name, stamps_path),
+                                Err(e) => println!("Couldn't save {}: {}",
+                                                   stamps_path, e),
+                            }
+                        }
+                    },
+                    Event::KeyDown {keycode: Some(keycode), ..}
+                        if number_key(keycode).is_some() => {
+                        let n = number_key(keycode).unwrap();
+                        if let Some(stamp) = stamps.values().nth(n - 1) {
+                            painter.set_stamp(stamp.clone());
+                        }
+                    },
+                    Event::KeyDown {keycode: Some(Keycode::V), ..} => {
+                        let visible = map.layers().iter()
+                            .any(|l| l.name() == editing && l.visible());
+                        history.set_visible(&mut map, editing, !visible).ok();
+                    },
+                    Event::KeyDown {keycode: Some(Keycode::T), ..} => {
+                        if let Some(layer) = map.layer(editing) {
+                            painter.next_terrain(layer.tileset());
+                        }
+                        history.end();
+                        painting = false;
+                    },
+                    Event::KeyDown {keycode: Some(Keycode::O), ..} => {
+                        painter.next_kind();
+                        history.end();
+                        painting = false;
+                    },
+                    Event::KeyDown {keycode: Some(Keycode::Delete), ..} |
+                    Event::KeyDown {keycode: Some(Keycode::Backspace), ..} => {
+                        painter.delete(&mut map, &mut history);
+                    },
+                    Event::KeyDown {keycode: Some(keycode), ..}
+                        if Tool::from_key(keycode).is_some() => {
+                        painter.set_tool(Tool::from_key(keycode).unwrap());
+                        history.end();
+                        painting = false;
+                    },
                     Event::MouseMotion {x, y, ..} => {
+                        if picking {
+                            tilepicker.drag((x, y));
+                        }
                         if painting {
                             let (x, y) = transform_to_world(x, y);
-                            map.set_px((x, y), tilepicker.selected()).ok();
+                            painter.drag(&mut map, &mut history, editing,
+                                         (x, y), tilepicker.selected());
+                            collision_sink.send(map.collision_map());
                         }
                     },
                     Event::MouseButtonDown {x, y, ..} => {
-                        if !show_gui.sample() || !tilepicker.click((x, y)) {
+                        if show_gui.sample() && tilepicker.click((x, y)) {
+                            picking = true;
+                        } else {
                             let (x, y) = transform_to_world(x, y);
-                            map.set_px((x, y), tilepicker.selected()).ok();
+                            let picked = painter.press(&mut map, &mut history,
+                                editing, (x, y), tilepicker.selected());
+                            if let Some(tile) = picked {
+                                tilepicker.select(tile);
+                            }
+                            collision_sink.send(map.collision_map());
                             painting = true;
                         }
                     },
-                    Event::MouseButtonUp {..} => {
+                    Event::MouseButtonUp {x, y, ..} => {
+                        if painting {
+                            let (x, y) = transform_to_world(x, y);
+                            painter.release(&mut map, &mut history, editing,
+                                            (x, y), tilepicker.selected());
+                            collision_sink.send(map.collision_map());
+                        }
+                        if picking {
+                            if let Some(stamp) = tilepicker.stamp() {
+                                if stamp.size() != (1, 1) {
+                                    painter.set_stamp(stamp);
+                                }
+                            }
+                        }
                         painting = false;
+                        picking = false;
                     },
                     Event::MouseWheel {y: scroll_y, ..} => {
                         tilepicker.scroll(scroll_y);
@@ -225,6 +514,66 @@ fn main() {
 
             accumulator -= dt;
             delta_sink.send((dt as f32) / 1e9);
+
+            if history.edits() != objects_edits {
+                warps = load_warps(&map, &map_path);
+                entities = entity::entities(&map);
+                objects_edits = history.edits();
+            }
+            let found = warp::find(&warps, feet(hero_pos.sample(), 16, 24))
+                .cloned();
+            match found {
+                Some(ref warp) if !in_warp => {
+                    let path = warp.path(&map_path);
+                    match Map::from_file(&path) {
+                        Ok(next) => {
+                            // Keep any edits to the map being left
+                            save_edits(&map, &map_path, &mut history);
+                            map = next;
+                            map_path = path;
+                            warps = load_warps(&map, &map_path);
+                            entities = entity::entities(&map);
+                            history = History::new(100);
+                            objects_edits = 0;
+                            way_to = None;
+                            painter.deselect();
+                            painting = false;
+                            if let Some(layer) = map.layer(editing) {
+                                tilepicker = TilePicker::new(
+                                    layer.tileset().clone(), 0, 0, 960, 66);
+                            }
+                            collision_sink.send(map.collision_map());
+                            let (x, y) = warp.to;
+                            warp_sink.send(((x as f32, y as f32),
+                                            warp.facing));
+                            transition = Some((warp.transition,
+                                               time.sample()));
+                        },
+                        Err(e) => println!("Can't warp to {}: {}",
+                                           path.display(), e),
+                    }
+                },
+                _ => {},
+            }
+            in_warp = found.is_some();
+
+            let touched = entity::touching(&entities,
+                                           feet(hero_pos.sample(), 16, 24));
+            // XXX: nothing listens for triggers yet, they're just printed
+            let mut now = Vec::new();
+            for entity in &touched {
+                if let entity::Kind::Trigger { ref event } = entity.kind {
+                    if !triggered.contains(&entity.name) {
+                        println!("Trigger: {}", event);
+                    }
+                    now.push(entity.name.clone());
+                }
+            }
+            triggered = now;
+            reading = touched.iter().filter_map(|e| match e.kind {
+                entity::Kind::Sign { ref text } => Some(text.clone()),
+                _ => None,
+            }).next();
         }
 
         // Count time spent updating the reactive network
@@ -236,42 +585,102 @@ fn main() {
 
         let render_start = time::precise_time_ns();
 
-        // XXX: note that box must be rendered before creating scene, since
-        // scene borrows references to all the instructions added to it. This
-        // is perhaps an API weakness; might end up just boxing visibles.
-        let rendered_box = textbox.render();
-        let rendered_map = map.render();
-        let rendered_hero = hero_display.sample();
-
         renderer.set_draw_color(Color::RGBA(176, 208, 184, 255));
         renderer.clear();
 
-        {
-            let mut world = Scene::new();
-            world.add_all(&rendered_map, -1);
-            world.add(&rendered_hero, 0);
-            world.add(&starman, 0);
-            world.present(&mut renderer, &mut render_context,
-                          screen_pos.sample(), scale);
+        // Only the chunks of the map that are on screen go into the scene,
+        // each one a single copy from a cached texture. Animated tiles
+        // aren't in the chunks, so they're added one by one.
+        let mut ground = Layer::new("ground", view);
+        let mut overhead = Layer::new("overhead", view);
+        let mut drawn = 0;
+        let area = view.area(output_size);
+        let layers = map.render_layers(area, time.sample());
+        for (z_index, chunks, tiles) in layers {
+            drawn += chunks.len() as u64;
+            let layer = if z_index <= 0 { &mut ground } else { &mut overhead };
+            layer.add_all(chunks, z_index);
+            layer.add_all(tiles, z_index);
         }
-
-        {
-            let mut hud = Scene::new();
-            hud.add_all(&rendered_box, 1);
-            hud.add(&hello, 2);
-            hud.present_scaled(&mut renderer, &mut render_context, scale);
+        let mut world = Layer::y_sorted("world", view);
+        world.add_all(entities.iter().filter_map(|e| e.sprite()), 0);
+        chunks_drawn_max = max(drawn, chunks_drawn_max);
+        chunks_drawn += drawn;
+        chunks_culled += map.chunk_count() as u64 - drawn;
+
+        // Outline every object over the world, so they can be found and
+        // moved, with the one picked up in another color
+        let mut marks = Vec::new();
+        for layer in map.object_layers() {
+            for (i, object) in layer.objects.iter().enumerate() {
+                let picked = painter.selected().map_or(false,
+                    |&(ref l, n)| *l == layer.name && n == i);
+                let color = if picked {
+                    Color::RGBA(255, 224, 0, 255)
+                } else {
+                    Color::RGBA(255, 255, 255, 255)
+                };
+                let rect = Rect::new(object.x, object.y,
+                                     object.width, object.height);
+                marks.push(Rectangle::unfilled(rect, color));
+            }
+        }
+        let picked = painter.selected()
+            .and_then(|&(ref l, i)| map.object_layer(l)
+                .and_then(|l| l.objects.get(i)));
+        if let (Some(object), true) = (picked, show_gui.sample()) {
+            let from = feet(hero_pos.sample(), 16, 24);
+            let from = ((from.x + from.w / 2.0) as i32,
+                        (from.y + from.h / 2.0) as i32);
+            let to = (object.x + object.width as i32 / 2,
+                      object.y + object.height as i32 / 2);
+            let cell = |point| map.layer(editing)
+                .map_or(point, |l| l.point_to_cell(point));
+            let key = (cell(from), cell(to), map.revision());
+            if way_to != Some(key) {
+                way = pathfinder.find(&map, from, to).unwrap_or(Vec::new());
+                way_to = Some(key);
+            }
+            for &(x, y) in &way {
+                let rect = Rect::new(x - 2, y - 2, 4, 4);
+                marks.push(Rectangle::filled(rect,
+                    Color::RGBA(255, 224, 0, 255)));
+            }
         }
 
-        if show_gui.sample() {
-            // This rendering bit is kind of "all wires exposed"; once we
-            // figure out a more managed structure for getting Visibles from
-            // widget to Scene, this will all look much nicer.
-            let (rects, tiles) = tilepicker.render();
-            let mut gui = Scene::new();
-            gui.add_all(&rects, 0);
-            gui.add_all(&tiles, 1);
-            gui.present(&mut renderer, &mut render_context, (0, 0),
-                Ratio::from_integer(1));
+        // This rendering bit is kind of "all wires exposed"; once we figure
+        // out a more managed structure for getting Visibles from widget to
+        // Scene, this will all look much nicer.
+        let (rects, tiles) = tilepicker.render();
+        let mut gui = Layer::new("gui", View::identity());
+        gui.add_all(rects, 0);
+        gui.add_all(tiles, 1);
+        // What's being painted with goes under the picker
+        gui.add(text(&painter.status(), "assets/orangekid", 8, 72), 2);
+
+        outside_sink.send(Outside {
+            ground: ground,
+            overhead: overhead,
+            world: world,
+            marks: marks,
+            reading: reading.clone(),
+            minimap: minimap.render(&map, time.sample()),
+            gui: gui,
+            output_size: output_size,
+        });
+        scene.sample().present(&mut renderer, &mut render_context);
+
+        // Fade in from black after a warp that asks for it. That needs
+        // blending, which scenes don't do, so it's drawn straight over
+        let overlay = transition.and_then(|(effect, start)| {
+            effect.overlay(time.sample() - start)
+        });
+        if let Some(alpha) = overlay {
+            let (w, h) = output_size;
+            renderer.set_blend_mode(BlendMode::Blend);
+            renderer.set_draw_color(Color::RGBA(0, 0, 0, alpha));
+            renderer.fill_rect(Rect::new(0, 0, w, h)).unwrap();
+            renderer.set_blend_mode(BlendMode::None);
         }
 
         // Count time spent rendering the frame
@@ -311,6 +720,61 @@ fn main() {
     println!("Present 𝚫t:\t\t\tmean: {:.*} ms\tmax: {:.*} ms",
              2, present_time as f64 / frames as f64 / 1e6,
              2, present_time_max as f64 / 1e6);
+    println!("Map chunks drawn:\t\tmean: {:.*}\tmax: {}",
+             1, chunks_drawn as f64 / frames as f64, chunks_drawn_max);
+    println!("Map chunks culled:\t\tmean: {:.*}",
+             1, chunks_culled as f64 / frames as f64);
 
-    map.save("assets/map.json").unwrap();
+    save_edits(&map, &map_path, &mut history);
+}
+
+/// The warps on a map, or none if they can't be made sense of.
+fn load_warps(map: &Map, path: &Path) -> Vec<warp::Warp> {
+    warp::warps(map).unwrap_or_else(|e| {
+        println!("Ignoring the warps on {}: {}", path.display(), e);
+        Vec::new()
+    })
+}
+
+/// Save the map if it's been edited since it was last saved. Errors are
+/// reported rather than stopping the game, which would lose the edits.
+fn save_edits(map: &Map, path: &Path, history: &mut History) {
+    if history.unsaved() {
+        match map.save(path) {
+            Ok(()) => history.mark_saved(),
+            Err(e) => println!("Couldn't save {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Which of the number keys 1 to 9 a key is, if any.
+fn number_key(keycode: Keycode) -> Option<usize> {
+    let keys = [Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+                Keycode::Num5, Keycode::Num6, Keycode::Num7, Keycode::Num8,
+                Keycode::Num9];
+    keys.iter().position(|&k| k == keycode).map(|i| i + 1)
+}
+
+/// The export-png subcommand, given the arguments after its name. Returns
+/// the status to exit with.
+fn export_png(args: &[String]) -> i32 {
+    let scale = match args.get(2) {
+        Some(s) => overview::parse_scale(s),
+        None => Some(Ratio::from_integer(1)),
+    };
+    let scale = match scale {
+        Some(scale) if args.len() >= 2 && args.len() <= 3 => scale,
+        _ => {
+            println!("Usage: tarnished export-png <map> <png> [scale]");
+            println!("The scale is a whole number or a fraction, like 1/4.");
+            return 2;
+        },
+    };
+    match overview::export_png(&args[0], &args[1], scale) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Couldn't export {} to {}: {}", args[0], args[1], e);
+            1
+        },
+    }
 }
@@ -1,14 +1,572 @@
-// XXX: switch to serde
-use rustc_serialize::json;
-use scene::Tile;
+use binary;
+use collision::CollisionMap;
+use scene::{Chunk, Tile};
+use sdl2::rect::Rect;
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::io;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tiled;
+use tileset::{TileProperties, Tileset};
 
-/// A grid of cells, drawn from a tileset.
-#[derive(RustcEncodable, RustcDecodable)]
+/// The value of a cell with no tile in it.
+pub const EMPTY: u32 = ::std::u32::MAX;
+
+/// A stack of tile layers, e.g. ground, decoration and overhead, each drawn
+/// at its own depth, plus any layers of objects placed on the map.
+pub struct Map {
+    // In stacking order, bottom first
+    layers: Vec<StackedLayer>,
+    objects: Vec<ObjectLayer>,
+}
+
+/// Something placed on a map that isn't a tile, like an NPC spawn point or
+/// a trigger zone. What `kind` means is up to the game.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Object {
+    pub name: String,
+    pub kind: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub properties: BTreeMap<String, String>,
+}
+
+/// A named group of objects.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ObjectLayer {
+    pub name: String,
+    pub objects: Vec<Object>,
+}
+
+/// A layer of a Map, with where and whether to draw it.
+pub struct StackedLayer {
+    name: String,
+    z_index: i32,
+    visible: bool,
+    layer: MapLayer,
+}
+
+impl StackedLayer {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn z_index(&self) -> i32 {
+        self.z_index
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn layer(&self) -> &MapLayer {
+        &self.layer
+    }
+}
+
+/// The on-disk form of a Map, as of the current version.
+#[derive(Serialize, Deserialize)]
+struct MapFile {
+    version: u32,
+    layers: Vec<StackedLayerData>,
+    objects: Vec<ObjectLayer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StackedLayerData {
+    name: String,
+    z_index: i32,
+    visible: bool,
+    layer: MapData,
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map {
+            layers: Vec::new(),
+            objects: Vec::new(),
+        }
+    }
+
+    /// Add a visible layer on top of the stack.
+    pub fn push(&mut self, name: &str, z_index: i32, layer: MapLayer) {
+        self.layers.push(StackedLayer {
+            name: name.into(),
+            z_index: z_index,
+            visible: true,
+            layer: layer,
+        });
+    }
+
+    /// Hide the layer on top of the stack, the last one pushed. Unlike
+    /// `set_visible`, this can't pick the wrong one of two layers with the
+    /// same name.
+    pub fn hide_top(&mut self) {
+        if let Some(l) = self.layers.last_mut() {
+            l.visible = false;
+        }
+    }
+
+    /// Add a layer of objects.
+    pub fn push_objects(&mut self, layer: ObjectLayer) {
+        self.objects.push(layer);
+    }
+
+    /// Take out the layer of objects with the given name, if there is one.
+    pub fn remove_objects(&mut self, name: &str) {
+        self.objects.retain(|l| l.name != name);
+    }
+
+    /// All the object layers of the map.
+    pub fn object_layers(&self) -> &[ObjectLayer] {
+        &self.objects
+    }
+
+    /// The object layer with the given name, if there is one.
+    pub fn object_layer(&self, name: &str) -> Option<&ObjectLayer> {
+        self.objects.iter().find(|l| l.name == name)
+    }
+
+    pub fn object_layer_mut(&mut self, name: &str)
+            -> Option<&mut ObjectLayer> {
+        self.objects.iter_mut().find(|l| l.name == name)
+    }
+
+    /// Load a map from a file. Files from before maps had layers, holding a
+    /// single MapLayer, load as a map with just a "ground" layer. Maps saved
+    /// by Tiled, as TMX or JSON, are imported, and `.tmap` files are in the
+    /// binary format.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Map> {
+        let map = try!(Map::read(path.as_ref()));
+        try!(map.check_tile_sizes());
+        Ok(map)
+    }
+
+    fn read(path: &Path) -> io::Result<Map> {
+        if tiled::is_tmx(path) {
+            return tiled::import(path);
+        }
+
+        let mut f = try!(File::open(path));
+        if path.extension() == Some(OsStr::new(binary::EXTENSION)) {
+            let mut bytes = Vec::new();
+            try!(f.read_to_end(&mut bytes));
+            return binary::decode(&bytes);
+        }
+        let mut s = String::new();
+        try!(f.read_to_string(&mut s));
+        let json: Value = try!(serde_json::from_str(&s).map_err(invalid));
+        if tiled::is_tiled_json(&json) {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            return tiled::import_json(&json, dir);
+        }
+        Map::from_json(json)
+    }
+
+    /// Load a map from a string in our format, as `from_file` does.
+    #[cfg(test)]
+    pub fn deserialize(s: &str) -> io::Result<Map> {
+        let map = try!(Map::from_json(try!(serde_json::from_str(s)
+            .map_err(invalid))));
+        try!(map.check_tile_sizes());
+        Ok(map)
+    }
+
+    /// Every layer has to share a tile size, so their cells line up, both
+    /// for collision and for painting across them.
+    fn check_tile_sizes(&self) -> io::Result<()> {
+        let mut sizes = self.layers.iter()
+            .map(|l| l.layer.tileset.tile_size());
+        let first = sizes.next();
+        match sizes.find(|&size| Some(size) != first) {
+            Some((w, h)) => {
+                let (fw, fh) = first.unwrap();
+                Err(invalid(format!("layers have {}x{} tiles and {}x{} tiles",
+                                    fw, fh, w, h)))
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Load a map from any version of our format, upgrading it to the
+    /// current one first.
+    fn from_json(json: Value) -> io::Result<Map> {
+        let file: MapFile = try!(serde_json::from_value(try!(migrate(json)))
+            .map_err(invalid));
+
+        let mut map = Map::new();
+        for data in file.layers {
+            map.layers.push(StackedLayer {
+                name: data.name,
+                z_index: data.z_index,
+                visible: data.visible,
+                layer: try!(MapLayer::from_data(data.layer)),
+            });
+        }
+        map.objects = file.objects;
+        Ok(map)
+    }
+
+    /// Save the map to a file. Paths ending in `.tmj` get a map Tiled can
+    /// open, with its tilesets written alongside, and paths ending in `.tmap`
+    /// get the binary format.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.extension() == Some(OsStr::new("tmj")) {
+            return tiled::export(self, path);
+        }
+
+        let mut f = try!(File::create(path));
+        if path.extension() == Some(OsStr::new(binary::EXTENSION)) {
+            return f.write_all(&try!(binary::encode(self)));
+        }
+        try!(f.write_all(self.serialize().as_bytes()));
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> String {
+        serde_json::to_string(&MapFile {
+            version: VERSION,
+            layers: self.layers.iter().map(|l| StackedLayerData {
+                name: l.name.clone(),
+                z_index: l.z_index,
+                visible: l.visible,
+                layer: l.layer.to_data(),
+            }).collect(),
+            objects: self.objects.clone(),
+        }).unwrap()
+    }
+
+    /// All the tile layers of the map, bottom first.
+    pub fn layers(&self) -> &[StackedLayer] {
+        &self.layers
+    }
+
+    /// Find a layer by name.
+    pub fn layer(&self, name: &str) -> Option<&MapLayer> {
+        self.layers.iter().find(|l| l.name == name).map(|l| &l.layer)
+    }
+
+    /// Find a layer by name, for editing.
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut MapLayer> {
+        self.layers.iter_mut().find(|l| l.name == name).map(|l| &mut l.layer)
+    }
+
+    /// Show or hide a layer. Returns Err if there is no such layer.
+    pub fn set_visible(&mut self, name: &str, visible: bool)
+            -> Result<(), ()> {
+        match self.layers.iter_mut().find(|l| l.name == name) {
+            Some(l) => {
+                l.visible = visible;
+                Ok(())
+            },
+            None => Err(()),
+        }
+    }
+
+    /// The tiles of every visible layer that overlap `area`, in pixels,
+    /// bottom first, each with the z-index to draw them at. Animated tiles
+    /// are shown as they are `time` seconds into the game.
+    pub fn render(&self, area: Rect, time: f32) -> Vec<(i32, Vec<Tile>)> {
+        self.layers.iter()
+            .filter(|l| l.visible)
+            .map(|l| (l.z_index, l.layer.render_in(area, time)))
+            .collect()
+    }
+
+    /// The chunks of every visible layer that overlap `area`, in pixels,
+    /// bottom first, each with the z-index to draw them at and that
+    /// layer's animated tiles as they are `time` seconds into the game.
+    /// Each chunk is drawn into a texture once and then copied whole until
+    /// it changes. A layer's animated tiles go on top of its own chunks,
+    /// but under the layers above, even at the same z-index, so layers
+    /// have to be drawn one after the other.
+    pub fn render_layers(&self, area: Rect, time: f32)
+            -> Vec<(i32, Vec<Chunk>, Vec<Tile>)> {
+        self.layers.iter()
+            .filter(|l| l.visible)
+            .map(|l| (l.z_index, l.layer.render_chunks_in(area),
+                      l.layer.render_animated_in(area, time)))
+            .collect()
+    }
+
+    /// How many chunks there are in all the visible layers.
+    pub fn chunk_count(&self) -> usize {
+        self.layers.iter()
+            .filter(|l| l.visible)
+            .map(|l| l.layer.chunks().len())
+            .sum()
+    }
+
+    /// The cells painted so far in any layer.
+    pub fn bounds(&self) -> Bounds {
+        self.layers.iter().fold(Bounds::new(0, 0, 0, 0),
+                                |b, l| b.union(&l.layer.bounds()))
+    }
+
+    /// A number that changes whenever a cell of the map does, so anything
+    /// worked out from the map can tell when it's out of date.
+    pub fn revision(&self) -> usize {
+        self.layers.iter().map(|l| l.layer.revision()).max().unwrap_or(0)
+    }
+
+    /// Which cells are solid in any layer, hidden or not. All layers are
+    /// expected to share the same tile size.
+    pub fn collision_map(&self) -> CollisionMap {
+        let mut layers = self.layers.iter();
+        let mut collision = match layers.next() {
+            Some(l) => l.layer.collision_map(),
+            None => CollisionMap::new((1, 1), Bounds::new(0, 0, 0, 0),
+                                      HashSet::new()),
+        };
+        for l in layers {
+            collision.merge(&l.layer.collision_map());
+        }
+        collision
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// The version of the map format this build writes. Whenever the format
+/// changes, bump this and add a migration from the previous version.
+const VERSION: u32 = 3;
+
+/// Upgrades from each version of the map format to the next, starting at
+/// version 0. Each gets a map of the version it upgrades from, and leaves
+/// setting the version number to `migrate`.
+const MIGRATIONS: [fn(Value) -> io::Result<Value>; VERSION as usize] = [
+    migrate_v0,
+    migrate_v1,
+    migrate_v2,
+];
+
+/// Version 0 was a single MapLayer on its own.
+fn migrate_v0(layer: Value) -> io::Result<Value> {
+    Ok(json!({
+        "layers": [{
+            "name": "ground",
+            "z_index": -1,
+            "visible": true,
+            "layer": layer,
+        }],
+    }))
+}
+
+/// Version 1 stacked layers, and maybe had objects.
+fn migrate_v1(mut map: Value) -> io::Result<Value> {
+    if !map.is_object() {
+        return Err(invalid("map is not a JSON object"));
+    }
+    if map.get("objects").map_or(true, |o| o.is_null()) {
+        map["objects"] = json!([]);
+    }
+    Ok(map)
+}
+
+/// Version 2 stored each layer as a grid with its corner at the origin.
+fn migrate_v2(mut map: Value) -> io::Result<Value> {
+    let layers = match map.get_mut("layers").and_then(|l| l.as_array_mut()) {
+        Some(layers) => layers,
+        None => return Err(invalid("map has no list of layers")),
+    };
+    for layer in layers {
+        let grid = match layer.get_mut("layer") {
+            Some(grid) => grid.take(),
+            None => return Err(invalid("map layer has no tiles")),
+        };
+        let grid: GridData = try!(serde_json::from_value(grid)
+            .map_err(invalid));
+        // Only the tile size matters here, so there's no need to load the
+        // tileset's descriptor
+        let tileset = Tileset::strip(&grid.asset, (grid.tile_w, grid.tile_h));
+        let chunked = MapLayer::new(tileset, grid.width, grid.tiles);
+        layer["layer"] = try!(serde_json::to_value(chunked.to_data())
+            .map_err(invalid));
+    }
+    Ok(map)
+}
+
+/// Work out which version of the format a map is in. Versions before 2
+/// didn't say, but can be told apart by their shape.
+fn version_of(json: &Value) -> io::Result<u32> {
+    match json.get("version") {
+        Some(v) => v.as_u64().map(|v| v as u32)
+            .ok_or_else(|| invalid("map version is not a whole number")),
+        None if json.get("layers").is_some() => Ok(1),
+        None => Ok(0),
+    }
+}
+
+/// Bring a map in any known version of the format up to the current one.
+fn migrate(mut json: Value) -> io::Result<Value> {
+    let version = try!(version_of(&json));
+    if version > VERSION {
+        return Err(invalid(format!(
+            "map is in version {} of the format, but this build only \
+             understands versions up to {}; it was probably saved by a newer \
+             build", version, VERSION)));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        json = try!(migration(json));
+    }
+    if !json.is_object() {
+        return Err(invalid("map is not a JSON object"));
+    }
+    json["version"] = json!(VERSION);
+    Ok(json)
+}
+
+/// The width and height, in cells, of the square chunks a layer's tiles are
+/// stored in.
+pub const CHUNK_SIZE: u32 = 16;
+
+/// How far from the origin cells can be painted, in cells, in any
+/// direction. However far out anyone paints, a layer stays small enough to
+/// go over cell by cell, or to draw whole.
+pub const REACH: i32 = 2048;
+
+/// A rectangle of cells, in tiles.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Bounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Bounds {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Bounds {
+        Bounds { x: x, y: y, width: width, height: height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Whether the cell at column `x`, row `y` is inside.
+    pub fn contains(&self, (x, y): (i32, i32)) -> bool {
+        let (dx, dy) = (x as i64 - self.x as i64, y as i64 - self.y as i64);
+        dx >= 0 && dx < self.width as i64 && dy >= 0 && dy < self.height as i64
+    }
+
+    /// The smallest bounds covering both.
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = min(self.x, other.x);
+        let y = min(self.y, other.y);
+        let right = max(self.x as i64 + self.width as i64,
+                        other.x as i64 + other.width as i64);
+        let bottom = max(self.y as i64 + self.height as i64,
+                         other.y as i64 + other.height as i64);
+        Bounds::new(x, y, (right - x as i64) as u32,
+                    (bottom - y as i64) as u32)
+    }
+
+    /// Whether every cell inside is within REACH of the origin.
+    pub fn within_reach(&self) -> bool {
+        let reach = REACH as i64;
+        self.is_empty() ||
+            self.x as i64 >= -reach && self.y as i64 >= -reach &&
+            self.x as i64 + self.width as i64 <= reach &&
+            self.y as i64 + self.height as i64 <= reach
+    }
+}
+
+/// The cells of the chunk at `key`, in chunk coordinates.
+pub fn chunk_bounds((cx, cy): (i32, i32)) -> Bounds {
+    let n = CHUNK_SIZE as i32;
+    Bounds::new(cx.saturating_mul(n), cy.saturating_mul(n),
+                CHUNK_SIZE, CHUNK_SIZE)
+}
+
+/// Division rounding towards negative infinity, so cells left of and above
+/// the origin get negative coordinates rather than sharing column/row 0.
+pub fn floor_div(a: i32, b: i32) -> i32 {
+    let q = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) { q - 1 } else { q }
+}
+
+/// The chunk holding the cell at column `x`, row `y`, and the cell's index
+/// within that chunk.
+fn chunk_of((x, y): (i32, i32)) -> ((i32, i32), usize) {
+    let n = CHUNK_SIZE as i32;
+    let (cx, cy) = (floor_div(x, n), floor_div(y, n));
+    ((cx, cy), ((y - cy * n) * n + (x - cx * n)) as usize)
+}
+
+/// Hands out numbers that are never reused, to tell apart layers and the
+/// versions of their chunks.
+static NEXT_STAMP: AtomicUsize = AtomicUsize::new(0);
+
+fn stamp() -> usize {
+    NEXT_STAMP.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A grid of cells, drawn from a tileset. There's no fixed edge: the tiles
+/// are stored in chunks, which are allocated as cells are painted, in any
+/// direction from the origin.
 pub struct MapLayer {
+    tileset: Tileset,
+    // Every cell painted so far. The game treats these as the edge of the
+    // world.
+    bounds: Bounds,
+    // Keyed by chunk coordinate, each holding CHUNK_SIZE rows of CHUNK_SIZE
+    // cells. Cells in no chunk are empty.
+    chunks: BTreeMap<(i32, i32), Vec<u32>>,
+    // How many cells aren't empty, kept up to date so it's cheap to ask
+    tile_count: usize,
+    // Names this layer's chunk textures apart from any other layer's
+    id: usize,
+    // The version of each chunk, which changes whenever one of its cells
+    // does, so a texture of the chunk can tell it's out of date
+    versions: HashMap<(i32, i32), usize>,
+    // The tiles of each chunk, made when the chunk is first rendered and
+    // kept until it changes. Animated tiles are left out.
+    rendered: RefCell<HashMap<(i32, i32), Arc<Vec<Tile>>>>,
+    // The cells holding an animated tile, which are drawn apart from the
+    // chunk they're in since they change all the time
+    animated: HashSet<(i32, i32)>,
+}
+
+/// The on-disk form of a map layer. The tileset is stored by name; its
+/// layout comes from the tileset's own descriptor, if it has one.
+#[derive(Serialize, Deserialize)]
+struct MapData {
+    asset: String,
+    tile_w: u32,
+    tile_h: u32,
+    bounds: Bounds,
+    chunks: Vec<ChunkData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkData {
+    x: i32,
+    y: i32,
+    tiles: Vec<u32>,
+}
+
+/// A map layer as stored before version 3, a grid with its top-left corner
+/// at the origin.
+#[derive(Deserialize)]
+struct GridData {
     asset: String,
     tile_w: u32,
     tile_h: u32,
@@ -17,118 +575,429 @@ pub struct MapLayer {
 }
 
 impl MapLayer {
-    pub fn new(asset: &str, (tw, th): (u32, u32), width: u32, tiles: Vec<u32>)
-            -> MapLayer {
+    /// A layer holding a grid of `tiles`, `width` cells wide, with its
+    /// top-left corner at the origin.
+    pub fn new(tileset: Tileset, width: u32, tiles: Vec<u32>) -> MapLayer {
+        let height = if width == 0 { 0 } else { tiles.len() as u32 / width };
+        let mut layer = MapLayer::from_chunks(
+            tileset, Bounds::new(0, 0, width, height), BTreeMap::new());
+        for (i, &tile) in tiles.iter().enumerate() {
+            if tile != EMPTY {
+                let i = i as u32;
+                layer.set_cell(((i % width) as i32, (i / width) as i32), tile);
+            }
+        }
+        layer
+    }
+
+    /// A layer made of already chunked tiles. Each chunk must hold
+    /// CHUNK_SIZE * CHUNK_SIZE cells.
+    pub fn from_chunks(tileset: Tileset, bounds: Bounds,
+                       chunks: BTreeMap<(i32, i32), Vec<u32>>) -> MapLayer {
+        assert!(chunks.values()
+            .all(|c| c.len() == (CHUNK_SIZE * CHUNK_SIZE) as usize));
+        let tile_count = chunks.values()
+            .map(|c| c.iter().filter(|&&t| t != EMPTY).count())
+            .sum();
+        let versions = chunks.keys().map(|&key| (key, stamp())).collect();
+        let n = CHUNK_SIZE as i32;
+        let mut animated = HashSet::new();
+        for (&(cx, cy), chunk) in &chunks {
+            for (i, &tile) in chunk.iter().enumerate() {
+                if tileset.is_animated(tile) {
+                    animated.insert((cx * n + i as i32 % n,
+                                     cy * n + i as i32 / n));
+                }
+            }
+        }
         MapLayer {
-            asset: asset.into(),
-            tile_w: tw,
-            tile_h: th,
-            width: width,
-            tiles: tiles,
+            tileset: tileset,
+            bounds: bounds,
+            chunks: chunks,
+            tile_count: tile_count,
+            id: stamp(),
+            versions: versions,
+            rendered: RefCell::new(HashMap::new()),
+            animated: animated,
         }
     }
 
-    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<MapLayer> {
-        let mut f = try!(File::open(path));
-        let mut s = String::new();
-        try!(f.read_to_string(&mut s));
-        json::decode(&s).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    fn from_data(data: MapData) -> io::Result<MapLayer> {
+        if !data.bounds.within_reach() {
+            return Err(invalid(format!(
+                "layer bounds {:?} reach further than {} cells from the \
+                 origin", data.bounds, REACH)));
+        }
+        let mut chunks = BTreeMap::new();
+        for chunk in data.chunks {
+            if !chunk_bounds((chunk.x, chunk.y)).within_reach() {
+                return Err(invalid(format!(
+                    "chunk {},{} is further than {} cells from the origin",
+                    chunk.x, chunk.y, REACH)));
+            }
+            if chunk.tiles.len() != (CHUNK_SIZE * CHUNK_SIZE) as usize {
+                return Err(invalid(format!(
+                    "chunk {},{} has {} tiles rather than {}", chunk.x,
+                    chunk.y, chunk.tiles.len(), CHUNK_SIZE * CHUNK_SIZE)));
+            }
+            chunks.insert((chunk.x, chunk.y), chunk.tiles);
+        }
+        let tileset = try!(Tileset::load_or_strip(
+            &data.asset, (data.tile_w, data.tile_h)));
+        Ok(MapLayer::from_chunks(tileset, data.bounds, chunks))
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        let mut f = try!(File::create(path));
-        try!(f.write_all(self.serialize().as_bytes()));
-        Ok(())
+    fn to_data(&self) -> MapData {
+        let (tile_w, tile_h) = self.tileset.tile_size();
+        MapData {
+            asset: self.tileset.asset().into(),
+            tile_w: tile_w,
+            tile_h: tile_h,
+            bounds: self.bounds,
+            chunks: self.chunks.iter().map(|(&(x, y), tiles)| ChunkData {
+                x: x,
+                y: y,
+                tiles: tiles.clone(),
+            }).collect(),
+        }
     }
 
+    #[cfg(test)]
     pub fn serialize(&self) -> String {
-        json::encode(self).unwrap()
+        serde_json::to_string(&self.to_data()).unwrap()
+    }
+
+    /// The tileset this layer's cells are drawn from.
+    pub fn tileset(&self) -> &Tileset {
+        &self.tileset
     }
 
-    pub fn render(&self) -> Vec<Tile> {
+    /// Every tile of the layer, with animated ones as they are `time`
+    /// seconds into the game.
+    #[cfg(test)]
+    pub fn render(&self, time: f32) -> Vec<Tile> {
         let mut result = Vec::new();
-        for (i, tile) in self.tiles.iter().enumerate() {
-            let i = i as u32;
-            let x = (i % self.width) * self.tile_w;
-            let y = (i / self.width) * self.tile_h;
-            result.push(Tile::new(&self.asset, *tile,
-                self.tile_w, self.tile_h, x as i32, y as i32));
+        let n = CHUNK_SIZE as i32;
+        for (&key, chunk) in &self.chunks {
+            self.render_chunk(key, chunk, (0, 0), (n, n), Some(time),
+                              &mut result);
         }
         result
     }
 
+    /// The tiles of the layer that overlap `area`, in pixels, as they are
+    /// `time` seconds into the game. Only the chunks overlapping it are
+    /// looked at, and only the cells of those that do too.
+    pub fn render_in(&self, area: Rect, time: f32) -> Vec<Tile> {
+        let mut result = Vec::new();
+        for (key, chunk, from, to) in self.chunks_in(area) {
+            self.render_chunk(key, chunk, from, to, Some(time), &mut result);
+        }
+        result
+    }
+
+    /// The chunks of the layer that overlap `area`, in pixels, ready to be
+    /// drawn through a texture each. Animated tiles aren't in them; see
+    /// `render_animated_in`.
+    pub fn render_chunks_in(&self, area: Rect) -> Vec<Chunk> {
+        let (tile_w, tile_h) = self.tileset.tile_size();
+        let (w, h) = (CHUNK_SIZE * tile_w, CHUNK_SIZE * tile_h);
+        let mut rendered = self.rendered.borrow_mut();
+        self.chunks_in(area).into_iter().map(|(key, chunk, _, _)| {
+            let tiles = rendered.entry(key).or_insert_with(|| {
+                let mut tiles = Vec::new();
+                let n = CHUNK_SIZE as i32;
+                self.render_chunk(key, chunk, (0, 0), (n, n), None,
+                                  &mut tiles);
+                Arc::new(tiles)
+            });
+            Chunk::new(&format!(":CHUNK:{}:{},{}", self.id, key.0, key.1),
+                       self.versions[&key],
+                       Rect::new(key.0 * w as i32, key.1 * h as i32, w, h),
+                       tiles.clone())
+        }).collect()
+    }
+
+    /// The animated tiles of the layer that overlap `area`, in pixels, as
+    /// they are `time` seconds into the game. These go on top of the chunks
+    /// from `render_chunks_in`, which have holes where they go.
+    pub fn render_animated_in(&self, area: Rect, time: f32) -> Vec<Tile> {
+        if area.width() == 0 || area.height() == 0 {
+            return Vec::new();
+        }
+        let (left, top) = self.point_to_cell((area.x(), area.y()));
+        let (right, bottom) = self.point_to_cell(
+            (area.x() + area.width() as i32 - 1,
+             area.y() + area.height() as i32 - 1));
+        let mut cells: Vec<_> = self.animated.iter().cloned()
+            .filter(|&(x, y)| {
+                x >= left && x <= right && y >= top && y <= bottom
+            })
+            .collect();
+        cells.sort();
+        let (tile_w, tile_h) = self.tileset.tile_size();
+        cells.into_iter().map(|(x, y)| {
+            let tile = self.tileset.frame(self.tile((x, y)), time);
+            Tile::new(&self.tileset, tile,
+                      x * tile_w as i32, y * tile_h as i32)
+        }).collect()
+    }
+
+    /// The chunks that overlap `area`, in pixels, each with the corners of
+    /// the area relative to the chunk as in `render_chunk`.
+    fn chunks_in(&self, area: Rect)
+            -> Vec<((i32, i32), &[u32], (i32, i32), (i32, i32))> {
+        let mut result = Vec::new();
+        if area.width() == 0 || area.height() == 0 {
+            return result;
+        }
+        let top_left = self.point_to_cell((area.x(), area.y()));
+        let bottom_right = self.point_to_cell(
+            (area.x() + area.width() as i32 - 1,
+             area.y() + area.height() as i32 - 1));
+        let (left, top) = chunk_of(top_left).0;
+        let (right, bottom) = chunk_of(bottom_right).0;
+
+        let n = CHUNK_SIZE as i32;
+        for cy in top..bottom + 1 {
+            for cx in left..right + 1 {
+                if let Some(chunk) = self.chunks.get(&(cx, cy)) {
+                    let from = (max(top_left.0 - cx * n, 0),
+                                max(top_left.1 - cy * n, 0));
+                    let to = (min(bottom_right.0 - cx * n + 1, n),
+                              min(bottom_right.1 - cy * n + 1, n));
+                    result.push(((cx, cy), &chunk[..], from, to));
+                }
+            }
+        }
+        result
+    }
+
+    /// Render the cells of a chunk from column, row `from` up to but not
+    /// including `to`, counting from the chunk's corner. Animated tiles are
+    /// shown as they are at `time` seconds, or left out if there's no time.
+    fn render_chunk(&self, (cx, cy): (i32, i32), chunk: &[u32],
+                    from: (i32, i32), to: (i32, i32), time: Option<f32>,
+                    result: &mut Vec<Tile>) {
+        let (tile_w, tile_h) = self.tileset.tile_size();
+        let n = CHUNK_SIZE as i32;
+        for row in from.1..to.1 {
+            for col in from.0..to.0 {
+                let tile = match (chunk[(row * n + col) as usize], time) {
+                    (EMPTY, _) => continue,
+                    (tile, Some(time)) => self.tileset.frame(tile, time),
+                    (tile, None) if self.tileset.is_animated(tile) => continue,
+                    (tile, None) => tile,
+                };
+                let x = cx * n + col;
+                let y = cy * n + row;
+                result.push(Tile::new(&self.tileset, tile,
+                                      x * tile_w as i32, y * tile_h as i32));
+            }
+        }
+    }
+
+    /// How many cells have a tile in them.
+    #[cfg(test)]
+    pub fn tile_count(&self) -> usize {
+        self.tile_count
+    }
+
+    /// The cells painted so far.
+    pub fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    /// A number that changes whenever a cell of the layer does; it's the
+    /// newest of its chunks' versions.
+    pub fn revision(&self) -> usize {
+        self.versions.values().cloned().fold(self.id, max)
+    }
+
     /// The width (in tiles) of the map layer.
     pub fn width(&self) -> u32 {
-        self.width
+        self.bounds.width
     }
 
     /// The height (in tiles) of the map layer.
     pub fn height(&self) -> u32 {
-        self.tiles.len() as u32 / self.width
+        self.bounds.height
     }
 
-    /// Map a point in pixels to the index of the tile containing that point.
-    fn point_to_index(&self, (x, y): (i32, i32)) -> Option<usize> {
-        let x = x / self.tile_w as i32;
-        let y = y / self.tile_h as i32;
+    /// The tile in every cell within the bounds, row by row.
+    #[cfg(test)]
+    pub fn tiles(&self) -> Vec<u32> {
+        let b = self.bounds;
+        let mut tiles = vec![EMPTY; b.width as usize * b.height as usize];
+        for ((x, y), tile) in self.painted() {
+            if !b.contains((x, y)) {
+                continue;
+            }
+            let (col, row) = ((x - b.x) as usize, (y - b.y) as usize);
+            tiles[row * b.width as usize + col] = tile;
+        }
+        tiles
+    }
 
-        // XXX: For now, maps have a hard top-left edge at 0,0. Maps with a
-        // non-zero origin may need to support negative coordinates, but using
-        // the i32 type here is mainly for convenience.
-        if x < 0 || x >= self.width() as i32 ||
-           y < 0 || y >= self.height() as i32 {
-            return None;
+    /// Every cell with a tile in it, and the tile, chunk by chunk.
+    pub fn painted(&self) -> Vec<((i32, i32), u32)> {
+        let n = CHUNK_SIZE as i32;
+        let mut cells = Vec::with_capacity(self.tile_count);
+        for (&(cx, cy), chunk) in &self.chunks {
+            for (i, &tile) in chunk.iter().enumerate() {
+                if tile != EMPTY {
+                    cells.push(((cx * n + i as i32 % n,
+                                 cy * n + i as i32 / n), tile));
+                }
+            }
         }
+        cells
+    }
+
+    /// The chunks allocated so far, keyed by chunk coordinate.
+    pub fn chunks(&self) -> &BTreeMap<(i32, i32), Vec<u32>> {
+        &self.chunks
+    }
+
+    /// The tile at column `x`, row `y`, or EMPTY.
+    pub fn tile(&self, cell: (i32, i32)) -> u32 {
+        let (key, index) = chunk_of(cell);
+        self.chunks.get(&key).map_or(EMPTY, |chunk| chunk[index])
+    }
 
-        Some((y * self.width() as i32 + x) as usize)
+    /// Map a point in pixels to the cell containing that point.
+    pub fn point_to_cell(&self, (x, y): (i32, i32)) -> (i32, i32) {
+        let (tile_w, tile_h) = self.tileset.tile_size();
+        (floor_div(x, tile_w as i32), floor_div(y, tile_h as i32))
     }
 
-    /// Get the tile value at a specified point (in pixels)
+    /// Get the tile value at a specified point (in pixels), or None if
+    /// nothing has been painted near there.
     pub fn _get_px(&self, point: (i32, i32)) -> Option<u32> {
-        match self.point_to_index(point) {
-            Some(index) => Some(self.tiles[index]),
-            None => None,
+        let (key, index) = chunk_of(self.point_to_cell(point));
+        self.chunks.get(&key).map(|chunk| chunk[index])
+    }
+
+    /// Get the metadata of the tile at a specified point (in pixels). None
+    /// if the cell is empty or the tile has no metadata.
+    pub fn properties_at(&self, point: (i32, i32))
+            -> Option<&TileProperties> {
+        self.tileset.properties(self.tile(self.point_to_cell(point)))
+    }
+
+    /// Which cells of this layer are solid, according to the tileset.
+    pub fn collision_map(&self) -> CollisionMap {
+        let n = CHUNK_SIZE as i32;
+        let mut solid = HashSet::new();
+        for (&(cx, cy), chunk) in &self.chunks {
+            for (i, &tile) in chunk.iter().enumerate() {
+                if self.tileset.properties(tile).map_or(false, |p| p.solid) {
+                    solid.insert((cx * n + i as i32 % n,
+                                  cy * n + i as i32 / n));
+                }
+            }
         }
+        CollisionMap::new(self.tileset.tile_size(), self.bounds, solid)
     }
 
-    /// Set the tile value at a specified point (in pixels)
-    pub fn set_px(&mut self, point: (i32, i32), tile: u32) -> Result<(), ()> {
-        match self.point_to_index(point) {
-            Some(index) => {
-                self.tiles[index] = tile;
-                Ok(())
-            },
-            None => Err(()),
+    /// Set the tile at column `x`, row `y`, growing the layer to take it in.
+    /// Cells further than REACH from the origin are left alone.
+    pub fn set_cell(&mut self, cell: (i32, i32), tile: u32) {
+        if !Bounds::new(cell.0, cell.1, 1, 1).within_reach() {
+            return;
+        }
+        // Erasing where nothing was painted changes nothing, not even the
+        // bounds
+        if tile == EMPTY && self.tile(cell) == EMPTY {
+            return;
+        }
+        self.bounds = self.bounds.union(&Bounds::new(cell.0, cell.1, 1, 1));
+        let (key, index) = chunk_of(cell);
+        self.versions.insert(key, stamp());
+        self.rendered.borrow_mut().remove(&key);
+        if self.tileset.is_animated(tile) {
+            self.animated.insert(cell);
+        } else {
+            self.animated.remove(&cell);
+        }
+        {
+            let chunk = self.chunks.entry(key).or_insert_with(
+                || vec![EMPTY; (CHUNK_SIZE * CHUNK_SIZE) as usize]);
+            match (chunk[index] == EMPTY, tile == EMPTY) {
+                (true, false) => self.tile_count += 1,
+                (false, true) => self.tile_count -= 1,
+                _ => {},
+            }
+            chunk[index] = tile;
+        }
+        // Chunks that have been emptied are dropped, so they aren't kept,
+        // saved or drawn for nothing. Their version stays, so the revision
+        // still moves on.
+        if tile == EMPTY && self.chunks[&key].iter().all(|&t| t == EMPTY) {
+            self.chunks.remove(&key);
+        }
+    }
+
+    /// Shrink the layer back to bounds it had before, e.g. when undoing a
+    /// paint that grew it. Nothing happens unless `bounds` fit inside the
+    /// current ones and every painted cell is still within them.
+    pub fn shrink_to(&mut self, bounds: Bounds) {
+        if self.bounds.union(&bounds) != self.bounds {
+            return;
+        }
+        if self.painted().iter().all(|&(cell, _)| bounds.contains(cell)) {
+            self.bounds = bounds;
         }
     }
+
+    /// Set the tile value at a specified point (in pixels). Points outside
+    /// the layer's bounds grow it.
+    pub fn set_px(&mut self, point: (i32, i32), tile: u32) {
+        let cell = self.point_to_cell(point);
+        self.set_cell(cell, tile);
+    }
+}
+
+#[test]
+fn point_to_cell() {
+    let map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 25,
+                            vec![0;25*16]);
+    assert_eq!((0, 0), map.point_to_cell((0, 0)));
+    assert_eq!((1, 0), map.point_to_cell((1*16, 0)));
+    assert_eq!((2, 0), map.point_to_cell((2*16 + 15, 0)));
+    assert_eq!((0, 1), map.point_to_cell((0, 1*16)));
+    assert_eq!((0, 2), map.point_to_cell((0, 2*16)));
+    assert_eq!((-1, -1), map.point_to_cell((-1, -16)));
+    assert_eq!((-2, 0), map.point_to_cell((-17, 15)));
 }
 
 #[test]
-fn point_to_index() {
-    let map = MapLayer::new("foobar", (16, 16), 25, vec![0;25*16]);
-    assert_eq!(Some(0), map.point_to_index((0, 0)));
-    assert_eq!(Some(1), map.point_to_index((1*16, 0)));
-    assert_eq!(Some(2), map.point_to_index((2*16, 0)));
-    assert_eq!(Some(25), map.point_to_index((0, 1*16)));
-    assert_eq!(Some(50), map.point_to_index((0, 2*16)));
-    assert_eq!(Some(0), map.point_to_index((0, 0)));
+fn chunk_of_handles_negative_cells() {
+    let n = CHUNK_SIZE as i32;
+    assert_eq!(chunk_of((0, 0)), ((0, 0), 0));
+    assert_eq!(chunk_of((n + 1, 2)), ((1, 0), 2 * n as usize + 1));
+    assert_eq!(chunk_of((-1, -1)), ((-1, -1), (n * n - 1) as usize));
+    assert_eq!(chunk_of((-n, -n - 1)), ((-1, -2), (n * (n - 1)) as usize));
 }
 
 #[test]
 fn width_works() {
-    let map = MapLayer::new("foobar", (16, 16), 25, vec![0;25*16]);
+    let map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 25,
+                            vec![0;25*16]);
     assert_eq!(map.width(), 25);
 }
 
 #[test]
 fn height_works() {
-    let map = MapLayer::new("foobar", (16, 16), 25, vec![0;25*16]);
+    let map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 25,
+                            vec![0;25*16]);
     assert_eq!(map.height(), 16);
 }
 
 #[test]
 fn get_px_returns_none_on_overflow() {
-    let map = MapLayer::new("foobar", (16, 16), 25, vec![0;25*16]);
+    let map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 25,
+                            vec![0;25*16]);
 
     // Passing a very large value as y is likely to overflow when trying
     // to compute the index!
@@ -136,17 +1005,396 @@ fn get_px_returns_none_on_overflow() {
 }
 
 #[test]
-fn set_px_returns_err_on_overflow() {
-    let mut map = MapLayer::new("foobar", (16, 16), 25, vec![0;25*16]);
-    assert_eq!(Err(()), map.set_px((1, i32::max_value()), 0));
+fn set_px_grows_the_layer() {
+    let mut map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 25,
+                                vec![0;25*16]);
+    map.set_px((-40, -1), 7);
+    assert_eq!(map.bounds(), Bounds::new(-3, -1, 28, 17));
+    assert_eq!(map._get_px((-40, -1)), Some(7));
+    assert_eq!(map._get_px((-20, -1)), Some(EMPTY));
+    assert_eq!(map.chunks().len(), 3);
+
+    // But only so far
+    map.set_px((1, REACH * 16 - 1), 3);
+    assert_eq!(map.bounds(), Bounds::new(-3, -1, 28, REACH as u32 + 1));
+    map.set_px((1, REACH * 16), 3);
+    map.set_px((1, i32::max_value()), 3);
+    map.set_px((i32::min_value(), 0), 3);
+    assert_eq!(map.bounds(), Bounds::new(-3, -1, 28, REACH as u32 + 1));
+    assert_eq!(map._get_px((1, i32::max_value())), None);
+}
+
+#[test]
+fn properties_at_looks_up_tileset() {
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.set_properties(1, TileProperties {
+        solid: true,
+        ..TileProperties::default()
+    });
+    let map = MapLayer::new(tileset, 2, vec![0, 1, 1, 0]);
+
+    assert_eq!(map.properties_at((0, 0)), None);
+    assert!(map.properties_at((20, 4)).unwrap().solid);
+    assert!(map.properties_at((15, 31)).unwrap().solid);
+    assert_eq!(map.properties_at((32, 0)), None);
+    assert_eq!(map.properties_at((-20, 0)), None);
+}
+
+#[test]
+fn collision_map_follows_solid_tiles() {
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.set_properties(1, TileProperties {
+        solid: true,
+        ..TileProperties::default()
+    });
+    tileset.set_properties(2, TileProperties {
+        terrain: Some("water".into()),
+        ..TileProperties::default()
+    });
+    let map = MapLayer::new(tileset, 2, vec![0, 1, 2, 1]);
+    let collision = map.collision_map();
+
+    assert!(!collision.is_solid((0, 0)));
+    assert!(collision.is_solid((1, 0)));
+    assert!(!collision.is_solid((0, 1)));
+    assert!(collision.is_solid((1, 1)));
 }
 
 #[test]
 fn serialize_works() {
-    let map = MapLayer::new("foobar", (16, 16), 4,
+    let map = MapLayer::new(Tileset::strip("foobar", (16, 16)), 4,
         vec![0, 1, 2, 3, 4, 5, 6, 7]);
     assert_eq!(
         map.serialize(),
-        "{\"asset\":\"foobar\",\"tile_w\":16,\"tile_h\":16,\"width\":4,\
-         \"tiles\":[0,1,2,3,4,5,6,7]}");
+        format!("{{\"asset\":\"foobar\",\"tile_w\":16,\"tile_h\":16,\
+                 \"bounds\":{{\"x\":0,\"y\":0,\"width\":4,\"height\":2}},\
+                 \"chunks\":[{{\"x\":0,\"y\":0,\"tiles\":{}}}]}}",
+                chunk_json(4, &[0, 1, 2, 3, 4, 5, 6, 7])));
+}
+
+/// The JSON for a chunk at the origin holding a grid of `tiles`, `width`
+/// cells wide.
+#[cfg(test)]
+fn chunk_json(width: usize, tiles: &[u32]) -> String {
+    let n = CHUNK_SIZE as usize;
+    let mut chunk = vec![EMPTY; n * n];
+    for (i, &tile) in tiles.iter().enumerate() {
+        chunk[i / width * n + i % width] = tile;
+    }
+    serde_json::to_string(&chunk).unwrap()
+}
+
+/// An area of the world big enough to see all of any map in these tests.
+#[cfg(test)]
+pub fn everywhere() -> Rect {
+    Rect::new(-10000, -10000, 20000, 20000)
+}
+
+#[cfg(test)]
+fn two_layer_map() -> Map {
+    let mut overhead = Tileset::strip("roof", (16, 16));
+    overhead.set_properties(5, TileProperties {
+        solid: true,
+        ..TileProperties::default()
+    });
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(Tileset::strip("grass", (16, 16)), 2,
+                                         vec![0, 1, 2, 3]));
+    map.push("overhead", 1, MapLayer::new(overhead, 2, vec![0, 0, 5, 0]));
+    map
+}
+
+#[test]
+fn map_round_trips() {
+    let mut map = two_layer_map();
+    map.set_visible("overhead", false).unwrap();
+
+    let s = map.serialize();
+    let bounds = "\"bounds\":{\"x\":0,\"y\":0,\"width\":2,\"height\":2}";
+    assert_eq!(s,
+        format!("{{\"version\":3,\"layers\":[\
+         {{\"name\":\"ground\",\"z_index\":-1,\"visible\":true,\
+          \"layer\":{{\"asset\":\"grass\",\"tile_w\":16,\"tile_h\":16,{},\
+                     \"chunks\":[{{\"x\":0,\"y\":0,\"tiles\":{}}}]}}}},\
+         {{\"name\":\"overhead\",\"z_index\":1,\"visible\":false,\
+          \"layer\":{{\"asset\":\"roof\",\"tile_w\":16,\"tile_h\":16,{},\
+                     \"chunks\":[{{\"x\":0,\"y\":0,\"tiles\":{}}}]}}}}],\
+         \"objects\":[]}}",
+        bounds, chunk_json(2, &[0, 1, 2, 3]),
+        bounds, chunk_json(2, &[0, 0, 5, 0]))
+        .replace(" ", ""));
+    assert_eq!(Map::deserialize(&s).unwrap().serialize(), s);
+}
+
+#[test]
+fn old_versions_are_migrated() {
+    // Version 0, a single layer
+    let v0 = "{\"asset\":\"grass\",\"tile_w\":16,\"tile_h\":16,\
+               \"width\":2,\"tiles\":[0,1,2,3]}";
+    // Version 1, a stack of layers with no version or objects
+    let v1 = "{\"layers\":[{\"name\":\"ground\",\"z_index\":-1,\
+               \"visible\":true,\"layer\":{\"asset\":\"grass\",\
+               \"tile_w\":16,\"tile_h\":16,\"width\":2,\
+               \"tiles\":[0,1,2,3]}}]}";
+    // Version 2, with objects, but each layer still a grid
+    let v2 = "{\"version\":2,\"layers\":[{\"name\":\"ground\",\
+               \"z_index\":-1,\"visible\":true,\"layer\":{\"asset\":\
+               \"grass\",\"tile_w\":16,\"tile_h\":16,\"width\":2,\
+               \"tiles\":[0,1,2,3]}}],\"objects\":[]}";
+
+    let mut expected = Map::new();
+    let grass = Tileset::strip("grass", (16, 16));
+    expected.push("ground", -1, MapLayer::new(grass, 2, vec![0, 1, 2, 3]));
+    for old in &[v0, v1, v2] {
+        let map = Map::deserialize(old).unwrap();
+        assert_eq!(map.serialize(), expected.serialize());
+    }
+}
+
+#[test]
+fn newer_versions_are_refused() {
+    let mut json: Value = serde_json::from_str(&two_layer_map().serialize())
+        .unwrap();
+    json["version"] = json!(VERSION + 1);
+    let e = Map::deserialize(&json.to_string()).err().unwrap();
+    assert!(e.to_string().contains(&format!(
+        "version {} of the format, but this build only understands versions \
+         up to {}", VERSION + 1, VERSION)), "{}", e);
+}
+
+#[test]
+fn objects_round_trip() {
+    let mut properties = BTreeMap::new();
+    properties.insert("says".into(), "We are Star Men.".into());
+    let mut map = two_layer_map();
+    map.push_objects(ObjectLayer {
+        name: "npcs".into(),
+        objects: vec![Object {
+            name: "starman".into(),
+            kind: "npc".into(),
+            x: 200,
+            y: 154,
+            width: 0,
+            height: 0,
+            properties: properties,
+        }],
+    });
+
+    let loaded = Map::deserialize(&map.serialize()).unwrap();
+    assert_eq!(loaded.object_layers(), map.object_layers());
+}
+
+#[test]
+fn empty_cells_are_not_rendered() {
+    let layer = MapLayer::new(Tileset::strip("foobar", (16, 16)), 2,
+                              vec![EMPTY, 1, 2, EMPTY]);
+    let tileset = layer.tileset().clone();
+    assert_eq!(layer.render(0.0), vec![Tile::new(&tileset, 1, 16, 0),
+                                    Tile::new(&tileset, 2, 0, 16)]);
+    assert_eq!(layer.properties_at((0, 0)), None);
+}
+
+#[test]
+fn single_layer_files_still_load() {
+    let old = "{\"asset\":\"foobar\",\"tile_w\":16,\"tile_h\":16,\
+               \"width\":4,\"tiles\":[0,1,2,3,4,5,6,7]}";
+    let map = Map::deserialize(old).unwrap();
+
+    let ground = map.layer("ground").unwrap();
+    assert_eq!(ground.tiles(), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(map.render(everywhere(), 0.0).len(), 1);
+    assert_eq!(map.render(everywhere(), 0.0)[0].0, -1);
+}
+
+#[test]
+fn hidden_layers_are_not_rendered() {
+    let mut map = two_layer_map();
+    let depths: Vec<_> = map.render(everywhere(), 0.0).iter()
+        .map(|&(z, _)| z).collect();
+    assert_eq!(depths, vec![-1, 1]);
+
+    map.set_visible("ground", false).unwrap();
+    let depths: Vec<_> = map.render(everywhere(), 0.0).iter()
+        .map(|&(z, _)| z).collect();
+    assert_eq!(depths, vec![1]);
+
+    assert_eq!(map.set_visible("sky", false), Err(()));
+}
+
+#[test]
+fn loaded_layers_must_be_within_reach() {
+    let json = two_layer_map().serialize();
+    assert!(json.contains(r#""x":0,"y":0,"tiles""#));
+    let far = json.replace(r#""x":0,"y":0,"tiles""#,
+                           r#""x":0,"y":200,"tiles""#);
+    assert!(Map::deserialize(&far).is_err());
+    let wide = json.replace(r#""width":2"#, r#""width":4000000000"#);
+    assert!(Map::deserialize(&wide).is_err());
+}
+
+#[test]
+fn layers_must_share_a_tile_size() {
+    let mut map = two_layer_map();
+    map.push("big", 2, MapLayer::new(Tileset::strip("big", (32, 32)), 1,
+                                     vec![0]));
+    assert!(Map::deserialize(&map.serialize()).is_err());
+    assert!(Map::deserialize(&two_layer_map().serialize()).is_ok());
+}
+
+#[test]
+fn map_collision_combines_layers() {
+    let collision = two_layer_map().collision_map();
+    assert!(!collision.is_solid((0, 0)));
+    assert!(collision.is_solid((0, 1)));
+}
+
+#[test]
+fn only_chunks_in_view_are_rendered() {
+    let tileset = Tileset::strip("foobar", (16, 16));
+    let mut layer = MapLayer::new(tileset.clone(), 0, vec![]);
+    let n = (CHUNK_SIZE * 16) as i32;
+    layer.set_px((0, 0), 1);
+    layer.set_px((-1, -1), 2);
+    layer.set_px((n * 3, 0), 3);
+
+    assert_eq!(layer.render_in(Rect::new(0, 0, 100, 100), 0.0),
+               vec![Tile::new(&tileset, 1, 0, 0)]);
+    assert_eq!(layer.render_in(Rect::new(-100, -100, 100, 100), 0.0),
+               vec![Tile::new(&tileset, 2, -16, -16)]);
+    assert_eq!(layer.render_in(Rect::new(-100, -100, 200, 200), 0.0).len(),
+               2);
+    assert_eq!(layer.render_in(Rect::new(n * 3 + 15, -5, 10, 10), 0.0),
+               vec![Tile::new(&tileset, 3, n * 3, 0)]);
+    assert_eq!(layer.render_in(Rect::new(n, 0, n as u32, n as u32), 0.0),
+               vec![]);
+}
+
+#[test]
+fn grown_maps_round_trip() {
+    let mut map = two_layer_map();
+    map.layer_mut("ground").unwrap().set_px((-100, -300), 9);
+    assert_eq!(map.bounds(), Bounds::new(-7, -19, 9, 21));
+
+    let loaded = Map::deserialize(&map.serialize()).unwrap();
+    assert_eq!(loaded.serialize(), map.serialize());
+    let ground = loaded.layer("ground").unwrap();
+    assert_eq!(ground._get_px((-100, -300)), Some(9));
+    assert_eq!(ground._get_px((16, 16)), Some(3));
+
+    // The edge of the world moves out with the painting
+    let collision = loaded.collision_map();
+    assert!(!collision.is_solid((-7, -19)));
+    assert!(collision.is_solid((-8, -19)));
+}
+
+#[test]
+fn tiles_outside_the_area_are_culled() {
+    let tileset = Tileset::strip("foobar", (16, 16));
+    let layer = MapLayer::new(tileset.clone(), 4,
+                              vec![1, 2, 3, 4,
+                                   5, 6, 7, 8]);
+
+    // Just touching a tile is enough to draw it
+    assert_eq!(layer.render_in(Rect::new(20, 10, 13, 10), 0.0),
+               vec![Tile::new(&tileset, 2, 16, 0),
+                    Tile::new(&tileset, 3, 32, 0),
+                    Tile::new(&tileset, 6, 16, 16),
+                    Tile::new(&tileset, 7, 32, 16)]);
+    assert_eq!(layer.render_in(Rect::new(63, 31, 100, 100), 0.0),
+               vec![Tile::new(&tileset, 8, 48, 16)]);
+    assert_eq!(layer.render_in(Rect::new(64, 0, 100, 100), 0.0), vec![]);
+}
+
+#[test]
+fn tile_count_follows_painting() {
+    let mut map = two_layer_map();
+    assert_eq!(map.layer("ground").unwrap().tile_count(), 4);
+
+    map.layer_mut("ground").unwrap().set_px((0, 0), EMPTY);
+    map.layer_mut("ground").unwrap().set_px((-50, 0), 4);
+    map.layer_mut("ground").unwrap().set_px((16, 0), 6);
+    assert_eq!(map.layer("ground").unwrap().tile_count(), 4);
+    map.layer_mut("ground").unwrap().set_px((16, 16), EMPTY);
+    assert_eq!(map.layer("ground").unwrap().tile_count(), 3);
+}
+
+#[test]
+fn erasing_where_nothing_is_painted_changes_nothing() {
+    let mut map = two_layer_map();
+    let before = map.layer("ground").unwrap().bounds();
+    let revision = map.revision();
+    map.layer_mut("ground").unwrap().set_px((-50, 0), EMPTY);
+    map.layer_mut("ground").unwrap().set_px((0, 500), EMPTY);
+    assert_eq!(map.layer("ground").unwrap().bounds(), before);
+    assert_eq!(map.revision(), revision);
+}
+
+#[test]
+fn chunks_change_version_when_painted() {
+    let tileset = Tileset::strip("foobar", (16, 16));
+    let mut layer = MapLayer::new(tileset.clone(), 2, vec![1, 2, 3, 4]);
+    layer.set_px((300, 0), 5);
+
+    let before = layer.render_chunks_in(everywhere());
+    assert_eq!(before.len(), 2);
+    assert_eq!(layer.render_chunks_in(everywhere()), before);
+    assert_eq!(layer.render_chunks_in(Rect::new(0, 0, 10, 10)),
+               vec![before[0].clone()]);
+
+    // Only the chunk that was painted changes
+    layer.set_px((20, 20), 9);
+    let after = layer.render_chunks_in(everywhere());
+    assert!(after[0] != before[0]);
+    assert_eq!(after[1], before[1]);
+    assert_eq!(after[0], Chunk::new(
+        &format!(":CHUNK:{}:0,0", layer.id), layer.versions[&(0, 0)],
+        Rect::new(0, 0, 256, 256),
+        Arc::new(vec![Tile::new(&tileset, 1, 0, 0),
+                     Tile::new(&tileset, 2, 16, 0),
+                     Tile::new(&tileset, 3, 0, 16),
+                     Tile::new(&tileset, 9, 16, 16)])));
+
+    // Layers don't share textures
+    let other = MapLayer::new(tileset, 2, vec![1, 2, 3, 4]);
+    assert!(other.render_chunks_in(everywhere())[0] != before[0]);
+}
+
+#[test]
+fn animated_tiles_are_drawn_apart_from_their_chunk() {
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.set_animation(2, vec![(2, 500), (3, 500)]);
+    let mut layer = MapLayer::new(tileset.clone(), 3, vec![1, 2, 1]);
+
+    // Plain rendering shows the frame for the time
+    assert_eq!(layer.render(0.75), vec![Tile::new(&tileset, 1, 0, 0),
+                                        Tile::new(&tileset, 3, 16, 0),
+                                        Tile::new(&tileset, 1, 32, 0)]);
+
+    // Chunks leave a hole for the animated tile, which comes on its own
+    assert_eq!(layer.render_chunks_in(everywhere()), vec![Chunk::new(
+        &format!(":CHUNK:{}:0,0", layer.id), layer.versions[&(0, 0)],
+        Rect::new(0, 0, 256, 256),
+        Arc::new(vec![Tile::new(&tileset, 1, 0, 0),
+                      Tile::new(&tileset, 1, 32, 0)]))]);
+    assert_eq!(layer.render_animated_in(everywhere(), 0.25),
+               vec![Tile::new(&tileset, 2, 16, 0)]);
+    assert_eq!(layer.render_animated_in(Rect::new(32, 0, 16, 16), 0.25),
+               vec![]);
+
+    // The map keeps the base tile, whatever the time
+    assert_eq!(layer.tiles(), vec![1, 2, 1]);
+
+    layer.set_cell((1, 0), 1);
+    layer.set_cell((5, 5), 2);
+    assert_eq!(layer.render_animated_in(everywhere(), 0.75),
+               vec![Tile::new(&tileset, 3, 80, 80)]);
+
+    // Each layer's animated tiles come with its own chunks
+    let mut map = Map::new();
+    map.push("water", -1, MapLayer::new(tileset.clone(), 1, vec![2]));
+    map.push("bridge", -1, MapLayer::new(tileset.clone(), 1, vec![1]));
+    let layers = map.render_layers(everywhere(), 0.0);
+    assert_eq!(layers.iter().map(|l| (l.0, l.1.len(), l.2.len()))
+                   .collect::<Vec<_>>(),
+               vec![(-1, 1, 1), (-1, 1, 0)]);
 }
@@ -0,0 +1,225 @@
+//! Overviews of whole maps: drawn into a PNG, for design reviews and the
+//! like, or shrunk into a minimap in a corner of the screen.
+use carboxyl::Signal;
+use map::Map;
+use ratio::{Ratio, Scalable};
+use scene::{Anchor, Layer, Rectangle, Scene, View};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use software::{Image, SoftwareContext, SoftwareRenderer};
+use std::cmp::min;
+use std::i32;
+use std::io;
+use std::path::Path;
+
+/// The most pixels across or down an overview can be, however it's scaled.
+const MAX_SIZE: u32 = 16384;
+
+/// The part of a map painted so far, in pixels, or None if it's too big to
+/// draw. All its layers are expected to share the first one's tile size.
+fn area(map: &Map) -> Option<Rect> {
+    let (tw, th) = map.layers().first()
+        .map_or((0, 0), |l| l.layer().tileset().tile_size());
+    let bounds = map.bounds();
+    let px = |cells: i64, size: u32| {
+        let px = cells * size as i64;
+        if px.abs() <= i32::MAX as i64 / 2 { Some(px as i32) } else { None }
+    };
+    match (px(bounds.x as i64, tw), px(bounds.y as i64, th),
+           px(bounds.width as i64, tw), px(bounds.height as i64, th)) {
+        (Some(x), Some(y), Some(w), Some(h)) =>
+            Some(Rect::new(x, y, w as u32, h as u32)),
+        _ => None,
+    }
+}
+
+/// Draw every visible layer of a map, all of it, at `scale`. Anywhere
+/// nothing is painted is left transparent. It's an error if the image
+/// would be more than MAX_SIZE pixels across or down.
+pub fn render(map: &Map, scale: Ratio<u32>) -> io::Result<Image> {
+    let too_big = || io::Error::new(io::ErrorKind::InvalidData, format!(
+        "the map is too big to draw more than {} pixels across at this \
+         scale", MAX_SIZE));
+    let area = try!(area(map).ok_or_else(&too_big));
+    // Scaled the long way round, since it could overflow a u32
+    let scaled = |n: u32| {
+        n as u64 * *scale.numer() as u64 / *scale.denom() as u64
+    };
+    if scaled(area.width()) > MAX_SIZE as u64 ||
+            scaled(area.height()) > MAX_SIZE as u64 {
+        return Err(too_big());
+    }
+    let mut layer = Layer::new("map",
+        View::new(scale, Anchor::TopLeft, (area.x(), area.y())));
+    for (z_index, tiles) in map.render(area, 0.0) {
+        layer.add_all(tiles, z_index);
+    }
+    let mut scene = Scene::new();
+    scene.push(layer);
+
+    let mut image = Image::new(area.width().scale(scale),
+                               area.height().scale(scale));
+    {
+        let mut context = SoftwareContext::new();
+        let mut renderer = SoftwareRenderer::new(&mut image, &mut context,
+                                                 (0, 0),
+                                                 Ratio::from_integer(1));
+        scene.show(&mut renderer);
+    }
+    Ok(image)
+}
+
+/// Draw the map in one file into a PNG in another, without opening a
+/// window.
+pub fn export_png<P, Q>(map: P, png: Q, scale: Ratio<u32>) -> io::Result<()>
+        where P: AsRef<Path>, Q: AsRef<Path> {
+    let map = try!(Map::from_file(map));
+    if map.bounds().is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "there's nothing painted on the map"));
+    }
+    try!(render(&map, scale)).save_png(png)
+}
+
+/// Read a scale like `2` or `1/4`. Scales must be more than zero.
+pub fn parse_scale(s: &str) -> Option<Ratio<u32>> {
+    let mut parts = s.splitn(2, '/');
+    let numer = parts.next().and_then(|n| n.trim().parse().ok());
+    let denom = match parts.next() {
+        Some(d) => d.trim().parse().ok(),
+        None => Some(1),
+    };
+    match (numer, denom) {
+        (Some(n), Some(d)) if n > 0 && d > 0 => Some(Ratio::new(n, d)),
+        _ => None,
+    }
+}
+
+/// How far the minimap is kept from the edges of the screen, in output
+/// pixels.
+const MARGIN: i32 = 8;
+
+/// How big the mark for the hero is, in output pixels.
+const MARK: i32 = 4;
+
+/// The whole map, shrunk to fit in the bottom-right corner of the screen,
+/// with a mark where the hero is.
+pub struct Minimap {
+    // Where the hero is, in pixels
+    hero: Signal<(i32, i32)>,
+    // The most of the screen the map can take up, in output pixels
+    size: (u32, u32),
+}
+
+impl Minimap {
+    pub fn new(hero: Signal<(i32, i32)>, size: (u32, u32)) -> Minimap {
+        Minimap {
+            hero: hero,
+            size: size,
+        }
+    }
+
+    /// The minimap as a layer of its own, to go over the others. Its
+    /// chunks are the same cached textures the world is drawn from, so
+    /// it's cheap to make every frame, and keeps up with edits. Animated
+    /// tiles are shown as they are `time` seconds into the game.
+    pub fn render(&self, map: &Map, time: f32) -> Layer {
+        let area = match area(map) {
+            Some(area) => area,
+            None => return Layer::new("minimap", View::identity()),
+        };
+        let scale = if area.width() == 0 || area.height() == 0 {
+            Ratio::from_integer(1)
+        } else {
+            min(Ratio::new(self.size.0, area.width()),
+                Ratio::new(self.size.1, area.height()))
+        };
+        let margin = MARGIN.scale(scale.recip());
+        let corner = (area.x() + area.width() as i32 + margin,
+                      area.y() + area.height() as i32 + margin);
+        let mut layer = Layer::new("minimap",
+            View::new(scale, Anchor::BottomRight, corner));
+
+        layer.add(Rectangle::filled(area, Color::RGBA(0, 0, 0, 255)),
+                  i32::MIN);
+        for (z_index, chunks, tiles) in map.render_layers(area, time) {
+            layer.add_all(chunks, z_index);
+            layer.add_all(tiles, z_index);
+        }
+
+        let (x, y) = self.hero.sample();
+        let mark = MARK.scale(scale.recip());
+        layer.add(Rectangle::filled(
+            Rect::new(x - mark / 2, y - mark / 2, mark as u32, mark as u32),
+            Color::RGBA(255, 32, 32, 255)), i32::MAX);
+        layer
+    }
+}
+
+#[cfg(test)]
+fn test_map() -> Map {
+    use map::MapLayer;
+    use tileset::Tileset;
+    let mut map = Map::new();
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    map.push("ground", -1, MapLayer::new(tileset, 25, vec![2; 25 * 16]));
+    map
+}
+
+#[test]
+fn maps_are_drawn_whole_at_any_scale() {
+    let map = test_map();
+    let image = render(&map, Ratio::from_integer(1)).unwrap();
+    assert_eq!((image.width(), image.height()), (400, 256));
+    let image = render(&map, Ratio::new(1, 4)).unwrap();
+    assert_eq!((image.width(), image.height()), (100, 64));
+
+    // Nor too big to keep in memory
+    assert!(render(&map, Ratio::from_integer(64)).is_err());
+}
+
+#[test]
+fn scales_are_whole_numbers_or_fractions() {
+    assert_eq!(parse_scale("2"), Some(Ratio::from_integer(2)));
+    assert_eq!(parse_scale("1/4"), Some(Ratio::new(1, 4)));
+    assert_eq!(parse_scale("0"), None);
+    assert_eq!(parse_scale("1/0"), None);
+    assert_eq!(parse_scale("big"), None);
+}
+
+#[test]
+fn the_minimap_sits_in_the_corner_with_the_hero_marked() {
+    let map = test_map();
+    let minimap = Minimap::new(Signal::new((100, 50)), (160, 120));
+    let layer = minimap.render(&map, 0.0);
+
+    // 400x256 pixels of map shrink to 160x102 and a bit, fitting the width
+    let view = layer.view();
+    assert_eq!(view.scale, Ratio::new(2, 5));
+    assert_eq!(view.unproject((960 - 8, 600 - 8), (960, 600)), (400, 256));
+
+    let mark = layer.instructions().last().unwrap();
+    assert_eq!(mark.op(), &::scene::Op::Rect(Rectangle::filled(
+        Rect::new(95, 45, 10, 10), Color::RGBA(255, 32, 32, 255))));
+}
+
+#[test]
+fn the_minimap_shows_animated_tiles() {
+    use map::MapLayer;
+    use scene::Op;
+    use tileset::Tileset;
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.set_animation(2, vec![(2, 500), (3, 500)]);
+    let mut map = Map::new();
+    map.push("water", -1, MapLayer::new(tileset, 2, vec![1, 2, 1, 1]));
+    let minimap = Minimap::new(Signal::new((0, 0)), (160, 120));
+
+    let tiles = |time| minimap.render(&map, time).instructions().iter()
+        .filter_map(|i| match *i.op() {
+            Op::Tile(ref tile) => Some(tile.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tiles(0.25).len(), 1);
+    assert!(tiles(0.25) != tiles(0.75));
+}
@@ -0,0 +1,246 @@
+//! Finding paths around the solid cells of a map, for anything that walks
+//! about on its own.
+//!
+//! The navigation grid is the map's collision map: any cell can be walked
+//! through unless it's solid, and everything off the map is. Paths are
+//! found with A*, optionally moving diagonally too, but never cutting the
+//! corner of a solid cell.
+use collision::CollisionMap;
+use map::{Map, floor_div};
+use std::collections::{BinaryHeap, HashMap};
+
+/// What a step costs: ten per cell across, fourteen diagonally (a little
+/// under ten times the square root of two).
+const STRAIGHT: i32 = 10;
+const DIAGONAL: i32 = 14;
+
+/// How many cells a search looks at before giving up. Without a limit, a
+/// goal walled off from the start would have the whole map searched.
+const BUDGET: usize = 4096;
+
+/// How many paths are kept before the cache is thrown away and started
+/// again.
+const CACHE_SIZE: usize = 256;
+
+/// The cheapest a path between two cells could possibly be.
+fn estimate((ax, ay): (i32, i32), (bx, by): (i32, i32), diagonal: bool)
+        -> i32 {
+    let (dx, dy) = ((ax - bx).abs(), (ay - by).abs());
+    if diagonal {
+        let (short, long) = if dx < dy { (dx, dy) } else { (dy, dx) };
+        short * DIAGONAL + (long - short) * STRAIGHT
+    } else {
+        (dx + dy) * STRAIGHT
+    }
+}
+
+/// The cells on the cheapest path from `from` to `to`, both included, or
+/// None if there isn't one, or it couldn't be found within BUDGET cells.
+/// Starting on a solid cell is fine; it's only left, never entered.
+pub fn astar(grid: &CollisionMap, from: (i32, i32), to: (i32, i32),
+             diagonal: bool) -> Option<Vec<(i32, i32)>> {
+    search(grid, from, to, diagonal, BUDGET)
+}
+
+/// `astar`, giving up after looking at the neighbours of `budget` cells.
+fn search(grid: &CollisionMap, from: (i32, i32), to: (i32, i32),
+          diagonal: bool, mut budget: usize) -> Option<Vec<(i32, i32)>> {
+    if grid.is_solid(to) {
+        return None;
+    }
+    // The heap is a max-heap, so costs go in negated
+    let mut open = BinaryHeap::new();
+    let mut cost = HashMap::new();
+    let mut came_from = HashMap::new();
+    open.push((-estimate(from, to, diagonal), from));
+    cost.insert(from, 0);
+
+    while let Some((guess, cell)) = open.pop() {
+        // A cell is pushed again each time a cheaper way to it is found;
+        // the older entries are stale
+        if -guess > cost[&cell] + estimate(cell, to, diagonal) {
+            continue;
+        }
+        if cell == to {
+            let mut path = vec![to];
+            while let Some(&previous) = came_from.get(path.last().unwrap()) {
+                path.push(previous);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if budget == 0 {
+            return None;
+        }
+        budget -= 1;
+        let (x, y) = cell;
+        for dy in -1..2 {
+            for dx in -1..2 {
+                let next = (x + dx, y + dy);
+                let straight = dx == 0 || dy == 0;
+                if (dx, dy) == (0, 0) || grid.is_solid(next) ||
+                        !straight && !diagonal {
+                    continue;
+                }
+                // Squeezing diagonally between two solid cells, or past
+                // the corner of one, isn't allowed
+                if !straight && (grid.is_solid((x + dx, y)) ||
+                                 grid.is_solid((x, y + dy))) {
+                    continue;
+                }
+                let step = if straight { STRAIGHT } else { DIAGONAL };
+                let through = cost[&cell] + step;
+                if cost.get(&next).map_or(true, |&c| through < c) {
+                    cost.insert(next, through);
+                    came_from.insert(next, cell);
+                    let guess = through + estimate(next, to, diagonal);
+                    open.push((-guess, next));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Finds paths on a map, remembering them until the map is edited.
+pub struct Pathfinder {
+    diagonal: bool,
+    // The map's revision when the grid was made
+    revision: Option<usize>,
+    grid: Option<CollisionMap>,
+    cache: HashMap<((i32, i32), (i32, i32)), Option<Vec<(i32, i32)>>>,
+}
+
+impl Pathfinder {
+    /// A pathfinder that moves diagonally as well as across, if
+    /// `diagonal`.
+    pub fn new(diagonal: bool) -> Pathfinder {
+        Pathfinder {
+            diagonal: diagonal,
+            revision: None,
+            grid: None,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The way from `from` to `to`, in pixels, as the points to walk to one
+    /// after the other: the middle of each cell along the way, after the
+    /// one `from` is in. None if `to` can't be reached.
+    pub fn find(&mut self, map: &Map, from: (i32, i32), to: (i32, i32))
+            -> Option<Vec<(i32, i32)>> {
+        let revision = map.revision();
+        if self.revision != Some(revision) {
+            self.revision = Some(revision);
+            self.grid = Some(map.collision_map());
+            self.cache.clear();
+        }
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.clear();
+        }
+
+        let grid = self.grid.as_ref().unwrap();
+        let (tw, th) = grid.tile_size();
+        let (tw, th) = (tw as i32, th as i32);
+        let cell = |(x, y): (i32, i32)| (floor_div(x, tw), floor_div(y, th));
+        let (from, to) = (cell(from), cell(to));
+        let diagonal = self.diagonal;
+        let path = self.cache.entry((from, to))
+            .or_insert_with(|| astar(grid, from, to, diagonal));
+        path.as_ref().map(|cells| cells.iter().skip(1)
+            .map(|&(x, y)| (x * tw + tw / 2, y * th + th / 2))
+            .collect())
+    }
+}
+
+/// A collision map of 16px cells drawn as rows of text, `#` for solid.
+#[cfg(test)]
+fn grid(rows: &[&str]) -> CollisionMap {
+    use map::Bounds;
+    let mut solid = ::std::collections::HashSet::new();
+    for (y, row) in rows.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            if c == '#' {
+                solid.insert((x as i32, y as i32));
+            }
+        }
+    }
+    let bounds = Bounds::new(0, 0, rows[0].len() as u32, rows.len() as u32);
+    CollisionMap::new((16, 16), bounds, solid)
+}
+
+#[test]
+fn paths_go_around_walls() {
+    let walls = grid(&["....",
+                       ".##.",
+                       ".#..",
+                       "...."]);
+    let path = astar(&walls, (0, 2), (2, 2), false).unwrap();
+    assert_eq!(path.len(), 5);
+    assert_eq!(path[0], (0, 2));
+    assert_eq!(path[4], (2, 2));
+    for pair in path.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        assert_eq!((a.0 - b.0).abs() + (a.1 - b.1).abs(), 1);
+        assert!(!walls.is_solid(b));
+    }
+
+    // Going diagonally is cheaper, given the room
+    let open = grid(&["...",
+                      "...",
+                      "..."]);
+    assert_eq!(astar(&open, (0, 0), (2, 2), true),
+               Some(vec![(0, 0), (1, 1), (2, 2)]));
+}
+
+#[test]
+fn paths_dont_cut_corners() {
+    let corner = grid(&[".#",
+                        ".."]);
+    assert_eq!(astar(&corner, (0, 0), (1, 1), true),
+               Some(vec![(0, 0), (0, 1), (1, 1)]));
+
+    // Nor squeeze between two solid cells touching at the corners
+    let gap = grid(&[".#",
+                     "#."]);
+    assert_eq!(astar(&gap, (0, 0), (1, 1), true), None);
+}
+
+#[test]
+fn unreachable_and_solid_goals_have_no_path() {
+    let split = grid(&["..#.",
+                       "..#."]);
+    assert_eq!(astar(&split, (0, 0), (3, 1), true), None);
+    assert_eq!(astar(&split, (0, 0), (2, 0), true), None);
+    assert_eq!(astar(&split, (0, 0), (9, 9), true), None);
+    assert_eq!(astar(&split, (1, 1), (1, 1), true), Some(vec![(1, 1)]));
+}
+
+#[test]
+fn searches_give_up_past_their_budget() {
+    let open = grid(&["........"]);
+    assert_eq!(search(&open, (0, 0), (7, 0), false, 7).map(|p| p.len()),
+               Some(8));
+    assert_eq!(search(&open, (0, 0), (7, 0), false, 6), None);
+}
+
+#[test]
+fn paths_are_pixel_waypoints_and_forgotten_after_edits() {
+    use map::MapLayer;
+    use tileset::{TileProperties, Tileset};
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    tileset.set_properties(1, TileProperties {
+        solid: true,
+        ..Default::default()
+    });
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset, 3, vec![0; 9]));
+
+    let mut pathfinder = Pathfinder::new(false);
+    assert_eq!(pathfinder.find(&map, (4, 4), (40, 4)),
+               Some(vec![(24, 8), (40, 8)]));
+
+    // A wall between them makes the way longer
+    map.layer_mut("ground").unwrap().set_cell((1, 0), 1);
+    assert_eq!(pathfinder.find(&map, (4, 4), (40, 4)),
+               Some(vec![(8, 24), (24, 24), (40, 24), (40, 8)]));
+}
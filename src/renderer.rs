@@ -1,7 +1,7 @@
 use num::rational::Ratio;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::render::{Texture, TextureQuery};
+use sdl2::render::{BlendMode, Texture, TextureQuery};
 use sdl2;
 use sdl2_image::LoadTexture;
 use sdl2_ttf;
@@ -9,7 +9,7 @@ use std::collections::HashMap;
 use std::path::Path;
 
 /// Specifies a draw rect's horizontal position and alignment
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum HPos {
     Left(i32),
     Right(i32),
@@ -18,7 +18,7 @@ pub enum HPos {
 }
 
 /// Specifies a draw rect's vertical position and alignment
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum VPos {
     Top(i32),
     Bottom(i32),
@@ -41,6 +41,8 @@ fn load_texture(asset: &str, renderer: &sdl2::render::Renderer) -> Texture {
 /// XXX: rename?
 pub struct RenderContext {
     textures: HashMap<String, Texture>,
+    // The version of each prerendered texture
+    versions: HashMap<String, usize>,
     ttf: sdl2_ttf::Sdl2TtfContext,
 }
 
@@ -48,6 +50,7 @@ impl RenderContext {
     pub fn new(ttf: sdl2_ttf::Sdl2TtfContext) -> RenderContext {
         RenderContext {
             textures: HashMap::new(),
+            versions: HashMap::new(),
             ttf: ttf,
         }
     }
@@ -69,6 +72,20 @@ impl RenderContext {
         self.textures.insert(name.into(), tex);
     }
 
+    /// Whether a prerendered texture is in cache at the given version.
+    pub fn has_version(&self, name: &str, version: usize) -> bool {
+        self.versions.get(name) == Some(&version)
+    }
+
+    /// Cache a prerendered texture, replacing any older version of it.
+    /// XXX: textures are never evicted, so chunks of maps that have since
+    /// been dropped stay around until the context is.
+    pub fn add_prerendered(&mut self, name: &str, version: usize,
+                           tex: Texture) {
+        self.add_texture(name, tex);
+        self.versions.insert(name.into(), version);
+    }
+
     /// Return a texture if it exists in cache, otherwise None.
     pub fn get_texture(&mut self, asset: &str,
                        renderer: &sdl2::render::Renderer) -> Option<&Texture> {
@@ -87,33 +104,121 @@ impl RenderContext {
 }
 
 /// Renderer: 1. n. A person or thing that renders.
-pub struct Renderer<'a> {
+///
+/// This is the drawing surface that `Visible`s show themselves on. It is a
+/// trait so that scenes can be drawn either through SDL (`SdlRenderer`) or
+/// into an in-memory image (`software::SoftwareRenderer`), e.g. in tests.
+pub trait Renderer {
+    /// The size of the rendering surface, in output pixels.
+    fn output_size(&self) -> (u32, u32);
+
+    /// Set the translation and scale applied to subsequent drawing.
+    fn set_transform(&mut self, offset: (i32, i32), scale: Ratio<u32>);
+
+    /// Query the dimensions of a named texture, if it can be loaded.
+    fn query(&mut self, asset: &str) -> Option<(u32, u32)>;
+
+    /// Copy a named texture onto the rendering surface at specified
+    /// destination rect, with optional source rect.
+    fn copy(&mut self, asset: &str, src: Option<Rect>, dst: Rect);
+
+    /// Draw a string using a specified font.
+    fn draw_text(&mut self, text: &str, font: &str, x: i32, y: i32);
+
+    /// Draw a non-filled rectangle onto the target surface.
+    /// NB: note that multiple rectangles drawn in the same layer could be
+    /// optimized into a draw_rects() call. Something to think about when
+    /// implementing the widget system.
+    fn draw_rect(&mut self, rect: Rect, color: Color);
+
+    /// Draw a filled rectangle onto the target surface.
+    fn fill_rect(&mut self, rect: Rect, color: Color);
+
+    /// Make sure there's a texture called `name` holding what `draw` draws
+    /// in `area`, at 1:1 scale, so it can be copied like any other. `draw`
+    /// uses the same coordinates as everything else; only `area` ends up in
+    /// the texture. The texture is kept, and only drawn again if asked for
+    /// with a different `version`.
+    fn prerender(&mut self, name: &str, version: usize, area: Rect,
+                 draw: &Fn(&mut Renderer));
+
+    /// Draw a named texture at its native size, aligned as specified.
+    fn draw(&mut self, asset: &str, hpos: HPos, vpos: VPos) {
+        let (width, height) = self.query(asset).unwrap();
+        let dst = align(width, height, hpos, vpos);
+        self.copy(asset, None, dst);
+    }
+}
+
+/// Compute the destination rect for an object of a given size, positioned
+/// and aligned according to `hpos` and `vpos`.
+pub fn align(width: u32, height: u32, hpos: HPos, vpos: VPos) -> Rect {
+    let width = width as i32;
+    let height = height as i32;
+
+    let (x1, x2) = match hpos {
+        HPos::Left(x) => (x, x + width),
+        HPos::Right(x) => (x - width, x),
+        HPos::Center(x) => (x - width/2, x + width/2),
+        HPos::Stretch(x, w) => (x, x+w as i32),
+    };
+
+    let (y1, y2) = match vpos {
+        VPos::Top(y) => (y, y + height),
+        VPos::Bottom(y) => (y - height, y),
+        VPos::Center(y) => (y - height/2, y + height/2),
+        VPos::Stretch(y, h) => (y, y+h as i32),
+    };
+
+    Rect::new(x1, y1, (x2 - x1) as u32, (y2 - y1) as u32)
+}
+
+/// A Renderer that draws through an SDL renderer, using a RenderContext
+/// to cache textures.
+pub struct SdlRenderer<'a> {
     renderer: &'a mut sdl2::render::Renderer<'static>,
+    context: &'a mut RenderContext,
     offset: (i32, i32),
     original_scale: (f32, f32),
 }
 
-impl<'a> Renderer<'a> {
-    /// Create a new Renderer, wrapping an existing SDL Renderer and
+impl<'a> SdlRenderer<'a> {
+    /// Create a new SdlRenderer, wrapping an existing SDL Renderer and
     /// setting up drawing for the specified scale and translation.
     pub fn new(renderer: &'a mut sdl2::render::Renderer<'static>,
+               context: &'a mut RenderContext,
                offset: (i32, i32),
-               scale: Ratio<u32>) -> Renderer<'a> {
-        let scale = *scale.numer() as f32 / *scale.denom() as f32;
+               scale: Ratio<u32>) -> SdlRenderer<'a> {
         let original_scale = renderer.scale();
-        renderer.set_scale(scale, scale).unwrap();
-        Renderer {
+        let mut result = SdlRenderer {
             renderer: renderer,
+            context: context,
             offset: offset,
             original_scale: original_scale,
-        }
+        };
+        result.set_transform(offset, scale);
+        result
     }
+}
 
-    /// Copy a named texture onto the rendering surface at specified
-    /// destination rect, with optional source rect.
-    pub fn copy(&mut self, context: &mut RenderContext,
-            asset: &str, src: Option<Rect>, dst: Rect) {
-        let tex = context.get_texture(asset, &self.renderer).unwrap();
+impl<'a> Renderer for SdlRenderer<'a> {
+    fn output_size(&self) -> (u32, u32) {
+        self.renderer.output_size().unwrap()
+    }
+
+    fn set_transform(&mut self, offset: (i32, i32), scale: Ratio<u32>) {
+        let scale = *scale.numer() as f32 / *scale.denom() as f32;
+        self.renderer.set_scale(scale, scale).unwrap();
+        self.offset = offset;
+    }
+
+    fn query(&mut self, asset: &str) -> Option<(u32, u32)> {
+        self.context.query(asset, self.renderer)
+            .map(|query| (query.width, query.height))
+    }
+
+    fn copy(&mut self, asset: &str, src: Option<Rect>, dst: Rect) {
+        let tex = self.context.get_texture(asset, self.renderer).unwrap();
 
         let (dx, dy) = self.offset;
         let x = (dst.x() - dx) as i32;
@@ -125,82 +230,72 @@ impl<'a> Renderer<'a> {
         self.renderer.copy(tex, src, Some(dst));
     }
 
-    pub fn draw(&mut self, context: &mut RenderContext,
-                asset: &str, hpos: HPos, vpos: VPos) {
-        let query = context.query(asset, &self.renderer).unwrap();
-        let width = query.width as i32;
-        let height = query.height as i32;
-
-        let (x1, x2) = match hpos {
-            HPos::Left(x) => (x, x + width),
-            HPos::Right(x) => (x - width, x),
-            HPos::Center(x) => (x - width/2, x + width/2),
-            HPos::Stretch(x, w) => (x, x+w as i32),
-        };
-
-        let (y1, y2) = match vpos {
-            VPos::Top(y) => (y, y + height),
-            VPos::Bottom(y) => (y - height, y),
-            VPos::Center(y) => (y - height/2, y + height/2),
-            VPos::Stretch(y, h) => (y, y+h as i32),
-        };
-
-        let dst = Rect::new(x1, y1, (x2 - x1) as u32, (y2 - y1) as u32);
-        self.copy(context, asset, None, dst);
-    }
-
-    /// Draw the nth tile of specified size from a given image asset, at a
-    /// specific position, assuming top-left alignment.
-    /// XXX: Assumes that tiles are laid out in a single horizontal strip.
-    pub fn draw_tile(&mut self, context: &mut RenderContext,
-                     tileset: &str, n: u32, w: u32, h: u32,
-                     x: i32, y: i32) {
-        let src = Rect::new((n * w) as i32, 0, w, h);
-        let dst = Rect::new(x, y, w, h);
-        self.copy(context, tileset, Some(src), dst);
-    }
-
-    /// Draw a string using a specified font.
-    pub fn draw_text(&mut self, context: &mut RenderContext,
-                     text: &str, font: &str, x: i32, y: i32) {
+    fn draw_text(&mut self, text: &str, font: &str, x: i32, y: i32) {
         // Check the texture cache for this string
         let id = format!(":STR:{}:{}", font, text);
 
-        if !context.has_texture(&id) {
+        if !self.context.has_texture(&id) {
             let path = font.to_string() + ".ttf";
             let path = Path::new(&path);
-            let font = context.ttf.load_font(path, 14).unwrap();
+            let texture = {
+                let font = self.context.ttf.load_font(path, 14).unwrap();
 
-            // render a surface, and convert it to a texture
-            // XXX: configurable text color
-            let surface = font.render(text)
-                .solid(Color::RGBA(224, 224, 224, 255)).unwrap();
-            let texture = self.renderer
-                .create_texture_from_surface(&surface).unwrap();
+                // render a surface, and convert it to a texture
+                // XXX: configurable text color
+                let surface = font.render(text)
+                    .solid(Color::RGBA(224, 224, 224, 255)).unwrap();
+                self.renderer.create_texture_from_surface(&surface).unwrap()
+            };
 
-            context.add_texture(&id, texture);
+            self.context.add_texture(&id, texture);
         }
 
-        self.draw(context, &id, HPos::Center(x), VPos::Center(y));
+        self.draw(&id, HPos::Center(x), VPos::Center(y));
     }
 
-    /// Draw a non-filled rectangle onto the target surface.
-    /// NB: note that multiple rectangles drawn in the same layer could be
-    /// optimized into a draw_rects() call. Something to think about when
-    /// implementing the widget system.
-    pub fn draw_rect(&mut self, rect: Rect, color: Color) {
+    fn draw_rect(&mut self, rect: Rect, color: Color) {
+        let (dx, dy) = self.offset;
+        let rect = Rect::new(rect.x() - dx, rect.y() - dy,
+                             rect.width(), rect.height());
         self.renderer.set_draw_color(color);
         self.renderer.draw_rect(rect).unwrap();
     }
 
-    /// Draw a filled rectangle onto the target surface.
-    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+    fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let (dx, dy) = self.offset;
+        let rect = Rect::new(rect.x() - dx, rect.y() - dy,
+                             rect.width(), rect.height());
         self.renderer.set_draw_color(color);
         self.renderer.fill_rect(rect).unwrap();
     }
+
+    fn prerender(&mut self, name: &str, version: usize, area: Rect,
+                 draw: &Fn(&mut Renderer)) {
+        if self.context.has_version(name, version) {
+            return;
+        }
+
+        let target = self.renderer.create_texture_target(
+            PixelFormatEnum::RGBA8888, area.width(), area.height()).unwrap();
+        self.renderer.render_target().unwrap().set(target).unwrap();
+        let (offset, scale) = (self.offset, self.renderer.scale());
+        self.offset = (area.x(), area.y());
+        self.renderer.set_scale(1.0, 1.0).unwrap();
+        self.renderer.set_draw_color(Color::RGBA(0, 0, 0, 0));
+        self.renderer.clear();
+
+        draw(self);
+
+        let mut texture = self.renderer.render_target().unwrap()
+            .reset().unwrap().unwrap();
+        texture.set_blend_mode(BlendMode::Blend);
+        self.context.add_prerendered(name, version, texture);
+        self.offset = offset;
+        self.renderer.set_scale(scale.0, scale.1).unwrap();
+    }
 }
 
-impl<'a> Drop for Renderer<'a> {
+impl<'a> Drop for SdlRenderer<'a> {
     fn drop(&mut self) {
         // Restore the borrowed renderer to its original scale
         let (sx, sy) = self.original_scale;
@@ -1,103 +1,356 @@
 use carboxyl::{Signal, Stream, Sink};
 use num::rational::Ratio;
-use renderer::{Renderer, RenderContext, HPos, VPos};
+use ratio::Scalable;
+use renderer::{Renderer, RenderContext, SdlRenderer, HPos, VPos};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::cmp::Ordering::{Greater, Less};
+use std::sync::Arc;
+use tileset::Tileset;
 
 /// A `Visible` object can be shown using a renderer. It is atomic with respect
 /// to z-ordering, i.e., it is always entirely behind or entirely in front of
 /// other visible objects. Rendering a frame or scene is a process of creating
 /// many Visibles, sorting them by layer and z-index, and showing them.
 pub trait Visible {
-    fn show(&self, renderer: &mut Renderer, context: &mut RenderContext);
+    fn show(&self, renderer: &mut Renderer);
 }
 
-/// A scene Instruction is a Visible and a z-index at which it is to be shown.
-pub struct Instruction<'a> {
+/// A primitive drawing operation. Scenes own their operations by value, so
+/// they can be built independently of whatever produced them, compared in
+/// tests, and carried around in signals.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Op {
+    Tex(Tex),
+    Sprite(Sprite),
+    Tile(Tile),
+    Chunk(Chunk),
+    Text(Text),
+    Rect(Rectangle),
+}
+
+impl Visible for Op {
+    fn show(&self, renderer: &mut Renderer) {
+        match *self {
+            Op::Tex(ref tex) => tex.show(renderer),
+            Op::Sprite(ref sprite) => sprite.show(renderer),
+            Op::Tile(ref tile) => tile.show(renderer),
+            Op::Chunk(ref chunk) => chunk.show(renderer),
+            Op::Text(ref text) => text.show(renderer),
+            Op::Rect(ref rect) => rect.show(renderer),
+        }
+    }
+}
+
+impl Op {
+    /// The vertical position at which this op "touches the ground", used to
+    /// order ops in y-sorted layers: the bottom edge of its destination.
+    /// Sprites and text aren't sized until they're drawn, so only sprites
+    /// that are bottom-aligned or stretched have a foot; anything else
+    /// panics, rather than quietly sorting as if it stood higher up.
+    pub fn foot(&self) -> i32 {
+        match *self {
+            Op::Tex(ref tex) => tex.dst.y() + tex.dst.height() as i32,
+            Op::Sprite(ref sprite) => match sprite.vpos {
+                VPos::Bottom(y) => y,
+                VPos::Stretch(y, h) => y + h as i32,
+                VPos::Top(_) | VPos::Center(_) => panic!(
+                    "sprite `{}` isn't bottom-aligned, so it can't be \
+                     y-sorted", sprite.name),
+            },
+            Op::Tile(ref tile) => tile.y + tile.src.height() as i32,
+            Op::Chunk(ref chunk) => chunk.dst.y() + chunk.dst.height() as i32,
+            Op::Text(ref text) => panic!(
+                "text `{}` has no known height, so it can't be y-sorted",
+                text.text),
+            Op::Rect(ref rect) => rect.rect.y() + rect.rect.height() as i32,
+        }
+    }
+}
+
+impl From<Tex> for Op {
+    fn from(tex: Tex) -> Op {
+        Op::Tex(tex)
+    }
+}
+
+impl From<Sprite> for Op {
+    fn from(sprite: Sprite) -> Op {
+        Op::Sprite(sprite)
+    }
+}
+
+impl From<Tile> for Op {
+    fn from(tile: Tile) -> Op {
+        Op::Tile(tile)
+    }
+}
+
+impl From<Chunk> for Op {
+    fn from(chunk: Chunk) -> Op {
+        Op::Chunk(chunk)
+    }
+}
+
+impl From<Text> for Op {
+    fn from(text: Text) -> Op {
+        Op::Text(text)
+    }
+}
+
+impl From<Rectangle> for Op {
+    fn from(rect: Rectangle) -> Op {
+        Op::Rect(rect)
+    }
+}
+
+/// A scene Instruction is an Op and a z-index at which it is to be shown.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Instruction {
     z_index: i32,
-    object: &'a Visible,
+    op: Op,
 }
 
-impl<'a> Ord for Instruction<'a> {
-    fn cmp(&self, other: &Instruction) -> Ordering {
-        other.z_index.cmp(&self.z_index)
+impl Instruction {
+    #[cfg(test)]
+    pub fn op(&self) -> &Op {
+        &self.op
     }
 }
 
-impl<'a> PartialOrd for Instruction<'a> {
-    fn partial_cmp(&self, other: &Instruction) -> Option<Ordering> {
-        other.z_index.partial_cmp(&self.z_index)
+/// The point on the output surface to which a layer is pinned.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// The location of this anchor on an output of the given size, in
+    /// output pixels.
+    pub fn point(&self, (w, h): (u32, u32)) -> (i32, i32) {
+        let (w, h) = (w as i32, h as i32);
+        match *self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (w/2, 0),
+            Anchor::TopRight => (w, 0),
+            Anchor::Left => (0, h/2),
+            Anchor::Center => (w/2, h/2),
+            Anchor::Right => (w, h/2),
+            Anchor::BottomLeft => (0, h),
+            Anchor::Bottom => (w/2, h),
+            Anchor::BottomRight => (w, h),
+        }
     }
 }
 
-impl<'a> PartialEq for Instruction<'a> {
-    fn eq(&self, other: &Instruction) -> bool {
-        self.z_index == other.z_index
+/// How a layer is placed on the output: the layer is drawn at `scale`, with
+/// the layer-space point `translation` appearing at the output's `anchor`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct View {
+    pub scale: Ratio<u32>,
+    pub anchor: Anchor,
+    pub translation: (i32, i32),
+}
+
+impl View {
+    pub fn new(scale: Ratio<u32>, anchor: Anchor, translation: (i32, i32))
+            -> View {
+        View {
+            scale: scale,
+            anchor: anchor,
+            translation: translation,
+        }
+    }
+
+    /// An unscaled view that maps layer space directly onto the output.
+    pub fn identity() -> View {
+        View::new(Ratio::from_integer(1), Anchor::TopLeft, (0, 0))
+    }
+
+    /// The layer-space point that appears at the output's top-left corner.
+    pub fn origin(&self, output: (u32, u32)) -> (i32, i32) {
+        let (ax, ay) = self.anchor.point(output);
+        let (tx, ty) = self.translation;
+        (tx - ax.scale(self.scale.recip()), ty - ay.scale(self.scale.recip()))
+    }
+
+    /// Map a point on the output into layer space.
+    pub fn unproject(&self, (x, y): (i32, i32), output: (u32, u32))
+            -> (i32, i32) {
+        let (ox, oy) = self.origin(output);
+        (x.scale(self.scale.recip()) + ox, y.scale(self.scale.recip()) + oy)
+    }
+
+    /// The part of layer space that can be seen on the output. It's rounded
+    /// out to whole pixels, so it may take in a little more.
+    pub fn area(&self, (w, h): (u32, u32)) -> Rect {
+        let (x0, y0) = self.unproject((0, 0), (w, h));
+        let (x1, y1) = self.unproject((w as i32, h as i32), (w, h));
+        Rect::new(x0, y0, (x1 - x0) as u32 + 1, (y1 - y0) as u32 + 1)
     }
 }
 
-impl<'a> Eq for Instruction<'a> { }
+/// How a layer orders elements that share a z-index.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Sort {
+    /// Elements are drawn in the order they were added.
+    Insertion,
+    /// Elements whose feet are further down the screen are drawn in front,
+    /// the usual overlap rule for characters in a top-down world. Elements
+    /// with their feet at the same height are drawn in insertion order.
+    Y,
+}
 
-/// A Scene is a place where Visibles may be shown.
-pub struct Scene<'a> {
-    elements: BinaryHeap<Instruction<'a>>,
+/// A Layer is a set of drawing operations sorted by z-index and drawn
+/// through a common View. Layers are composited in order, so every element
+/// of a layer is drawn in front of all the layers before it.
+///
+/// Ordering within a layer is always deterministic: elements are sorted by
+/// z-index, then by the layer's Sort mode.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Layer {
+    name: String,
+    view: View,
+    sort: Sort,
+    // Kept sorted back to front
+    elements: Vec<Instruction>,
 }
 
-impl<'a> Scene<'a> {
-    pub fn new() -> Scene<'a> {
-        Scene {
-            elements: BinaryHeap::new(),
+impl Layer {
+    pub fn new(name: &str, view: View) -> Layer {
+        Layer {
+            name: name.into(),
+            view: view,
+            sort: Sort::Insertion,
+            elements: Vec::new(),
+        }
+    }
+
+    /// Create a layer whose elements are y-sorted within each z-index.
+    pub fn y_sorted(name: &str, view: View) -> Layer {
+        Layer {
+            sort: Sort::Y,
+            ..Layer::new(name, view)
+        }
+    }
+
+    /// The key elements of this layer are sorted by, back to front.
+    fn key(&self, z_index: i32, op: &Op) -> (i32, i32) {
+        match self.sort {
+            Sort::Insertion => (z_index, 0),
+            Sort::Y => (z_index, op.foot()),
         }
     }
 
-    pub fn add(&mut self, element: &'a Visible, z_index: i32) {
-        self.elements.push(Instruction {
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    pub fn add<V: Into<Op>>(&mut self, element: V, z_index: i32) {
+        let op = element.into();
+
+        // Insert after everything that sorts equal or further back, so that
+        // ties are broken by insertion order.
+        let key = self.key(z_index, &op);
+        // The comparison never says Equal, so this always misses, landing
+        // on the first element that sorts after.
+        let index = match self.elements.binary_search_by(|e| {
+            if self.key(e.z_index, &e.op) > key { Greater } else { Less }
+        }) {
+            Ok(index) | Err(index) => index,
+        };
+        self.elements.insert(index, Instruction {
             z_index: z_index,
-            object: element
+            op: op,
         });
     }
 
     /// Add multiple objects with the same z-index
-    pub fn add_all<P: Visible>(&mut self, elements: &'a Vec<P>, z_index: i32) {
+    pub fn add_all<V, I>(&mut self, elements: I, z_index: i32)
+            where V: Into<Op>, I: IntoIterator<Item=V> {
         for obj in elements {
             self.add(obj, z_index);
         }
     }
 
-    /// Remove the rearmost element from the Scene and return it.
-    /// Panics if the scene is empty.
-    pub fn pop(&mut self) -> Instruction<'a> {
-        self.elements.pop().unwrap()
+    /// The layer's instructions, in the order they will be drawn.
+    #[cfg(test)]
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.elements
     }
 
-    /// Presents the scene onto the specified renderer.
-    /// Consumes the scene's contents in the process.
-    pub fn present(mut self, renderer: &mut sdl2::render::Renderer<'static>,
-                   context: &mut RenderContext,
-                   translation: (i32, i32),
-                   scale: Ratio<u32>) {
-        let mut renderer = Renderer::new(renderer, translation, scale);
-        loop {
-            match self.elements.pop() {
-                Some(element) => element.object.show(&mut renderer, context),
-                None => break
-            }
+    /// Remove the rearmost element from the Layer and return it.
+    /// Panics if the layer is empty.
+    #[cfg(test)]
+    pub fn pop(&mut self) -> Instruction {
+        self.elements.remove(0)
+    }
+
+    /// Shows the layer's elements back to front, using whatever transform
+    /// the renderer currently has.
+    pub fn show(&self, renderer: &mut Renderer) {
+        for element in &self.elements {
+            element.op.show(renderer);
+        }
+    }
+}
+
+/// A Scene is a place where Visibles may be shown. It is a stack of named
+/// layers, which together describe a whole frame.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Scene {
+    layers: Vec<Layer>,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            layers: Vec::new(),
         }
     }
 
-    pub fn present_scaled(self,
-                          renderer: &mut sdl2::render::Renderer<'static>,
-                          context: &mut RenderContext,
-                          scale: Ratio<u32>) {
-        self.present(renderer, context, (0, 0), scale);
+    /// Add a layer in front of all existing layers.
+    pub fn push(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    /// Find a layer by name.
+    #[cfg(test)]
+    pub fn layer(&mut self, name: &str) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|layer| layer.name == name)
+    }
+
+    /// Presents the scene onto the specified SDL renderer.
+    pub fn present(&self, renderer: &mut sdl2::render::Renderer<'static>,
+                   context: &mut RenderContext) {
+        let mut renderer = SdlRenderer::new(renderer, context,
+                                            (0, 0), Ratio::from_integer(1));
+        self.show(&mut renderer);
+    }
+
+    /// Shows the scene on any Renderer backend, layer by layer.
+    pub fn show(&self, renderer: &mut Renderer) {
+        let output = renderer.output_size();
+        for layer in &self.layers {
+            let view = layer.view;
+            renderer.set_transform(view.origin(output), view.scale);
+            layer.show(renderer);
+        }
     }
 }
 
 /// A Visible object that consists of a single texture copy with specified
 /// source and destination. The most primitive texture-based Visible.
+#[derive(Clone, PartialEq, Debug)]
 pub struct Tex {
     asset: String,
     src: Option<Rect>,
@@ -115,8 +368,8 @@ impl Tex {
 }
 
 impl Visible for Tex {
-    fn show(&self, renderer: &mut Renderer, context: &mut RenderContext) {
-        renderer.copy(context, &self.asset, self.src, self.dst);
+    fn show(&self, renderer: &mut Renderer) {
+        renderer.copy(&self.asset, self.src, self.dst);
     }
 }
 
@@ -125,6 +378,7 @@ impl Visible for Tex {
 /// XXX: resolve generality/usefulness mismatch here between Tex, Sprite,
 /// and Tile -- seems like there's too much overlap and we keep bumping into
 /// it in the higher layers.
+#[derive(Clone, PartialEq, Debug)]
 pub struct Sprite {
     name: String,
     hpos: HPos,
@@ -147,30 +401,25 @@ pub fn sprite(name: &str, h: HPos, v: VPos) -> Sprite {
 }
 
 impl Visible for Sprite {
-    fn show(&self, renderer: &mut Renderer, context: &mut RenderContext) {
-        renderer.draw(context, &self.name, self.hpos, self.vpos);
+    fn show(&self, renderer: &mut Renderer) {
+        renderer.draw(&self.name, self.hpos, self.vpos);
     }
 }
 
 /// A Visible object that is a tile drawn from a tileset.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Tile {
     tileset: String,
-    tile: u32,
-    width: u32,
-    height: u32,
+    src: Rect,
     x: i32,
     y: i32,
 }
 
 impl Tile {
-    pub fn new(tileset: &str, tile: u32, w: u32, h: u32,
-               x: i32, y: i32) -> Tile {
+    pub fn new(tileset: &Tileset, tile: u32, x: i32, y: i32) -> Tile {
         Tile {
-            tileset: tileset.into(),
-            tile: tile,
-            width: w,
-            height: h,
+            tileset: tileset.asset().into(),
+            src: tileset.src(tile),
             x: x,
             y: y,
         }
@@ -178,13 +427,56 @@ impl Tile {
 }
 
 impl Visible for Tile {
-    fn show(&self, renderer: &mut Renderer, context: &mut RenderContext) {
-        renderer.draw_tile(context, &self.tileset, self.tile,
-                           self.width, self.height, self.x, self.y);
+    fn show(&self, renderer: &mut Renderer) {
+        let dst = Rect::new(self.x, self.y,
+                            self.src.width(), self.src.height());
+        renderer.copy(&self.tileset, Some(self.src), dst);
+    }
+}
+
+/// A Visible object that is a block of tiles, e.g. a chunk of a map layer.
+/// The tiles are drawn into a texture of their own the first time the
+/// chunk is shown, and after that the texture is copied in one go, until a
+/// chunk with the same name and a new version comes along.
+///
+/// XXX: in a y-sorted layer the whole chunk sorts by its bottom edge, so
+/// it only makes sense to draw chunks at a z-index of their own.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Chunk {
+    name: String,
+    version: usize,
+    dst: Rect,
+    tiles: Arc<Vec<Tile>>,
+}
+
+impl Chunk {
+    /// A chunk covering `dst`, holding `tiles` which are positioned as if
+    /// they were being drawn directly.
+    pub fn new(name: &str, version: usize, dst: Rect, tiles: Arc<Vec<Tile>>)
+            -> Chunk {
+        Chunk {
+            name: name.into(),
+            version: version,
+            dst: dst,
+            tiles: tiles,
+        }
+    }
+}
+
+impl Visible for Chunk {
+    fn show(&self, renderer: &mut Renderer) {
+        let tiles = &self.tiles;
+        renderer.prerender(&self.name, self.version, self.dst, &|r| {
+            for tile in tiles.iter() {
+                tile.show(r);
+            }
+        });
+        renderer.copy(&self.name, None, self.dst);
     }
 }
 
 /// A Visible object that displays text in a given font
+#[derive(Clone, PartialEq, Debug)]
 pub struct Text {
     text: String,
     font: String,
@@ -208,12 +500,13 @@ pub fn text(text: &str, font: &str, x: i32, y: i32) -> Text {
 }
 
 impl Visible for Text {
-    fn show(&self, renderer: &mut Renderer, context: &mut RenderContext) {
-        renderer.draw_text(context, &self.text, &self.font, self.x, self.y);
+    fn show(&self, renderer: &mut Renderer) {
+        renderer.draw_text(&self.text, &self.font, self.x, self.y);
     }
 }
 
 /// A Visible object that is just a rectangle. Woo, rectangles.
+#[derive(Clone, PartialEq, Debug)]
 pub struct Rectangle {
     rect: Rect,
     color: Color,
@@ -229,7 +522,7 @@ impl Rectangle {
         }
     }
 
-    pub fn _unfilled(rect: Rect, color: Color) -> Rectangle {
+    pub fn unfilled(rect: Rect, color: Color) -> Rectangle {
         Rectangle {
             rect: rect,
             color: color,
@@ -239,7 +532,7 @@ impl Rectangle {
 }
 
 impl Visible for Rectangle {
-    fn show(&self, renderer: &mut Renderer, _: &mut RenderContext) {
+    fn show(&self, renderer: &mut Renderer) {
         if self.filled {
             renderer.fill_rect(self.rect, self.color);
         } else {
@@ -252,28 +545,126 @@ impl Visible for Rectangle {
 
 #[test]
 fn scene_pop_works() {
-    #[derive(Eq, PartialEq)]
-    struct Pixel {};
-    impl Visible for Pixel {
-        // XXX: Scene's role as a strict value container perhaps argues against
-        // concrete Renderer and RenderContext dependencies for its items?
-        fn show(&self, _: &mut Renderer, _: &mut RenderContext) { }
+    let fore = Tex::new("fore", None, Rect::new(0, 0, 1, 1));
+    let back = Tex::new("back", None, Rect::new(0, 0, 1, 1));
+
+    let mut s = Layer::new("test", View::identity());
+    s.add(fore.clone(), 10);
+    s.add(back.clone(), 0);
+
+    assert_eq!(s.pop().op(), &Op::Tex(back));
+    assert_eq!(s.pop().op(), &Op::Tex(fore));
+}
+
+#[test]
+fn equal_z_keeps_insertion_order() {
+    let mut layer = Layer::new("test", View::identity());
+    let texts: Vec<_> = (0..20)
+        .map(|i| Text::new(&i.to_string(), "font", 0, 20 - i))
+        .collect();
+    for text in &texts {
+        layer.add(text.clone(), 0);
     }
+    layer.add(Text::new("behind", "font", 0, 0), -1);
+
+    let order: Vec<_> = layer.instructions().iter()
+        .map(|i| i.op().clone())
+        .collect();
+    let mut expected = vec![Op::Text(Text::new("behind", "font", 0, 0))];
+    expected.extend(texts.into_iter().map(Op::Text));
+    assert_eq!(order, expected);
+}
 
-    let fore = Pixel {};
-    let back = Pixel {};
+#[test]
+fn y_sorted_layer_orders_by_foot() {
+    // Two characters at the same depth: whoever stands lower on the screen
+    // is in front, regardless of the order they were added in.
+    let hero = Tile::new(&Tileset::strip("hero", (16, 24)), 0, 80, 100);
+    let starman = sprite("starman", HPos::Center(90), VPos::Bottom(130));
+    let tree = Tex::new("tree", None, Rect::new(70, 60, 32, 48));
+    let ground = Tile::new(&Tileset::strip("ground", (16, 16)), 0, 80, 112);
+
+    let mut layer = Layer::y_sorted("world", View::identity());
+    layer.add(starman.clone(), 0);
+    layer.add(hero.clone(), 0);
+    layer.add(tree.clone(), 0);
+    layer.add(ground.clone(), -1);
+
+    let order: Vec<_> = layer.instructions().iter()
+        .map(|i| i.op().clone())
+        .collect();
+    assert_eq!(order, vec![Op::Tile(ground), Op::Tex(tree),
+                           Op::Tile(hero), Op::Sprite(starman)]);
+}
 
-    let mut s = Scene::new();
-    s.add(&fore, 10);
-    s.add(&back, 0);
+#[test]
+#[should_panic]
+fn y_sorted_sprites_must_stand_on_their_feet() {
+    let mut layer = Layer::y_sorted("world", View::identity());
+    layer.add(sprite("porky", HPos::Left(4), VPos::Top(30)), 0);
+}
 
-    s.pop();
-    s.pop();
+#[test]
+fn y_sorted_ties_keep_insertion_order() {
+    let tileset = Tileset::strip("tiles", (16, 16));
+    let a = Tile::new(&tileset, 0, 0, 0);
+    let b = Tile::new(&tileset, 1, 32, 0);
+    let c = Tile::new(&tileset, 2, 64, 0);
+
+    let mut layer = Layer::y_sorted("world", View::identity());
+    layer.add(b.clone(), 0);
+    layer.add(a.clone(), 0);
+    layer.add(c.clone(), 0);
+
+    let order: Vec<_> = layer.instructions().iter()
+        .map(|i| i.op().clone())
+        .collect();
+    assert_eq!(order, vec![Op::Tile(b), Op::Tile(a), Op::Tile(c)]);
+}
 
-    // XXX: the way Scene deals with render instructions as trait objects
-    // makes it difficult to test! The only way to actually interact with
-    // a Visible right now is to show it on a Renderer.
-    //assert_eq!(&fore, s.pop());
+#[test]
+fn scenes_can_be_compared() {
+    let build = |text: &str| {
+        let mut hud = Layer::new("hud", View::identity());
+        hud.add(Rectangle::filled(Rect::new(0, 0, 8, 8),
+                                  Color::RGBA(0, 0, 0, 255)), 0);
+        hud.add(Text::new(text, "font", 4, 4), 1);
+        let mut s = Scene::new();
+        s.push(hud);
+        s
+    };
+
+    assert_eq!(build("hello"), build("hello"));
+    assert!(build("hello") != build("goodbye"));
+    assert_eq!(build("hello").clone(), build("hello"));
+}
+
+#[test]
+fn view_origin_uses_anchor_and_scale() {
+    let view = View::new(Ratio::from_integer(2), Anchor::Center, (100, 50));
+    assert_eq!(view.origin((80, 60)), (80, 35));
+
+    let view = View::new(Ratio::new(1, 2), Anchor::BottomRight, (0, 0));
+    assert_eq!(view.origin((80, 60)), (-160, -120));
+
+    assert_eq!(View::identity().origin((80, 60)), (0, 0));
+}
+
+#[test]
+fn view_unproject_inverts_origin() {
+    let view = View::new(Ratio::from_integer(4), Anchor::Center, (100, 50));
+    assert_eq!(view.unproject((480, 300), (960, 600)), (100, 50));
+    assert_eq!(view.unproject((0, 0), (960, 600)), (-20, -25));
+}
+
+#[test]
+fn scene_finds_layers_by_name() {
+    let mut s = Scene::new();
+    s.push(Layer::new("world", View::identity()));
+    s.push(Layer::new("hud", View::new(Ratio::from_integer(2),
+                                       Anchor::TopLeft, (0, 0))));
+    assert_eq!(s.layer("hud").unwrap().view().scale, Ratio::from_integer(2));
+    assert!(s.layer("gui").is_none());
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -344,3 +735,9 @@ fn population_exists() {
     let current_pop = p.sample();
     assert_eq!(current_pop[0].sample(), sprite);
 }
+
+#[test]
+fn view_area_covers_the_output() {
+    let view = View::new(Ratio::from_integer(4), Anchor::Center, (100, 50));
+    assert_eq!(view.area((960, 600)), Rect::new(-20, -25, 241, 151));
+}
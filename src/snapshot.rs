@@ -0,0 +1,260 @@
+//! Golden-image tests: scenes are rendered offscreen with the software
+//! renderer and compared pixel-for-pixel against PNGs checked in under
+//! `snapshots/`. When a comparison fails, the actual image and a diff image
+//! are written to `target/snapshots/` for inspection.
+//!
+//! Run the tests with `BLESS=1` to (re)write the stored snapshots from the
+//! current output instead of comparing against them.
+use map::MapLayer;
+use num::rational::Ratio;
+use renderer::{HPos, VPos};
+use scene::{Anchor, Layer, Rectangle, Scene, View, sprite, text};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use software::{Image, SoftwareContext, SoftwareRenderer, rgba};
+use std::cmp::max;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use textbox::Textbox;
+use tileset::Tileset;
+
+/// Render a scene offscreen onto a background of a single color.
+pub fn render(scene: &Scene, (width, height): (u32, u32), background: Color)
+        -> Image {
+    let mut image = Image::new(width, height);
+    image.clear(background);
+    {
+        let mut context = SoftwareContext::new();
+        let mut renderer = SoftwareRenderer::new(&mut image, &mut context,
+                                                 (0, 0), Ratio::from_integer(1));
+        scene.show(&mut renderer);
+    }
+    image
+}
+
+/// A summary of how two images differ.
+#[derive(Debug)]
+pub struct Diff {
+    /// Number of pixels that differ in any channel
+    pub pixels: usize,
+    /// Largest difference seen in any single channel
+    pub max_delta: u8,
+    /// Coordinates of the first differing pixel, in row-major order
+    pub first: (u32, u32),
+    /// An image highlighting differing pixels in red over a faded copy
+    /// of the expected image
+    pub image: Image,
+}
+
+/// Compare two images of the same size pixel by pixel. Returns None if they
+/// are identical.
+pub fn compare(expected: &Image, actual: &Image) -> Option<Diff> {
+    assert_eq!((expected.width(), expected.height()),
+               (actual.width(), actual.height()));
+
+    let mut diff = Diff {
+        pixels: 0,
+        max_delta: 0,
+        first: (0, 0),
+        image: Image::new(expected.width(), expected.height()),
+    };
+
+    for y in 0..expected.height() {
+        for x in 0..expected.width() {
+            let (a, b) = (expected.pixel(x, y), actual.pixel(x, y));
+            let delta = channel_delta(a, b);
+            if delta > 0 {
+                if diff.pixels == 0 {
+                    diff.first = (x, y);
+                }
+                diff.pixels += 1;
+                diff.max_delta = max(diff.max_delta, delta);
+                diff.image.set_pixel(x, y, Color::RGBA(255, 0, 0, 255));
+            } else {
+                diff.image.set_pixel(x, y, fade(a));
+            }
+        }
+    }
+
+    if diff.pixels == 0 { None } else { Some(diff) }
+}
+
+fn channel_delta(a: Color, b: Color) -> u8 {
+    let (a, b) = (rgba(a), rgba(b));
+    let d = |x: u8, y: u8| if x > y { x - y } else { y - x };
+    max(max(d(a.0, b.0), d(a.1, b.1)), max(d(a.2, b.2), d(a.3, b.3)))
+}
+
+/// A washed-out grayscale version of a color, for diff backgrounds.
+fn fade(c: Color) -> Color {
+    let (r, g, b, _) = rgba(c);
+    let luma = (r as u32 * 3 + g as u32 * 6 + b as u32) / 10;
+    let v = (128 + luma / 2) as u8;
+    Color::RGBA(v, v, v, 255)
+}
+
+fn blessing() -> bool {
+    env::var("BLESS").map(|v| v != "" && v != "0").unwrap_or(false)
+}
+
+/// Compare an image against the stored snapshot with the given name, or
+/// overwrite the snapshot if blessing. Panics with a report on mismatch.
+pub fn assert_snapshot(name: &str, actual: &Image) {
+    let stored = PathBuf::from("snapshots").join(format!("{}.png", name));
+
+    if blessing() {
+        fs::create_dir_all("snapshots").unwrap();
+        actual.save_png(&stored).unwrap();
+        return;
+    }
+
+    let expected = match Image::from_png(&stored) {
+        Ok(image) => image,
+        Err(e) => panic!("snapshot `{}` could not be loaded ({}); \
+                          run with BLESS=1 to create it",
+                         stored.display(), e),
+    };
+
+    let out = PathBuf::from("target").join("snapshots");
+    let actual_path = out.join(format!("{}.actual.png", name));
+
+    if (expected.width(), expected.height()) !=
+       (actual.width(), actual.height()) {
+        fs::create_dir_all(&out).unwrap();
+        actual.save_png(&actual_path).unwrap();
+        panic!("snapshot `{}` is {}x{} but rendered {}x{}; \
+                actual output written to {}",
+               name, expected.width(), expected.height(),
+               actual.width(), actual.height(), actual_path.display());
+    }
+
+    if let Some(diff) = compare(&expected, actual) {
+        let diff_path = out.join(format!("{}.diff.png", name));
+        fs::create_dir_all(&out).unwrap();
+        actual.save_png(&actual_path).unwrap();
+        diff.image.save_png(&diff_path).unwrap();
+        panic!("snapshot `{}` differs in {} of {} pixels (max channel \
+                delta {}, first at {:?}); actual output written to {}, \
+                diff to {}. Run with BLESS=1 to accept the new output.",
+               name, diff.pixels, expected.width() * expected.height(),
+               diff.max_delta, diff.first,
+               actual_path.display(), diff_path.display());
+    }
+}
+
+// Snapshots of scenes built from the same pieces the game uses.
+
+fn background() -> Color {
+    Color::RGBA(176, 208, 184, 255)
+}
+
+#[test]
+fn snapshot_map() {
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let map = MapLayer::new(tileset, 6,
+        vec![0, 1, 2, 3, 4, 5,
+             6, 7, 9, 10, 12, 19,
+             20, 21, 22, 24, 26, 30]);
+    let mut layer = Layer::new("world", View::identity());
+    layer.add_all(map.render(0.0), 0);
+    let mut scene = Scene::new();
+    scene.push(layer);
+    let image = render(&scene, (96, 48), background());
+    assert_snapshot("map", &image);
+}
+
+#[test]
+fn snapshot_map_through_chunks() {
+    // The same map as `snapshot_map`, drawn via chunk textures, which
+    // should make no difference
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let map = MapLayer::new(tileset, 6,
+        vec![0, 1, 2, 3, 4, 5,
+             6, 7, 9, 10, 12, 19,
+             20, 21, 22, 24, 26, 30]);
+    let mut layer = Layer::new("world", View::identity());
+    layer.add_all(map.render_chunks_in(Rect::new(0, 0, 96, 48)), 0);
+    let mut scene = Scene::new();
+    scene.push(layer);
+    let image = render(&scene, (96, 48), background());
+    assert_snapshot("map", &image);
+}
+
+#[test]
+fn snapshot_sprites_scaled() {
+    let back = sprite("assets/starmanjr", HPos::Center(20), VPos::Bottom(60));
+    let front = sprite("assets/porky", HPos::Left(4), VPos::Top(30));
+    let mut layer = Layer::new("world",
+        View::new(Ratio::new(3, 2), Anchor::TopLeft, (-10, 0)));
+    layer.add(front, 1);
+    layer.add(back, 0);
+    let mut scene = Scene::new();
+    scene.push(layer);
+    let image = render(&scene, (160, 120), background());
+    assert_snapshot("sprites_scaled", &image);
+}
+
+#[test]
+fn snapshot_textbox() {
+    let textbox = Textbox::new("assets/box", Rect::new(12, 12, 64, 16));
+    let hello = text("$0.00", "assets/orangekid", 44, 20);
+    let mut layer = Layer::new("hud",
+        View::new(Ratio::from_integer(2), Anchor::TopLeft, (0, 0)));
+    layer.add_all(textbox.render(), 0);
+    layer.add(hello, 1);
+    let mut scene = Scene::new();
+    scene.push(layer);
+    let image = render(&scene, (176, 80), background());
+    assert_snapshot("textbox", &image);
+}
+
+#[test]
+fn snapshot_layers() {
+    // A world layer centered on a sprite, with a HUD and an unscaled GUI
+    // layer composited over it, as in the game's main loop.
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let map = MapLayer::new(tileset, 4,
+        vec![2, 2, 3, 2,
+             2, 24, 2, 2,
+             9, 10, 2, 2,
+             2, 2, 2, 31]);
+    let starman = sprite("assets/starmanjr", HPos::Center(32), VPos::Center(32));
+    let textbox = Textbox::new("assets/box", Rect::new(4, 4, 24, 8));
+    let highlight = Rectangle::filled(Rect::new(0, 72, 32, 8),
+                                      Color::RGBA(32, 32, 32, 255));
+
+    let mut world = Layer::new("world",
+        View::new(Ratio::from_integer(2), Anchor::Center, (32, 32)));
+    world.add_all(map.render(0.0), -1);
+    world.add(starman, 0);
+
+    let mut hud = Layer::new("hud",
+        View::new(Ratio::from_integer(1), Anchor::TopLeft, (0, 0)));
+    hud.add_all(textbox.render(), 1);
+
+    let mut gui = Layer::new("gui", View::identity());
+    gui.add(highlight, 0);
+
+    let mut scene = Scene::new();
+    scene.push(world);
+    scene.push(hud);
+    scene.push(gui);
+    let image = render(&scene, (96, 80), background());
+    assert_snapshot("layers", &image);
+}
+
+#[test]
+fn snapshot_overview() {
+    // A whole map drawn at half size, as by `tarnished export-png`
+    use map::Map;
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset, 4,
+        vec![2, 2, 3, 2,
+             2, 24, 2, 2,
+             9, 10, 2, 2,
+             2, 2, 2, 31]));
+    let image = ::overview::render(&map, Ratio::new(1, 2)).unwrap();
+    assert_snapshot("overview", &image);
+}
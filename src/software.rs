@@ -0,0 +1,512 @@
+use num::rational::Ratio;
+use png::{self, HasParameters};
+use renderer::{Renderer, HPos, VPos};
+use rusttype::{Font, Scale, point};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// An RGBA image held in memory, with one byte per channel.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Create a fully transparent image of the given size.
+    pub fn new(width: u32, height: u32) -> Image {
+        Image {
+            width: width,
+            height: height,
+            pixels: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    /// Decode an image from a PNG file.
+    pub fn from_png<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+        let decoder = png::Decoder::new(try!(File::open(path)));
+        let (info, mut reader) = try!(decoder.read_info()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        let mut buf = vec![0; info.buffer_size()];
+        try!(reader.next_frame(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+        // The decoder expands palettes and low bit depths for us, so all
+        // that's left is to fill in any missing channels.
+        let pixels = match info.color_type {
+            png::ColorType::RGBA => buf,
+            png::ColorType::RGB => buf.chunks(3)
+                .flat_map(|p| vec![p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks(2)
+                .flat_map(|p| vec![p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => buf.iter()
+                .flat_map(|&v| vec![v, v, v, 255])
+                .collect(),
+            png::ColorType::Indexed => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "unexpanded indexed PNG"));
+            }
+        };
+
+        Ok(Image {
+            width: info.width,
+            height: info.height,
+            pixels: pixels,
+        })
+    }
+
+    /// Encode the image as an 8-bit RGBA PNG file.
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(try!(File::create(path)),
+                                            self.width, self.height);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = try!(encoder.write_header()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        writer.write_image_data(&self.pixels)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The raw pixel data, as rows of RGBA bytes.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        ((y * self.width + x) * 4) as usize
+    }
+
+    /// Get the color of a single pixel. Panics if out of bounds.
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        assert!(x < self.width && y < self.height);
+        let i = self.offset(x, y);
+        let p = &self.pixels[i..i+4];
+        Color::RGBA(p[0], p[1], p[2], p[3])
+    }
+
+    /// Overwrite a single pixel. Panics if out of bounds.
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        assert!(x < self.width && y < self.height);
+        let (r, g, b, a) = rgba(color);
+        let i = self.offset(x, y);
+        self.pixels[i] = r;
+        self.pixels[i+1] = g;
+        self.pixels[i+2] = b;
+        self.pixels[i+3] = a;
+    }
+
+    /// Composite a color over a single pixel using its alpha channel, the
+    /// same way SDL's default texture blend mode does.
+    fn blend_pixel(&mut self, x: u32, y: u32, color: Color) {
+        let (r, g, b, a) = rgba(color);
+        match a {
+            0 => return,
+            255 => return self.set_pixel(x, y, color),
+            _ => { }
+        }
+
+        let i = self.offset(x, y);
+        let a = a as u32;
+        let blend = |src: u8, dst: u8| {
+            ((src as u32 * a + dst as u32 * (255 - a)) / 255) as u8
+        };
+        self.pixels[i] = blend(r, self.pixels[i]);
+        self.pixels[i+1] = blend(g, self.pixels[i+1]);
+        self.pixels[i+2] = blend(b, self.pixels[i+2]);
+        self.pixels[i+3] =
+            (a + self.pixels[i+3] as u32 * (255 - a) / 255) as u8;
+    }
+
+    /// Fill the entire image with one color.
+    pub fn clear(&mut self, color: Color) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+/// Split a color into its RGBA components.
+pub fn rgba(color: Color) -> (u8, u8, u8, u8) {
+    match color {
+        Color::RGB(r, g, b) => (r, g, b, 255),
+        Color::RGBA(r, g, b, a) => (r, g, b, a),
+    }
+}
+
+/// The software counterpart to RenderContext: a cache of decoded images
+/// and fonts, keyed by asset name.
+pub struct SoftwareContext {
+    images: HashMap<String, Image>,
+    // The version of each prerendered image
+    versions: HashMap<String, usize>,
+    fonts: HashMap<String, Font<'static>>,
+}
+
+impl SoftwareContext {
+    pub fn new() -> SoftwareContext {
+        SoftwareContext {
+            images: HashMap::new(),
+            versions: HashMap::new(),
+            fonts: HashMap::new(),
+        }
+    }
+
+    pub fn has_image(&self, name: &str) -> bool {
+        self.images.contains_key(name)
+    }
+
+    pub fn add_image(&mut self, name: &str, image: Image) {
+        self.images.insert(name.into(), image);
+    }
+
+    /// Whether a prerendered image is in cache at the given version.
+    pub fn has_version(&self, name: &str, version: usize) -> bool {
+        self.versions.get(name) == Some(&version)
+    }
+
+    /// Cache a prerendered image, replacing any older version of it.
+    pub fn add_prerendered(&mut self, name: &str, version: usize,
+                           image: Image) {
+        self.add_image(name, image);
+        self.versions.insert(name.into(), version);
+    }
+
+    /// Return an image, loading it from `<asset>.png` if it is not already
+    /// in cache. None if it could not be loaded.
+    pub fn get_image(&mut self, asset: &str) -> Option<&Image> {
+        if !self.images.contains_key(asset) {
+            match Image::from_png(asset.to_string() + ".png") {
+                Ok(image) => self.add_image(asset, image),
+                Err(_) => return None,
+            }
+        }
+        self.images.get(asset)
+    }
+
+    /// Return a font, loading it from `<font>.ttf` if necessary.
+    fn get_font(&mut self, font: &str) -> Option<&Font<'static>> {
+        if !self.fonts.contains_key(font) {
+            let mut bytes = Vec::new();
+            let loaded = File::open(font.to_string() + ".ttf")
+                .and_then(|mut f| f.read_to_end(&mut bytes));
+            if loaded.is_err() {
+                return None;
+            }
+            match Font::from_bytes(bytes) {
+                Ok(f) => { self.fonts.insert(font.into(), f); },
+                Err(_) => return None,
+            }
+        }
+        self.fonts.get(font)
+    }
+}
+
+/// Rasterize a line of text into a new image, with no antialiasing, which
+/// is how SDL_ttf's "solid" mode renders it.
+fn render_text(font: &Font, text: &str, color: Color) -> Image {
+    // XXX: SDL_ttf sizes fonts in points; this is only approximately the
+    // same size, so text layout is not pixel-identical between backends.
+    let scale = Scale::uniform(14.0);
+    let v_metrics = font.v_metrics(scale);
+    let glyphs: Vec<_> = font
+        .layout(text, scale, point(0.0, v_metrics.ascent))
+        .collect();
+
+    let width = glyphs.last().map(|g| {
+        g.position().x + g.unpositioned().h_metrics().advance_width
+    }).unwrap_or(0.0).ceil() as u32;
+    let height = (v_metrics.ascent - v_metrics.descent).ceil() as u32;
+
+    let mut image = Image::new(width, height);
+    for glyph in &glyphs {
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|x, y, coverage| {
+                let x = x as i32 + bb.min.x;
+                let y = y as i32 + bb.min.y;
+                if coverage >= 0.5 &&
+                   x >= 0 && x < width as i32 &&
+                   y >= 0 && y < height as i32 {
+                    image.set_pixel(x as u32, y as u32, color);
+                }
+            });
+        }
+    }
+    image
+}
+
+/// Scale a coordinate by a ratio, rounding towards negative infinity.
+fn scale_coord(v: i32, scale: Ratio<u32>) -> i32 {
+    let n = *scale.numer() as i64;
+    let d = *scale.denom() as i64;
+    let v = v as i64 * n;
+    let q = if v >= 0 { v / d } else { (v - d + 1) / d };
+    q as i32
+}
+
+/// A Renderer that rasterizes into an in-memory Image. It needs no display,
+/// so it can be used to draw scenes offscreen, e.g. in tests.
+pub struct SoftwareRenderer<'a> {
+    target: &'a mut Image,
+    context: &'a mut SoftwareContext,
+    offset: (i32, i32),
+    scale: Ratio<u32>,
+}
+
+impl<'a> SoftwareRenderer<'a> {
+    /// Create a new SoftwareRenderer drawing onto `target` with the specified
+    /// scale and translation.
+    pub fn new(target: &'a mut Image,
+               context: &'a mut SoftwareContext,
+               offset: (i32, i32),
+               scale: Ratio<u32>) -> SoftwareRenderer<'a> {
+        SoftwareRenderer {
+            target: target,
+            context: context,
+            offset: offset,
+            scale: scale,
+        }
+    }
+
+    /// Map a rect in drawing coordinates to its (left, top, right, bottom)
+    /// extent in target pixels.
+    fn project(&self, rect: Rect) -> (i32, i32, i32, i32) {
+        (scale_coord(rect.x(), self.scale),
+         scale_coord(rect.y(), self.scale),
+         scale_coord(rect.x() + rect.width() as i32, self.scale),
+         scale_coord(rect.y() + rect.height() as i32, self.scale))
+    }
+
+    /// Clip a projected extent to the bounds of the target image.
+    fn clip(&self, (x0, y0, x1, y1): (i32, i32, i32, i32))
+            -> (i32, i32, i32, i32) {
+        (max(x0, 0), max(y0, 0),
+         min(x1, self.target.width() as i32),
+         min(y1, self.target.height() as i32))
+    }
+}
+
+impl<'a> Renderer for SoftwareRenderer<'a> {
+    fn output_size(&self) -> (u32, u32) {
+        (self.target.width(), self.target.height())
+    }
+
+    fn set_transform(&mut self, offset: (i32, i32), scale: Ratio<u32>) {
+        self.offset = offset;
+        self.scale = scale;
+    }
+
+    fn query(&mut self, asset: &str) -> Option<(u32, u32)> {
+        self.context.get_image(asset)
+            .map(|image| (image.width(), image.height()))
+    }
+
+    fn copy(&mut self, asset: &str, src: Option<Rect>, dst: Rect) {
+        let (dx, dy) = self.offset;
+        let dst = Rect::new(dst.x() - dx, dst.y() - dy,
+                            dst.width(), dst.height());
+        let (x0, y0, x1, y1) = self.project(dst);
+        let (cx0, cy0, cx1, cy1) = self.clip((x0, y0, x1, y1));
+
+        let image = self.context.get_image(asset).unwrap();
+        let src = src.unwrap_or(
+            Rect::new(0, 0, image.width(), image.height()));
+
+        // Nearest-neighbour sampling from the source rect, which is also
+        // what SDL does with its default scale quality.
+        for ty in cy0..cy1 {
+            let sy = src.y() + ((ty - y0) as i64 * src.height() as i64 /
+                                (y1 - y0) as i64) as i32;
+            if sy < 0 || sy >= image.height() as i32 {
+                continue;
+            }
+            for tx in cx0..cx1 {
+                let sx = src.x() + ((tx - x0) as i64 * src.width() as i64 /
+                                    (x1 - x0) as i64) as i32;
+                if sx < 0 || sx >= image.width() as i32 {
+                    continue;
+                }
+                let color = image.pixel(sx as u32, sy as u32);
+                self.target.blend_pixel(tx as u32, ty as u32, color);
+            }
+        }
+    }
+
+    fn draw_text(&mut self, text: &str, font: &str, x: i32, y: i32) {
+        // Check the image cache for this string
+        let id = format!(":STR:{}:{}", font, text);
+
+        if !self.context.has_image(&id) {
+            // XXX: configurable text color
+            let image = render_text(self.context.get_font(font).unwrap(),
+                                    text, Color::RGBA(224, 224, 224, 255));
+            self.context.add_image(&id, image);
+        }
+
+        self.draw(&id, HPos::Center(x), VPos::Center(y));
+    }
+
+    fn draw_rect(&mut self, rect: Rect, color: Color) {
+        let (x, y) = (rect.x(), rect.y());
+        let (w, h) = (rect.width(), rect.height());
+        if w == 0 || h == 0 {
+            return;
+        }
+        self.fill_rect(Rect::new(x, y, w, 1), color);
+        self.fill_rect(Rect::new(x, y + h as i32 - 1, w, 1), color);
+        self.fill_rect(Rect::new(x, y, 1, h), color);
+        self.fill_rect(Rect::new(x + w as i32 - 1, y, 1, h), color);
+    }
+
+    fn fill_rect(&mut self, rect: Rect, color: Color) {
+        // SDL's renderer draws shapes without blending by default, so the
+        // color (alpha included) simply replaces what was there.
+        let (dx, dy) = self.offset;
+        let rect = Rect::new(rect.x() - dx, rect.y() - dy,
+                             rect.width(), rect.height());
+        let (x0, y0, x1, y1) = self.clip(self.project(rect));
+        for y in y0..y1 {
+            for x in x0..x1 {
+                self.target.set_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+
+    fn prerender(&mut self, name: &str, version: usize, area: Rect,
+                 draw: &Fn(&mut Renderer)) {
+        if self.context.has_version(name, version) {
+            return;
+        }
+        let mut image = Image::new(area.width(), area.height());
+        {
+            let mut r = SoftwareRenderer::new(&mut image, self.context,
+                                              (area.x(), area.y()),
+                                              Ratio::from_integer(1));
+            draw(&mut r);
+        }
+        self.context.add_prerendered(name, version, image);
+    }
+}
+
+#[test]
+fn fill_rect_is_scaled() {
+    let mut image = Image::new(8, 8);
+    let mut context = SoftwareContext::new();
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (0, 0), Ratio::from_integer(2));
+        r.fill_rect(Rect::new(1, 1, 2, 1), Color::RGBA(255, 0, 0, 255));
+    }
+    assert_eq!(image.pixel(1, 2), Color::RGBA(0, 0, 0, 0));
+    assert_eq!(image.pixel(2, 2), Color::RGBA(255, 0, 0, 255));
+    assert_eq!(image.pixel(5, 3), Color::RGBA(255, 0, 0, 255));
+    assert_eq!(image.pixel(6, 3), Color::RGBA(0, 0, 0, 0));
+    assert_eq!(image.pixel(2, 4), Color::RGBA(0, 0, 0, 0));
+}
+
+#[test]
+fn rectangles_are_translated() {
+    let mut image = Image::new(8, 8);
+    let mut context = SoftwareContext::new();
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (10, 20), Ratio::from_integer(1));
+        r.draw_rect(Rect::new(11, 21, 3, 3), Color::RGBA(255, 0, 0, 255));
+    }
+    assert_eq!(image.pixel(0, 0), Color::RGBA(0, 0, 0, 0));
+    assert_eq!(image.pixel(1, 1), Color::RGBA(255, 0, 0, 255));
+    assert_eq!(image.pixel(3, 3), Color::RGBA(255, 0, 0, 255));
+    assert_eq!(image.pixel(2, 2), Color::RGBA(0, 0, 0, 0));
+}
+
+#[test]
+fn copy_translates_and_samples_source() {
+    let mut tiles = Image::new(2, 1);
+    tiles.set_pixel(0, 0, Color::RGBA(255, 0, 0, 255));
+    tiles.set_pixel(1, 0, Color::RGBA(0, 0, 255, 255));
+
+    let mut image = Image::new(4, 4);
+    let mut context = SoftwareContext::new();
+    context.add_image("tiles", tiles);
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (-1, -1), Ratio::from_integer(1));
+        r.copy("tiles", Some(Rect::new(1, 0, 1, 1)), Rect::new(0, 0, 2, 2));
+    }
+    assert_eq!(image.pixel(0, 0), Color::RGBA(0, 0, 0, 0));
+    assert_eq!(image.pixel(1, 1), Color::RGBA(0, 0, 255, 255));
+    assert_eq!(image.pixel(2, 2), Color::RGBA(0, 0, 255, 255));
+    assert_eq!(image.pixel(3, 3), Color::RGBA(0, 0, 0, 0));
+}
+
+#[test]
+fn copy_blends_alpha() {
+    let mut dot = Image::new(1, 1);
+    dot.set_pixel(0, 0, Color::RGBA(255, 255, 255, 128));
+
+    let mut image = Image::new(1, 1);
+    image.clear(Color::RGBA(0, 0, 0, 255));
+    let mut context = SoftwareContext::new();
+    context.add_image("dot", dot);
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (0, 0), Ratio::from_integer(1));
+        r.draw("dot", HPos::Left(0), VPos::Top(0));
+    }
+    assert_eq!(image.pixel(0, 0), Color::RGBA(128, 128, 128, 255));
+}
+
+#[test]
+fn prerendered_images_are_kept_until_their_version_changes() {
+    let red = Color::RGBA(255, 0, 0, 255);
+    let blue = Color::RGBA(0, 0, 255, 255);
+    let mut context = SoftwareContext::new();
+    for &(name, color) in &[("red", red), ("blue", blue)] {
+        let mut dot = Image::new(1, 1);
+        dot.set_pixel(0, 0, color);
+        context.add_image(name, dot);
+    }
+
+    let mut image = Image::new(4, 4);
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (0, 0), Ratio::from_integer(2));
+        let area = Rect::new(10, 10, 2, 2);
+        r.prerender("block", 1, area,
+                    &|r| r.copy("red", None, Rect::new(11, 10, 1, 1)));
+        // Same version, so this isn't drawn
+        r.prerender("block", 1, area,
+                    &|r| r.copy("blue", None, Rect::new(10, 10, 2, 2)));
+        r.copy("block", None, Rect::new(0, 0, 2, 2));
+    }
+    assert_eq!(image.pixel(0, 0), Color::RGBA(0, 0, 0, 0));
+    assert_eq!(image.pixel(2, 0), red);
+    assert_eq!(image.pixel(3, 1), red);
+    assert_eq!(image.pixel(2, 2), Color::RGBA(0, 0, 0, 0));
+
+    {
+        let mut r = SoftwareRenderer::new(&mut image, &mut context,
+                                          (0, 0), Ratio::from_integer(2));
+        r.prerender("block", 2, Rect::new(10, 10, 2, 2),
+                    &|r| r.copy("blue", None, Rect::new(10, 10, 2, 2)));
+        r.copy("block", None, Rect::new(0, 0, 2, 2));
+    }
+    assert_eq!(image.pixel(0, 0), blue);
+    assert_eq!(image.pixel(3, 3), blue);
+}
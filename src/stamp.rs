@@ -0,0 +1,235 @@
+//! Stamps: rectangles of tiles painted as one, like a house or a tree, and
+//! libraries of them that can be shared as prefabs.
+//!
+//! A library is a JSON file mapping names to stamps, e.g.
+//!
+//! ```json
+//! {"version": 1, "stamps": {"tree": {"asset": "assets/cotp", "width": 2,
+//!   "height": 3, "tiles": [4294967295, 12, 13, 14, 15, 16]}}}
+//! ```
+//!
+//! Cells holding EMPTY (4294967295) are see-through: painting the stamp
+//! leaves whatever was there alone.
+use map::{EMPTY, MapLayer};
+use serde_json;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::io;
+use std::path::Path;
+use tileset::Tileset;
+
+/// A rectangle of tiles from one tileset, row by row.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Stamp {
+    asset: String,
+    width: u32,
+    height: u32,
+    tiles: Vec<u32>,
+}
+
+impl Stamp {
+    /// The tiles in the rectangle of a tileset's image with tiles `a` and
+    /// `b` at its corners, or None if either is EMPTY. Tilesets in a strip
+    /// only have one row.
+    pub fn from_tileset(tileset: &Tileset, a: u32, b: u32) -> Option<Stamp> {
+        if a == EMPTY || b == EMPTY {
+            return None;
+        }
+        let columns = tileset.columns().unwrap_or(max(a, b) + 1);
+        let (left, right) = (min(a % columns, b % columns),
+                             max(a % columns, b % columns));
+        let (top, bottom) = (min(a / columns, b / columns),
+                             max(a / columns, b / columns));
+        let mut tiles = Vec::new();
+        for row in top..bottom + 1 {
+            for col in left..right + 1 {
+                tiles.push(row * columns + col);
+            }
+        }
+        Some(Stamp {
+            asset: tileset.asset().into(),
+            width: right - left + 1,
+            height: bottom - top + 1,
+            tiles: tiles,
+        })
+    }
+
+    /// Copy the cells of a layer in the rectangle with cells `a` and `b` at
+    /// its corners.
+    pub fn copy(layer: &MapLayer, a: (i32, i32), b: (i32, i32)) -> Stamp {
+        let (left, top) = (min(a.0, b.0), min(a.1, b.1));
+        let (right, bottom) = (max(a.0, b.0), max(a.1, b.1));
+        let mut tiles = Vec::new();
+        for y in top..bottom + 1 {
+            for x in left..right + 1 {
+                tiles.push(layer.tile((x, y)));
+            }
+        }
+        Stamp {
+            asset: layer.tileset().asset().into(),
+            width: (right - left + 1) as u32,
+            height: (bottom - top + 1) as u32,
+            tiles: tiles,
+        }
+    }
+
+    /// The tileset the stamp's tiles are drawn from.
+    pub fn asset(&self) -> &str {
+        &self.asset
+    }
+
+    /// The width and height of the stamp, in cells.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The tiles to paint to put the stamp's top-left corner at `origin`,
+    /// and the cell to paint each into. See-through cells are left out.
+    pub fn cells(&self, origin: (i32, i32)) -> Vec<((i32, i32), u32)> {
+        let w = self.width as usize;
+        self.tiles.iter().enumerate()
+            .filter(|&(_, &tile)| tile != EMPTY)
+            .map(|(i, &tile)| ((origin.0 + (i % w) as i32,
+                                origin.1 + (i / w) as i32), tile))
+            .collect()
+    }
+}
+
+/// Named stamps, as saved to and loaded from a library file.
+#[derive(Serialize, Deserialize)]
+struct LibraryFile {
+    version: u32,
+    stamps: BTreeMap<String, Stamp>,
+}
+
+/// The version of the library format this build writes.
+const VERSION: u32 = 1;
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Load a library of stamps, by name.
+pub fn load_library<P: AsRef<Path>>(path: P)
+        -> io::Result<BTreeMap<String, Stamp>> {
+    let mut f = try!(File::open(path));
+    let mut s = String::new();
+    try!(f.read_to_string(&mut s));
+    decode_library(&s)
+}
+
+fn decode_library(s: &str) -> io::Result<BTreeMap<String, Stamp>> {
+    let file: LibraryFile = try!(serde_json::from_str(s).map_err(invalid));
+    if file.version > VERSION {
+        return Err(invalid(format!(
+            "stamp library is in version {} of the format, but this build \
+             only understands versions up to {}", file.version, VERSION)));
+    }
+    for (name, stamp) in &file.stamps {
+        if stamp.width == 0 || stamp.height == 0 {
+            return Err(invalid(format!("stamp `{}` is empty", name)));
+        }
+        let cells = stamp.width.checked_mul(stamp.height);
+        if cells.map(|n| n as u64) != Some(stamp.tiles.len() as u64) {
+            return Err(invalid(format!(
+                "stamp `{}` has {} tiles rather than {}x{}", name,
+                stamp.tiles.len(), stamp.width, stamp.height)));
+        }
+    }
+    Ok(file.stamps)
+}
+
+/// A name for a new stamp in a library, `stamp N` for the lowest N not
+/// taken yet.
+pub fn unused_name(stamps: &BTreeMap<String, Stamp>) -> String {
+    (1..).map(|n| format!("stamp {}", n))
+        .find(|name| !stamps.contains_key(name))
+        .unwrap()
+}
+
+/// Save a library of stamps, replacing whatever was in the file.
+pub fn save_library<P: AsRef<Path>>(path: P,
+                                    stamps: &BTreeMap<String, Stamp>)
+        -> io::Result<()> {
+    let mut f = try!(File::create(path));
+    f.write_all(encode_library(stamps).as_bytes())
+}
+
+fn encode_library(stamps: &BTreeMap<String, Stamp>) -> String {
+    serde_json::to_string(&LibraryFile {
+        version: VERSION,
+        stamps: stamps.clone(),
+    }).unwrap()
+}
+
+#[test]
+fn stamps_come_from_rectangles_of_the_tileset() {
+    // 0  1  2  3
+    // 4  5  6  7
+    // 8  9 10 11
+    let tileset = Tileset::grid("grid", (16, 16), 4, 0, 0);
+    let stamp = Stamp::from_tileset(&tileset, 9, 2).unwrap();
+    assert_eq!(stamp.size(), (2, 3));
+    assert_eq!(stamp.tiles, vec![1, 2, 5, 6, 9, 10]);
+
+    let strip = Tileset::strip("strip", (16, 16));
+    let stamp = Stamp::from_tileset(&strip, 5, 3).unwrap();
+    assert_eq!(stamp.size(), (3, 1));
+    assert_eq!(stamp.tiles, vec![3, 4, 5]);
+
+    // An empty cell isn't in any tileset
+    assert_eq!(Stamp::from_tileset(&strip, EMPTY, 3), None);
+    assert_eq!(Stamp::from_tileset(&tileset, EMPTY, EMPTY), None);
+}
+
+#[test]
+fn stamps_copy_layers_and_see_through_empty_cells() {
+    let layer = MapLayer::new(Tileset::strip("foobar", (16, 16)), 3,
+                              vec![0, 1, 2,
+                                   3, EMPTY, 5]);
+    let stamp = Stamp::copy(&layer, (1, 1), (-1, 0));
+    assert_eq!(stamp.size(), (3, 2));
+    assert_eq!(stamp.tiles, vec![EMPTY, 0, 1, EMPTY, 3, EMPTY]);
+    assert_eq!(stamp.cells((10, -10)),
+               vec![((11, -10), 0), ((12, -10), 1), ((11, -9), 3)]);
+}
+
+#[test]
+fn libraries_round_trip() {
+    let mut stamps = BTreeMap::new();
+    let tileset = Tileset::grid("grid", (16, 16), 4, 0, 0);
+    stamps.insert("house".to_string(),
+                  Stamp::from_tileset(&tileset, 0, 9).unwrap());
+    stamps.insert("rock".to_string(),
+                  Stamp::from_tileset(&tileset, 3, 3).unwrap());
+    assert_eq!(decode_library(&encode_library(&stamps)).unwrap(), stamps);
+}
+
+#[test]
+fn new_stamps_get_names_not_taken() {
+    let tileset = Tileset::strip("strip", (16, 16));
+    let stamp = Stamp::from_tileset(&tileset, 0, 0).unwrap();
+    let mut stamps = BTreeMap::new();
+    assert_eq!(unused_name(&stamps), "stamp 1");
+    stamps.insert("stamp 2".to_string(), stamp.clone());
+    stamps.insert("tree".to_string(), stamp.clone());
+    assert_eq!(unused_name(&stamps), "stamp 1");
+    stamps.insert("stamp 1".to_string(), stamp);
+    assert_eq!(unused_name(&stamps), "stamp 3");
+}
+
+#[test]
+fn bad_libraries_are_errors() {
+    assert!(decode_library("{\"version\":2,\"stamps\":{}}").is_err());
+    assert!(decode_library(
+        "{\"version\":1,\"stamps\":{\"x\":{\"asset\":\"a\",\"width\":2,\
+         \"height\":2,\"tiles\":[1,2,3]}}}").is_err());
+    assert!(decode_library(
+        "{\"version\":1,\"stamps\":{\"x\":{\"asset\":\"a\",\"width\":0,\
+         \"height\":0,\"tiles\":[]}}}").is_err());
+    assert!(decode_library(
+        "{\"version\":1,\"stamps\":{\"x\":{\"asset\":\"a\",\
+         \"width\":65536,\"height\":65536,\"tiles\":[]}}}").is_err());
+}
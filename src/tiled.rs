@@ -0,0 +1,1398 @@
+//! Importing maps made with the Tiled editor, from either its JSON or its
+//! TMX (XML) format, and exporting ours back to Tiled JSON.
+//!
+//! Only the parts of Tiled's model we have an equivalent for are supported:
+//! finite orthogonal maps, tile layers that draw from a single tileset each,
+//! and object layers of rectangles and points. Anything else is an error
+//! rather than being silently dropped.
+//!
+//! Tile layers are drawn at the z-index in their `z_index` property, or -1
+//! if they don't have one. Tile properties named `solid` and `terrain`
+//! become the matching TileProperties fields; all others are kept as custom
+//! properties.
+//!
+//! Exported maps keep the z-index of each layer in the same property, so
+//! they come back unchanged when imported again. Tiled tilesets are always
+//! grids, so our strips are exported as a single row with a `strip`
+//! property, which makes them strips again on import.
+//!
+//! XXX: Tiled's finite maps start at 0,0, so a map that has grown above or
+//! left of the origin can't be exported. Tiled's infinite maps could hold
+//! it, but aren't supported yet.
+//!
+//! XXX: our own map format refers to tilesets by image only, so an imported
+//! map saved with `Map::save` needs a descriptor next to each tileset image
+//! to keep its layout.
+//!
+//! XXX: Tiled's Wang sets and tile animations aren't imported or exported
+//! yet.
+use base64;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use map::{Bounds, EMPTY, Map, MapLayer, Object, ObjectLayer, REACH};
+use png;
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, self};
+use std::path::{Component, Path, PathBuf};
+use tileset::{TileProperties, Tileset};
+use xml::reader::{EventReader, XmlEvent};
+
+// Tiled stores flips and rotations in the top bits of each tile's gid
+const FLIP_FLAGS: u32 = 0xf0000000;
+
+/// Whether a path names a TMX map, by its extension.
+pub fn is_tmx(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("tmx"))
+}
+
+/// Whether a JSON document is a map saved by Tiled, rather than one of ours.
+pub fn is_tiled_json(json: &Value) -> bool {
+    json.get("tiledversion").is_some() ||
+        json.get("type").and_then(|t| t.as_str()) == Some("map")
+}
+
+/// Import a Tiled map from a TMX or JSON file.
+pub fn import(path: &Path) -> io::Result<Map> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if is_tmx(path) {
+        import_tmx(&try!(parse_xml(try!(File::open(path)))), dir)
+    } else {
+        import_json(&try!(read_json(path)), dir)
+    }
+}
+
+fn error<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Tiled import: {}", message.into()))
+}
+
+/// The parts of a Tiled map we care about, whichever format it came from.
+struct Header {
+    width: u32,
+    height: u32,
+    tile_size: (u32, u32),
+}
+
+enum Layer {
+    Tiles {
+        name: String,
+        visible: bool,
+        properties: BTreeMap<String, String>,
+        gids: Vec<u32>,
+    },
+    Objects(ObjectLayer),
+}
+
+fn check_format(orientation: &str, infinite: bool) -> io::Result<()> {
+    if orientation != "orthogonal" {
+        return Err(error(format!(
+            "{} maps are not supported, only orthogonal ones", orientation)));
+    }
+    if infinite {
+        return Err(error("infinite maps are not supported"));
+    }
+    Ok(())
+}
+
+/// Build a map from its parts, checking that each layer fits the map's grid
+/// and sticks to one tileset. `tilesets` pairs each tileset with the first
+/// gid it covers.
+fn assemble(header: Header, mut tilesets: Vec<(u32, Tileset)>,
+            layers: Vec<Layer>) -> io::Result<Map> {
+    if header.tile_size.0 == 0 || header.tile_size.1 == 0 {
+        return Err(error("the map's tiles have no size"));
+    }
+    if !Bounds::new(0, 0, header.width, header.height).within_reach() {
+        return Err(error(format!(
+            "the map is {}x{}, but can't reach further than {} cells from \
+             the origin", header.width, header.height, REACH)));
+    }
+    tilesets.sort_by_key(|&(first_gid, _)| first_gid);
+    for &(_, ref tileset) in &tilesets {
+        if tileset.tile_size() != header.tile_size {
+            return Err(error(format!(
+                "tileset `{}` has {:?} tiles but the map grid is {:?}; \
+                 tiles must match the grid", tileset.asset(),
+                tileset.tile_size(), header.tile_size)));
+        }
+    }
+
+    let mut map = Map::new();
+    for layer in layers {
+        let (name, visible, properties, gids) = match layer {
+            Layer::Tiles { name, visible, properties, gids } =>
+                (name, visible, properties, gids),
+            Layer::Objects(objects) => {
+                map.push_objects(objects);
+                continue;
+            },
+        };
+
+        if gids.len() as u32 != header.width * header.height {
+            return Err(error(format!(
+                "layer `{}` has {} tiles but the map is {}x{}",
+                name, gids.len(), header.width, header.height)));
+        }
+
+        // Which of the tilesets this layer uses, as an index into tilesets
+        let mut used = None;
+        let mut tiles = Vec::with_capacity(gids.len());
+        for gid in gids {
+            if gid & FLIP_FLAGS != 0 {
+                return Err(error(format!(
+                    "layer `{}` has flipped or rotated tiles, which are not \
+                     supported", name)));
+            }
+            if gid == 0 {
+                tiles.push(EMPTY);
+                continue;
+            }
+            let index = match tilesets.iter().rposition(|t| t.0 <= gid) {
+                Some(index) => index,
+                None => return Err(error(format!(
+                    "layer `{}` uses tile {}, which is in no tileset",
+                    name, gid))),
+            };
+            if used.map_or(false, |u| u != index) {
+                return Err(error(format!(
+                    "layer `{}` uses tiles from more than one tileset",
+                    name)));
+            }
+            used = Some(index);
+            tiles.push(gid - tilesets[index].0);
+        }
+
+        let tileset = match tilesets.get(used.unwrap_or(0)) {
+            Some(&(_, ref tileset)) => tileset.clone(),
+            None => return Err(error("the map has no tilesets")),
+        };
+        let z_index = match properties.get("z_index") {
+            Some(z) => try!(z.parse().map_err(|_| error(format!(
+                "layer `{}` has a z_index of `{}`, which is not a number",
+                name, z)))),
+            None => -1,
+        };
+
+        map.push(&name, z_index, MapLayer::new(tileset, header.width, tiles));
+        if !visible {
+            map.hide_top();
+        }
+    }
+    Ok(map)
+}
+
+/// Build one of our tilesets from a Tiled one. The image path is relative
+/// to `dir`, the directory of the file that declared the tileset. Tiled
+/// only writes `columns` in newer versions, so older ones work it out from
+/// the image width.
+fn make_tileset(dir: &Path, image: &str, tile_size: (u32, u32),
+                columns: Option<u32>, image_width: Option<u32>,
+                spacing: u32, margin: u32, strip: bool,
+                tiles: Vec<(u32, BTreeMap<String, String>)>)
+        -> io::Result<Tileset> {
+    let image = normalize(&dir.join(image));
+    if image.extension() != Some(OsStr::new("png")) {
+        return Err(error(format!(
+            "tileset image `{}` is not a PNG", image.display())));
+    }
+    let asset = image.with_extension("");
+    let asset = asset.to_string_lossy();
+
+    let mut tileset = if strip {
+        let mut tileset = Tileset::strip(&asset, tile_size);
+        tileset.set_spacing_and_margin(spacing, margin);
+        tileset
+    } else {
+        let columns = columns.or(image_width.map(|w| {
+            (w + spacing).saturating_sub(2 * margin) / (tile_size.0 + spacing)
+        }));
+        let columns = match columns {
+            Some(c) if c > 0 => c,
+            _ => return Err(error(format!(
+                "can't tell how many columns tileset `{}` has",
+                image.display()))),
+        };
+        Tileset::grid(&asset, tile_size, columns, spacing, margin)
+    };
+    for (id, mut custom) in tiles {
+        if custom.is_empty() {
+            continue;
+        }
+        let solid = custom.remove("solid").map_or(false, |s| s == "true");
+        let terrain = custom.remove("terrain");
+        tileset.set_properties(id, TileProperties {
+            solid: solid,
+            terrain: terrain,
+            custom: custom,
+        });
+    }
+    Ok(tileset)
+}
+
+/// Whether a tileset's properties say it was exported from a strip.
+fn is_strip(properties: &BTreeMap<String, String>) -> bool {
+    properties.get("strip").map_or(false, |s| s == "true")
+}
+
+/// Load an external tileset file, either TSX or JSON.
+fn load_tileset(path: &Path) -> io::Result<Tileset> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if path.extension() == Some(OsStr::new("tsx")) {
+        tmx_tileset(&try!(parse_xml(try!(File::open(path)))), dir)
+    } else {
+        json_tileset(&try!(read_json(path)), dir)
+    }
+}
+
+/// Convert an object. `shape` names the shape of the object if it is
+/// anything more than a rectangle or a point.
+fn make_object(name: String, kind: String, (x, y, w, h): (f32, f32, f32, f32),
+               properties: BTreeMap<String, String>, shape: Option<&str>)
+        -> io::Result<Object> {
+    if let Some(shape) = shape {
+        return Err(error(format!(
+            "object `{}` is {}; only rectangle and point objects are \
+             supported", name, shape)));
+    }
+    Ok(Object {
+        name: name,
+        kind: kind,
+        x: x.round() as i32,
+        y: y.round() as i32,
+        width: w.round() as u32,
+        height: h.round() as u32,
+        properties: properties,
+    })
+}
+
+/// Decode layer data stored as text, which is CSV or base64 encoded little
+/// endian gids, optionally compressed.
+fn decode_data(text: &str, encoding: &str, compression: &str)
+        -> io::Result<Vec<u32>> {
+    match encoding {
+        "csv" => text.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().map_err(|_| error(format!(
+                "`{}` in CSV layer data is not a tile", s))))
+            .collect(),
+        "base64" => {
+            let text: String = text.chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            let bytes = try!(base64::decode(&text).map_err(|e| error(format!(
+                "bad base64 layer data: {}", e))));
+            let bytes = match compression {
+                "" => bytes,
+                "zlib" => try!(read_all(ZlibDecoder::new(&bytes[..]))),
+                "gzip" => try!(read_all(GzDecoder::new(&bytes[..]))),
+                other => return Err(error(format!(
+                    "{} compressed layer data is not supported", other))),
+            };
+            if bytes.len() % 4 != 0 {
+                return Err(error("layer data is not a whole number of tiles"));
+            }
+            Ok(bytes.chunks(4).map(|b| {
+                b[0] as u32 | (b[1] as u32) << 8 |
+                    (b[2] as u32) << 16 | (b[3] as u32) << 24
+            }).collect())
+        },
+        other => Err(error(format!(
+            "`{}` layer data encoding is not supported", other))),
+    }
+}
+
+fn read_all<R: Read>(mut source: R) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    try!(source.read_to_end(&mut bytes)
+        .map_err(|e| error(format!("bad compressed layer data: {}", e))));
+    Ok(bytes)
+}
+
+// JSON
+
+fn read_json(path: &Path) -> io::Result<Value> {
+    let mut s = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut s));
+    serde_json::from_str(&s).map_err(|e| error(format!(
+        "{} is not valid JSON: {}", path.display(), e)))
+}
+
+fn json_field<'a>(json: &'a Value, key: &str) -> io::Result<&'a Value> {
+    json.get(key).ok_or_else(|| error(format!("missing `{}`", key)))
+}
+
+fn json_u32(json: &Value, key: &str) -> io::Result<u32> {
+    try!(json_field(json, key)).as_u64().map(|n| n as u32)
+        .ok_or_else(|| error(format!("`{}` is not a whole number", key)))
+}
+
+fn json_u32_or(json: &Value, key: &str, default: u32) -> io::Result<u32> {
+    match json.get(key) {
+        Some(_) => json_u32(json, key),
+        None => Ok(default),
+    }
+}
+
+fn json_f32_or(json: &Value, key: &str, default: f32) -> io::Result<f32> {
+    match json.get(key) {
+        Some(n) => n.as_f64().map(|n| n as f32)
+            .ok_or_else(|| error(format!("`{}` is not a number", key))),
+        None => Ok(default),
+    }
+}
+
+fn json_bool_or(json: &Value, key: &str, default: bool) -> io::Result<bool> {
+    match json.get(key) {
+        Some(b) => b.as_bool()
+            .ok_or_else(|| error(format!("`{}` is not a boolean", key))),
+        None => Ok(default),
+    }
+}
+
+fn json_str_or(json: &Value, key: &str, default: &str) -> io::Result<String> {
+    match json.get(key) {
+        Some(s) => s.as_str().map(|s| s.into())
+            .ok_or_else(|| error(format!("`{}` is not a string", key))),
+        None => Ok(default.into()),
+    }
+}
+
+fn json_array<'a>(json: &'a Value, key: &str) -> io::Result<&'a [Value]> {
+    match json.get(key) {
+        Some(a) => a.as_array().map(|a| &a[..])
+            .ok_or_else(|| error(format!("`{}` is not an array", key))),
+        None => Ok(&[]),
+    }
+}
+
+fn json_value(json: &Value) -> io::Result<String> {
+    match *json {
+        Value::String(ref s) => Ok(s.clone()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Number(ref n) => Ok(n.to_string()),
+        _ => Err(error("property values must be strings, numbers or \
+                        booleans")),
+    }
+}
+
+/// Properties are a list of name/type/value objects, or in files from
+/// older versions of Tiled, a plain object.
+fn json_properties(json: &Value) -> io::Result<BTreeMap<String, String>> {
+    let mut properties = BTreeMap::new();
+    match json.get("properties") {
+        Some(&Value::Array(ref list)) => {
+            for p in list {
+                let name = try!(json_str_or(p, "name", ""));
+                let value = try!(json_value(try!(json_field(p, "value"))));
+                properties.insert(name, value);
+            }
+        },
+        Some(&Value::Object(ref object)) => {
+            for (name, value) in object {
+                properties.insert(name.clone(), try!(json_value(value)));
+            }
+        },
+        Some(_) => return Err(error("`properties` is not a list")),
+        None => {},
+    }
+    Ok(properties)
+}
+
+/// Import a map from a Tiled JSON document. Paths in it are relative to
+/// `dir`.
+pub fn import_json(json: &Value, dir: &Path) -> io::Result<Map> {
+    try!(check_format(&try!(json_str_or(json, "orientation", "orthogonal")),
+                      try!(json_bool_or(json, "infinite", false))));
+    let header = Header {
+        width: try!(json_u32(json, "width")),
+        height: try!(json_u32(json, "height")),
+        tile_size: (try!(json_u32(json, "tilewidth")),
+                    try!(json_u32(json, "tileheight"))),
+    };
+
+    let mut tilesets = Vec::new();
+    for t in try!(json_array(json, "tilesets")) {
+        let first_gid = try!(json_u32(t, "firstgid"));
+        let tileset = match t.get("source").and_then(|s| s.as_str()) {
+            Some(source) => try!(load_tileset(&dir.join(source))),
+            None => try!(json_tileset(t, dir)),
+        };
+        tilesets.push((first_gid, tileset));
+    }
+
+    let mut layers = Vec::new();
+    for l in try!(json_array(json, "layers")) {
+        layers.push(try!(json_layer(l)));
+    }
+
+    assemble(header, tilesets, layers)
+}
+
+fn json_tileset(json: &Value, dir: &Path) -> io::Result<Tileset> {
+    let image = match json.get("image").and_then(|i| i.as_str()) {
+        Some(image) => image,
+        None => return Err(error(format!(
+            "tileset `{}` has no image; image collection tilesets are not \
+             supported", try!(json_str_or(json, "name", ""))))),
+    };
+
+    let mut tiles = Vec::new();
+    for tile in try!(json_array(json, "tiles")) {
+        tiles.push((try!(json_u32(tile, "id")),
+                    try!(json_properties(tile))));
+    }
+
+    let columns = match json.get("columns") {
+        Some(_) => Some(try!(json_u32(json, "columns"))),
+        None => None,
+    };
+    let image_width = match json.get("imagewidth") {
+        Some(_) => Some(try!(json_u32(json, "imagewidth"))),
+        None => None,
+    };
+
+    make_tileset(dir, image,
+                 (try!(json_u32(json, "tilewidth")),
+                  try!(json_u32(json, "tileheight"))),
+                 columns, image_width,
+                 try!(json_u32_or(json, "spacing", 0)),
+                 try!(json_u32_or(json, "margin", 0)),
+                 is_strip(&try!(json_properties(json))),
+                 tiles)
+}
+
+fn json_layer(json: &Value) -> io::Result<Layer> {
+    let name = try!(json_str_or(json, "name", ""));
+    if try!(json_f32_or(json, "offsetx", 0.0)) != 0.0 ||
+       try!(json_f32_or(json, "offsety", 0.0)) != 0.0 {
+        return Err(error(format!(
+            "layer `{}` is offset, which is not supported", name)));
+    }
+
+    match &try!(json_str_or(json, "type", ""))[..] {
+        "tilelayer" => {
+            let data = try!(json_field(json, "data"));
+            let gids = match *data {
+                Value::Array(ref list) => try!(list.iter()
+                    .map(|g| g.as_u64().map(|g| g as u32).ok_or_else(|| {
+                        error(format!("layer `{}` has a bad tile", name))
+                    }))
+                    .collect()),
+                Value::String(ref text) => try!(decode_data(text,
+                    &try!(json_str_or(json, "encoding", "csv")),
+                    &try!(json_str_or(json, "compression", "")))),
+                _ => return Err(error(format!(
+                    "layer `{}` has bad data", name))),
+            };
+            Ok(Layer::Tiles {
+                visible: try!(json_bool_or(json, "visible", true)),
+                properties: try!(json_properties(json)),
+                gids: gids,
+                name: name,
+            })
+        },
+        "objectgroup" => {
+            let mut objects = Vec::new();
+            for o in try!(json_array(json, "objects")) {
+                objects.push(try!(json_object(o)));
+            }
+            Ok(Layer::Objects(ObjectLayer {
+                name: name,
+                objects: objects,
+            }))
+        },
+        other => Err(error(format!(
+            "layer `{}` has type `{}`, which is not supported", name, other))),
+    }
+}
+
+fn json_object(json: &Value) -> io::Result<Object> {
+    let shape = if try!(json_bool_or(json, "ellipse", false)) {
+        Some("an ellipse")
+    } else if json.get("polygon").is_some() {
+        Some("a polygon")
+    } else if json.get("polyline").is_some() {
+        Some("a polyline")
+    } else if json.get("text").is_some() {
+        Some("a text object")
+    } else if json.get("gid").is_some() {
+        Some("a tile object")
+    } else {
+        None
+    };
+
+    // Tiled 1.9 renamed an object's type to its class
+    let kind = match json.get("class") {
+        Some(_) => try!(json_str_or(json, "class", "")),
+        None => try!(json_str_or(json, "type", "")),
+    };
+
+    make_object(try!(json_str_or(json, "name", "")), kind,
+                (try!(json_f32_or(json, "x", 0.0)),
+                 try!(json_f32_or(json, "y", 0.0)),
+                 try!(json_f32_or(json, "width", 0.0)),
+                 try!(json_f32_or(json, "height", 0.0))),
+                try!(json_properties(json)), shape)
+}
+
+// TMX
+
+/// Just enough of an XML document model to read TMX files with.
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn attr_str_or(&self, name: &str, default: &str) -> String {
+        self.attr(name).unwrap_or(default).into()
+    }
+
+    fn attr_u32(&self, name: &str) -> io::Result<u32> {
+        match self.attr(name) {
+            Some(v) => v.parse().map_err(|_| error(format!(
+                "`{}` of <{}> is not a whole number", name, self.name))),
+            None => Err(error(format!(
+                "<{}> has no `{}`", self.name, name))),
+        }
+    }
+
+    fn attr_u32_opt(&self, name: &str) -> io::Result<Option<u32>> {
+        match self.attr(name) {
+            Some(_) => self.attr_u32(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn attr_f32_or(&self, name: &str, default: f32) -> io::Result<f32> {
+        match self.attr(name) {
+            Some(v) => v.parse().map_err(|_| error(format!(
+                "`{}` of <{}> is not a number", name, self.name))),
+            None => Ok(default),
+        }
+    }
+
+    /// TMX booleans are written as 0 or 1.
+    fn attr_bool_or(&self, name: &str, default: bool) -> bool {
+        self.attr(name).map_or(default, |v| v != "0" && v != "false")
+    }
+}
+
+fn parse_xml<R: Read>(source: R) -> io::Result<Element> {
+    let mut stack: Vec<Element> = Vec::new();
+    for event in EventReader::new(source) {
+        match try!(event.map_err(|e| error(format!("bad XML: {}", e)))) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes.into_iter()
+                        .map(|a| (a.name.local_name, a.value))
+                        .collect(),
+                    children: Vec::new(),
+                    text: String::new(),
+                });
+            },
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            },
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text);
+                }
+            },
+            _ => {},
+        }
+    }
+    Err(error("empty XML document"))
+}
+
+fn tmx_properties(element: &Element) -> BTreeMap<String, String> {
+    let mut properties = BTreeMap::new();
+    if let Some(list) = element.child("properties") {
+        for p in list.children.iter().filter(|p| p.name == "property") {
+            // Multi-line string values are written as the element's text
+            let value = match p.attr("value") {
+                Some(value) => value.into(),
+                None => p.text.clone(),
+            };
+            properties.insert(p.attr_str_or("name", ""), value);
+        }
+    }
+    properties
+}
+
+fn import_tmx(root: &Element, dir: &Path) -> io::Result<Map> {
+    if root.name != "map" {
+        return Err(error(format!("expected <map>, found <{}>", root.name)));
+    }
+    try!(check_format(&root.attr_str_or("orientation", "orthogonal"),
+                      root.attr_bool_or("infinite", false)));
+    let header = Header {
+        width: try!(root.attr_u32("width")),
+        height: try!(root.attr_u32("height")),
+        tile_size: (try!(root.attr_u32("tilewidth")),
+                    try!(root.attr_u32("tileheight"))),
+    };
+
+    let mut tilesets = Vec::new();
+    let mut layers = Vec::new();
+    for child in &root.children {
+        match &child.name[..] {
+            "tileset" => {
+                let first_gid = try!(child.attr_u32("firstgid"));
+                let tileset = match child.attr("source") {
+                    Some(source) => try!(load_tileset(&dir.join(source))),
+                    None => try!(tmx_tileset(child, dir)),
+                };
+                tilesets.push((first_gid, tileset));
+            },
+            "layer" => layers.push(try!(tmx_layer(child))),
+            "objectgroup" => layers.push(try!(tmx_objects(child))),
+            "imagelayer" | "group" => return Err(error(format!(
+                "layer `{}` has type `{}`, which is not supported",
+                child.attr_str_or("name", ""), child.name))),
+            _ => {},
+        }
+    }
+
+    assemble(header, tilesets, layers)
+}
+
+fn tmx_tileset(element: &Element, dir: &Path) -> io::Result<Tileset> {
+    let image = match element.child("image") {
+        Some(image) => image,
+        None => return Err(error(format!(
+            "tileset `{}` has no image; image collection tilesets are not \
+             supported", element.attr_str_or("name", "")))),
+    };
+    let source = match image.attr("source") {
+        Some(source) => source,
+        None => return Err(error("tileset <image> has no source")),
+    };
+
+    let mut tiles = Vec::new();
+    for tile in element.children.iter().filter(|c| c.name == "tile") {
+        tiles.push((try!(tile.attr_u32("id")), tmx_properties(tile)));
+    }
+
+    make_tileset(dir, source,
+                 (try!(element.attr_u32("tilewidth")),
+                  try!(element.attr_u32("tileheight"))),
+                 try!(element.attr_u32_opt("columns")),
+                 try!(image.attr_u32_opt("width")),
+                 try!(element.attr_u32_opt("spacing")).unwrap_or(0),
+                 try!(element.attr_u32_opt("margin")).unwrap_or(0),
+                 is_strip(&tmx_properties(element)),
+                 tiles)
+}
+
+fn tmx_layer(element: &Element) -> io::Result<Layer> {
+    let name = element.attr_str_or("name", "");
+    if try!(element.attr_f32_or("offsetx", 0.0)) != 0.0 ||
+       try!(element.attr_f32_or("offsety", 0.0)) != 0.0 {
+        return Err(error(format!(
+            "layer `{}` is offset, which is not supported", name)));
+    }
+
+    let data = match element.child("data") {
+        Some(data) => data,
+        None => return Err(error(format!("layer `{}` has no data", name))),
+    };
+    let gids = match data.attr("encoding") {
+        Some(encoding) => try!(decode_data(&data.text, encoding,
+            data.attr("compression").unwrap_or(""))),
+        // The oldest format: one <tile gid=".."/> element per cell
+        None => try!(data.children.iter()
+            .filter(|t| t.name == "tile")
+            .map(|t| t.attr_u32_opt("gid").map(|g| g.unwrap_or(0)))
+            .collect()),
+    };
+
+    Ok(Layer::Tiles {
+        visible: element.attr_bool_or("visible", true),
+        properties: tmx_properties(element),
+        gids: gids,
+        name: name,
+    })
+}
+
+fn tmx_objects(element: &Element) -> io::Result<Layer> {
+    let mut objects = Vec::new();
+    for o in element.children.iter().filter(|c| c.name == "object") {
+        let shape = if o.attr("gid").is_some() {
+            Some("a tile object")
+        } else {
+            [("ellipse", "an ellipse"), ("polygon", "a polygon"),
+             ("polyline", "a polyline"), ("text", "a text object")].iter()
+                .find(|&&(child, _)| o.child(child).is_some())
+                .map(|&(_, shape)| shape)
+        };
+        let kind = match o.attr("class") {
+            Some(class) => class.into(),
+            None => o.attr_str_or("type", ""),
+        };
+        objects.push(try!(make_object(
+            o.attr_str_or("name", ""), kind,
+            (try!(o.attr_f32_or("x", 0.0)), try!(o.attr_f32_or("y", 0.0)),
+             try!(o.attr_f32_or("width", 0.0)),
+             try!(o.attr_f32_or("height", 0.0))),
+            tmx_properties(o), shape)));
+    }
+    Ok(Layer::Objects(ObjectLayer {
+        name: element.attr_str_or("name", ""),
+        objects: objects,
+    }))
+}
+
+// Export
+
+/// Export a map as a Tiled JSON map at `path`. Each of its tilesets is
+/// written next to the map as an external Tiled tileset, named after the
+/// tileset's image, which the map refers to.
+pub fn export(map: &Map, path: &Path) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    // Every distinct tileset, with its file name and its size in tiles
+    let mut tilesets: Vec<(&Tileset, String, u32)> = Vec::new();
+    for l in map.layers() {
+        let tileset = l.layer().tileset();
+        if tilesets.iter().any(|t| t.0 == tileset) {
+            continue;
+        }
+        let stem = Path::new(tileset.asset()).file_name()
+            .map_or("tileset".into(), |s| s.to_string_lossy().into_owned());
+        let mut name = format!("{}.tsj", stem);
+        let mut n = 1;
+        while tilesets.iter().any(|t| t.1 == name) {
+            n += 1;
+            name = format!("{}-{}.tsj", stem, n);
+        }
+        let json = try!(export_tileset(tileset, dir));
+        let count = json.get("tilecount").and_then(|c| c.as_u64()).unwrap();
+        try!(write_json(&dir.join(&name), &json));
+        tilesets.push((tileset, name, count as u32));
+    }
+
+    // Each tileset's first gid follows on from the tiles before it
+    let mut first_gids = Vec::new();
+    let mut next_gid = 1;
+    for &(_, _, count) in &tilesets {
+        first_gids.push(next_gid);
+        next_gid += count;
+    }
+
+    // Every layer covers the whole map, so they share one grid, which
+    // starts at the origin
+    let bounds = map.bounds();
+    let tile_size = match map.layers().first() {
+        Some(l) => l.layer().tileset().tile_size(),
+        None => return Err(error("can't export a map with no tile layers")),
+    };
+    if !bounds.is_empty() && (bounds.x < 0 || bounds.y < 0) {
+        return Err(error(format!(
+            "the map reaches left of or above the origin, to cell {},{}, \
+             which Tiled's finite maps can't hold", bounds.x, bounds.y)));
+    }
+    let (width, height) = if bounds.is_empty() {
+        (0, 0)
+    } else {
+        (bounds.x as u32 + bounds.width, bounds.y as u32 + bounds.height)
+    };
+
+    let mut layers = Vec::new();
+    let mut next_id = 1;
+    for l in map.layers() {
+        let index = tilesets.iter()
+            .position(|t| t.0 == l.layer().tileset())
+            .unwrap();
+        let (first_gid, count) = (first_gids[index], tilesets[index].2);
+        let mut data = vec![0; width as usize * height as usize];
+        for ((x, y), tile) in l.layer().painted() {
+            if !bounds.contains((x, y)) {
+                continue;
+            }
+            if tile >= count {
+                return Err(error(format!(
+                    "layer `{}` uses tile {}, but its tileset only has {}",
+                    l.name(), tile, count)));
+            }
+            data[y as usize * width as usize + x as usize] = tile + first_gid;
+        }
+        layers.push(object(vec![
+            ("type", Value::String("tilelayer".into())),
+            ("id", Value::from(next_id)),
+            ("name", Value::String(l.name().into())),
+            ("x", Value::from(0)),
+            ("y", Value::from(0)),
+            ("width", Value::from(width as u64)),
+            ("height", Value::from(height as u64)),
+            ("opacity", Value::from(1)),
+            ("visible", Value::Bool(l.visible())),
+            ("properties", Value::Array(vec![property(
+                "z_index", "int", Value::from(l.z_index() as i64))])),
+            ("data", Value::Array(data.into_iter()
+                .map(|gid| Value::from(gid as u64)).collect())),
+        ]));
+        next_id += 1;
+    }
+
+    let mut next_object_id = 1;
+    for objects in map.object_layers() {
+        let mut list = Vec::new();
+        for o in &objects.objects {
+            let mut fields = vec![
+                ("id", Value::from(next_object_id)),
+                ("name", Value::String(o.name.clone())),
+                ("type", Value::String(o.kind.clone())),
+                ("x", Value::from(o.x as i64)),
+                ("y", Value::from(o.y as i64)),
+                ("width", Value::from(o.width as u64)),
+                ("height", Value::from(o.height as u64)),
+                ("rotation", Value::from(0)),
+                ("visible", Value::Bool(true)),
+                ("properties", Value::Array(o.properties.iter()
+                    .map(|(k, v)| property(k, "string",
+                                           Value::String(v.clone())))
+                    .collect())),
+            ];
+            if o.width == 0 && o.height == 0 {
+                fields.push(("point", Value::Bool(true)));
+            }
+            list.push(object(fields));
+            next_object_id += 1;
+        }
+        layers.push(object(vec![
+            ("type", Value::String("objectgroup".into())),
+            ("id", Value::from(next_id)),
+            ("name", Value::String(objects.name.clone())),
+            ("x", Value::from(0)),
+            ("y", Value::from(0)),
+            ("opacity", Value::from(1)),
+            ("visible", Value::Bool(true)),
+            ("draworder", Value::String("topdown".into())),
+            ("objects", Value::Array(list)),
+        ]));
+        next_id += 1;
+    }
+
+    write_json(path, &object(vec![
+        ("type", Value::String("map".into())),
+        ("version", Value::String("1.2".into())),
+        ("tiledversion", Value::String("1.2.3".into())),
+        ("orientation", Value::String("orthogonal".into())),
+        ("renderorder", Value::String("right-down".into())),
+        ("infinite", Value::Bool(false)),
+        ("width", Value::from(width as u64)),
+        ("height", Value::from(height as u64)),
+        ("tilewidth", Value::from(tile_size.0 as u64)),
+        ("tileheight", Value::from(tile_size.1 as u64)),
+        ("nextlayerid", Value::from(next_id)),
+        ("nextobjectid", Value::from(next_object_id)),
+        ("tilesets", Value::Array(tilesets.iter().zip(first_gids.iter())
+            .map(|(t, &first_gid)| object(vec![
+                ("firstgid", Value::from(first_gid as u64)),
+                ("source", Value::String(t.1.clone())),
+            ]))
+            .collect())),
+        ("layers", Value::Array(layers)),
+    ]))
+}
+
+/// Describe a tileset as a Tiled tileset file to be saved in `dir`. Tiled
+/// wants to know the size of the image, so it has to exist.
+fn export_tileset(tileset: &Tileset, dir: &Path) -> io::Result<Value> {
+    let image = PathBuf::from(format!("{}.png", tileset.asset()));
+    let (image_w, image_h) = match File::open(&image) {
+        Ok(f) => try!(png::Decoder::new(f).read_info()
+            .map(|(info, _)| (info.width, info.height))
+            .map_err(|e| error(format!(
+                "can't read tileset image `{}`: {}", image.display(), e)))),
+        Err(e) => return Err(error(format!(
+            "can't read tileset image `{}`: {}", image.display(), e))),
+    };
+
+    let (tile_w, tile_h) = tileset.tile_size();
+    let (spacing, margin) = tileset.spacing_and_margin();
+    let fit = |image: u32, tile: u32| {
+        (image + spacing).saturating_sub(2 * margin) / (tile + spacing)
+    };
+    let columns = tileset.columns().unwrap_or(fit(image_w, tile_w));
+    let rows = match tileset.columns() {
+        Some(_) => fit(image_h, tile_h),
+        None => 1,
+    };
+
+    let tiles = tileset.all_properties().into_iter().map(|(id, p)| {
+        let mut properties = vec![
+            property("solid", "bool", Value::Bool(p.solid))];
+        if let Some(ref terrain) = p.terrain {
+            properties.push(property("terrain", "string",
+                                     Value::String(terrain.clone())));
+        }
+        for (k, v) in &p.custom {
+            properties.push(property(k, "string", Value::String(v.clone())));
+        }
+        object(vec![
+            ("id", Value::from(id as u64)),
+            ("properties", Value::Array(properties)),
+        ])
+    }).collect();
+
+    let source = relative(&image, dir).to_string_lossy().replace('\\', "/");
+    let name = Path::new(tileset.asset()).file_name()
+        .map_or("".into(), |s| s.to_string_lossy().into_owned());
+    let properties = match tileset.columns() {
+        Some(_) => vec![],
+        None => vec![property("strip", "bool", Value::Bool(true))],
+    };
+    Ok(object(vec![
+        ("type", Value::String("tileset".into())),
+        ("version", Value::String("1.2".into())),
+        ("tiledversion", Value::String("1.2.3".into())),
+        ("name", Value::String(name)),
+        ("image", Value::String(source)),
+        ("imagewidth", Value::from(image_w as u64)),
+        ("imageheight", Value::from(image_h as u64)),
+        ("tilewidth", Value::from(tile_w as u64)),
+        ("tileheight", Value::from(tile_h as u64)),
+        ("columns", Value::from(columns as u64)),
+        ("tilecount", Value::from((columns * rows) as u64)),
+        ("spacing", Value::from(spacing as u64)),
+        ("margin", Value::from(margin as u64)),
+        ("properties", Value::Array(properties)),
+        ("tiles", Value::Array(tiles)),
+    ]))
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+}
+
+fn property(name: &str, kind: &str, value: Value) -> Value {
+    object(vec![
+        ("name", Value::String(name.into())),
+        ("type", Value::String(kind.into())),
+        ("value", value),
+    ])
+}
+
+fn write_json(path: &Path, json: &Value) -> io::Result<()> {
+    let mut f = try!(File::create(path));
+    try!(serde_json::to_writer_pretty(&mut f, json)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    Ok(())
+}
+
+/// Tidy away `.` and `..` in a path, without looking at the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => match result.components().last() {
+                Some(Component::Normal(_)) => { result.pop(); },
+                _ => result.push(".."),
+            },
+            c => result.push(c.as_os_str()),
+        }
+    }
+    result
+}
+
+/// The path that leads from `dir` to `path`.
+fn relative(path: &Path, dir: &Path) -> PathBuf {
+    let absolute = |p: &Path| match env::current_dir() {
+        Ok(cwd) => normalize(&cwd.join(p)),
+        Err(_) => normalize(p),
+    };
+    let (path, dir) = (absolute(path), absolute(dir));
+
+    let common = path.components().zip(dir.components())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let mut result = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        result.push("..");
+    }
+    for c in path.components().skip(common) {
+        result.push(c.as_os_str());
+    }
+    result
+}
+
+#[cfg(test)]
+const TEST_JSON: &'static str = r#"{
+    "type": "map", "tiledversion": "1.2.3", "orientation": "orthogonal",
+    "infinite": false, "width": 3, "height": 2,
+    "tilewidth": 16, "tileheight": 16,
+    "tilesets": [{
+        "firstgid": 1, "name": "cotp", "image": "cotp.png",
+        "imagewidth": 1152, "imageheight": 16,
+        "tilewidth": 16, "tileheight": 16, "columns": 72,
+        "spacing": 0, "margin": 0,
+        "tiles": [
+            {"id": 4, "properties": [
+                {"name": "solid", "type": "bool", "value": true}]},
+            {"id": 5, "properties": [
+                {"name": "terrain", "type": "string", "value": "water"},
+                {"name": "depth", "type": "int", "value": 3}]}
+        ]
+    }],
+    "layers": [
+        {"type": "tilelayer", "name": "ground", "visible": true,
+         "width": 3, "height": 2, "data": [1, 2, 3, 0, 5, 6]},
+        {"type": "tilelayer", "name": "decoration", "visible": true,
+         "width": 3, "height": 2, "encoding": "base64",
+         "data": "AQAAAAIAAAADAAAAAAAAAAUAAAAGAAAA"},
+        {"type": "tilelayer", "name": "overhead", "visible": false,
+         "width": 3, "height": 2, "encoding": "base64",
+         "compression": "zlib", "data": "eJxjZGBgYAJiZgYIYAViNiAGAADIABI=",
+         "properties": [{"name": "z_index", "type": "int", "value": 1}]},
+        {"type": "objectgroup", "name": "things", "objects": [
+            {"id": 1, "name": "starman", "type": "npc",
+             "x": 20.4, "y": 8, "width": 16, "height": 24,
+             "properties": [{"name": "says", "type": "string",
+                             "value": "We are Star Men."}]},
+            {"id": 2, "name": "spawn", "type": "", "point": true,
+             "x": 4, "y": 4, "width": 0, "height": 0}
+        ]}
+    ]
+}"#;
+
+#[cfg(test)]
+const TEST_TMX: &'static str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.2" tiledversion="1.2.3" orientation="orthogonal"
+     renderorder="right-down" width="3" height="2"
+     tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" name="cotp" tilewidth="16" tileheight="16"
+          tilecount="72">
+  <image source="cotp.png" width="1152" height="16"/>
+  <tile id="4">
+   <properties><property name="solid" type="bool" value="true"/></properties>
+  </tile>
+  <tile id="5">
+   <properties>
+    <property name="terrain" value="water"/>
+    <property name="depth" type="int" value="3"/>
+   </properties>
+  </tile>
+ </tileset>
+ <layer name="ground" width="3" height="2">
+  <data encoding="csv">
+1,2,3,
+0,5,6
+</data>
+ </layer>
+ <layer name="decoration" width="3" height="2">
+  <data>
+   <tile gid="1"/><tile gid="2"/><tile gid="3"/>
+   <tile/><tile gid="5"/><tile gid="6"/>
+  </data>
+ </layer>
+ <layer name="overhead" width="3" height="2" visible="0">
+  <properties><property name="z_index" type="int" value="1"/></properties>
+  <data encoding="base64" compression="gzip">
+   H4sIAAAAAAACA2NkYGBgAmJmBghgBWI2IAYAwTxprBgAAAA=
+  </data>
+ </layer>
+ <objectgroup name="things">
+  <object id="1" name="starman" type="npc" x="20.4" y="8" width="16"
+          height="24">
+   <properties><property name="says" value="We are Star Men."/></properties>
+  </object>
+  <object id="2" name="spawn" x="4" y="4"><point/></object>
+ </objectgroup>
+</map>"#;
+
+#[cfg(test)]
+fn check_test_map(map: &Map) {
+    let expected = MapLayer::new(Tileset::strip("assets/cotp", (16, 16)), 3,
+                                 vec![0, 1, 2, EMPTY, 4, 5]);
+    for name in &["ground", "decoration", "overhead"] {
+        let layer = map.layer(name).unwrap();
+        assert_eq!(layer.serialize(), expected.serialize());
+    }
+
+    let tileset = map.layer("ground").unwrap().tileset();
+    assert_eq!(tileset.src(5), Tileset::grid("assets/cotp", (16, 16), 72, 0, 0)
+                               .src(5));
+    assert!(tileset.properties(4).unwrap().solid);
+    let water = tileset.properties(5).unwrap();
+    assert_eq!(water.terrain, Some("water".into()));
+    assert_eq!(water.custom.get("depth").map(|d| &d[..]), Some("3"));
+
+    // The overhead layer is hidden, so only the other two get drawn
+    let depths: Vec<_> = map.render(::map::everywhere(), 0.0).iter()
+        .map(|&(z, _)| z).collect();
+    assert_eq!(depths, vec![-1, -1]);
+
+    let things = &map.object_layers()[0];
+    assert_eq!(things.name, "things");
+    let starman = &things.objects[0];
+    assert_eq!((&starman.name[..], &starman.kind[..]), ("starman", "npc"));
+    assert_eq!((starman.x, starman.y, starman.width, starman.height),
+               (20, 8, 16, 24));
+    assert_eq!(starman.properties.get("says").map(|s| &s[..]),
+               Some("We are Star Men."));
+    let spawn = &things.objects[1];
+    assert_eq!((spawn.x, spawn.y, spawn.width, spawn.height), (4, 4, 0, 0));
+}
+
+#[test]
+fn imports_json() {
+    let json: Value = serde_json::from_str(TEST_JSON).unwrap();
+    assert!(is_tiled_json(&json));
+    check_test_map(&import_json(&json, Path::new("assets")).unwrap());
+}
+
+#[test]
+fn imports_tmx() {
+    let xml = parse_xml(TEST_TMX.as_bytes()).unwrap();
+    let map = import_tmx(&xml, Path::new("assets")).unwrap();
+    check_test_map(&map);
+
+    // Both formats describe the same map
+    let json: Value = serde_json::from_str(TEST_JSON).unwrap();
+    let from_json = import_json(&json, Path::new("assets")).unwrap();
+    assert_eq!(map.serialize(), from_json.serialize());
+}
+
+#[test]
+fn imports_external_tilesets() {
+    let dir = ::std::env::temp_dir().join("tarnished-tiled-test");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let mut tsx = File::create(dir.join("cotp.tsx")).unwrap();
+    io::Write::write_all(&mut tsx, br#"<?xml version="1.0"?>
+        <tileset name="cotp" tilewidth="16" tileheight="16" spacing="1"
+                 margin="2">
+         <image source="cotp.png" width="180" height="36"/>
+        </tileset>"#).unwrap();
+
+    let json: Value = serde_json::from_str(&TEST_JSON.replace(
+        r#""name": "cotp", "image": "cotp.png""#,
+        r#""source": "cotp.tsx""#)).unwrap();
+    let map = import_json(&json, &dir).unwrap();
+    let tileset = map.layer("ground").unwrap().tileset();
+
+    // Ten 16px tiles with 1px between them fit in 180px with 2px margins
+    let asset = dir.join("cotp").to_string_lossy().into_owned();
+    assert_eq!(tileset, &Tileset::grid(&asset, (16, 16), 10, 1, 2));
+}
+
+#[test]
+fn hidden_layers_stay_hidden_whatever_their_name() {
+    let json = TEST_JSON.replace(r#""name": "overhead""#,
+                                 r#""name": "ground""#);
+    let json: Value = serde_json::from_str(&json).unwrap();
+    let map = import_json(&json, Path::new("assets")).unwrap();
+    let visible: Vec<_> = map.layers().iter()
+        .map(|l| (l.name(), l.visible())).collect();
+    assert_eq!(visible, vec![("ground", true), ("decoration", true),
+                             ("ground", false)]);
+}
+
+#[test]
+fn unsupported_features_are_errors() {
+    fn import_error(from: &str, to: &str) -> String {
+        assert!(TEST_JSON.contains(from), "{}", from);
+        let json = TEST_JSON.replace(from, to);
+        let json: Value = serde_json::from_str(&json).unwrap();
+        import_json(&json, Path::new("assets")).err().unwrap().to_string()
+    }
+
+    let e = import_error(r#""orientation": "orthogonal""#,
+                         r#""orientation": "isometric""#);
+    assert!(e.contains("isometric maps are not supported"), "{}", e);
+
+    let e = import_error(r#""infinite": false"#, r#""infinite": true"#);
+    assert!(e.contains("infinite maps are not supported"), "{}", e);
+
+    let e = import_error("[1, 2, 3, 0, 5, 6]", "[1, 2, 3, 0, 5, 2147483654]");
+    assert!(e.contains("flipped or rotated"), "{}", e);
+
+    let e = import_error("[1, 2, 3, 0, 5, 6]", "[1, 2, 3]");
+    assert!(e.contains("has 3 tiles but the map is 3x2"), "{}", e);
+
+    let e = import_error(r#""compression": "zlib""#,
+                         r#""compression": "zstd""#);
+    assert!(e.contains("zstd compressed layer data is not supported"),
+            "{}", e);
+
+    let e = import_error(r#""point": true"#, r#""ellipse": true"#);
+    assert!(e.contains("`spawn` is an ellipse"), "{}", e);
+
+    let e = import_error(r#""type": "objectgroup""#,
+                         r#""type": "imagelayer""#);
+    assert!(e.contains("`things` has type `imagelayer`"), "{}", e);
+
+    let e = import_error("\"tileheight\": 16,\n    \"tilesets\"",
+                         "\"tileheight\": 0,\n    \"tilesets\"");
+    assert!(e.contains("the map's tiles have no size"), "{}", e);
+
+    let e = import_error(r#""width": 3, "height": 2,"#,
+                         r#""width": 3, "height": 4096,"#);
+    assert!(e.contains("can't reach further than 2048 cells"), "{}", e);
+
+    let e = import_error(r#""tilewidth": 16, "tileheight": 16, "columns""#,
+                         r#""tilewidth": 8, "tileheight": 8, "columns""#);
+    assert!(e.contains("tiles must match the grid"), "{}", e);
+
+    let e = import_error("    }],\n    \"layers\"", r#"    }, {
+        "firstgid": 5, "name": "more", "image": "more.png",
+        "tilewidth": 16, "tileheight": 16, "columns": 4}],
+    "layers""#);
+    assert!(e.contains("`ground` uses tiles from more than one tileset"),
+            "{}", e);
+}
+
+#[test]
+fn exported_maps_import_unchanged() {
+    let mut tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let mut custom = BTreeMap::new();
+    custom.insert("sound".into(), "splash".into());
+    tileset.set_properties(3, TileProperties {
+        solid: false,
+        terrain: Some("water".into()),
+        custom: custom,
+    });
+    tileset.set_properties(9, TileProperties {
+        solid: true,
+        ..TileProperties::default()
+    });
+
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset.clone(), 3,
+                                         vec![0, 3, 9, 71, 2, 2]));
+    map.push("overhead", 2, MapLayer::new(tileset.clone(), 3,
+                                          vec![EMPTY, 5, EMPTY, 1, 1, 1]));
+    map.set_visible("overhead", false).unwrap();
+    let mut properties = BTreeMap::new();
+    properties.insert("says".into(), "We are Star Men.".into());
+    map.push_objects(ObjectLayer {
+        name: "things".into(),
+        objects: vec![
+            Object {
+                name: "starman".into(),
+                kind: "npc".into(),
+                x: 20,
+                y: -8,
+                width: 16,
+                height: 24,
+                properties: properties,
+            },
+            Object {
+                name: "spawn".into(),
+                kind: "".into(),
+                x: 4,
+                y: 4,
+                width: 0,
+                height: 0,
+                properties: BTreeMap::new(),
+            },
+        ],
+    });
+
+    let dir = Path::new("target").join("tiled-export");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("level.json");
+    export(&map, &path).unwrap();
+
+    let imported = Map::from_file(&path).unwrap();
+    assert_eq!(imported.serialize(), map.serialize());
+    for l in imported.layers() {
+        assert_eq!(l.layer().tileset(), &tileset);
+    }
+}
+
+#[test]
+fn strips_stay_strips() {
+    let tileset = Tileset::strip("assets/cotp", (16, 16));
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset.clone(), 2, vec![0, 71]));
+
+    let dir = Path::new("target").join("tiled-export");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("strip.json");
+    export(&map, &path).unwrap();
+
+    let imported = Map::from_file(&path).unwrap();
+    assert_eq!(imported.layer("ground").unwrap().tileset(), &tileset);
+}
+
+#[test]
+fn maps_are_exported_from_the_origin() {
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset, 2, vec![0, 1, 2, 3]));
+    map.layer_mut("ground").unwrap().set_px((40, 20), 7);
+    map.push_objects(ObjectLayer {
+        name: "things".into(),
+        objects: vec![Object {
+            name: "spawn".into(),
+            kind: "".into(),
+            x: -4,
+            y: 4,
+            width: 0,
+            height: 0,
+            properties: BTreeMap::new(),
+        }],
+    });
+
+    let dir = Path::new("target").join("tiled-export");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("grown.json");
+    export(&map, &path).unwrap();
+
+    let imported = Map::from_file(&path).unwrap();
+    assert_eq!(imported.serialize(), map.serialize());
+    let spawn = &imported.object_layers()[0].objects[0];
+    assert_eq!((spawn.x, spawn.y), (-4, 4));
+
+    // Tiles left of or above the origin don't fit
+    map.layer_mut("ground").unwrap().set_px((-1, 0), 7);
+    let e = export(&map, &path).err().unwrap().to_string();
+    assert!(e.contains("left of or above the origin"), "{}", e);
+}
+
+#[test]
+fn export_checks_tiles_fit_their_tileset() {
+    let tileset = Tileset::load_or_strip("assets/cotp", (16, 16)).unwrap();
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset, 2, vec![0, 72]));
+
+    let dir = Path::new("target").join("tiled-export");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let e = export(&map, &dir.join("bad.json")).err().unwrap().to_string();
+    assert!(e.contains("uses tile 72, but its tileset only has 72"), "{}", e);
+}
+
+#[test]
+fn relative_paths() {
+    assert_eq!(relative(Path::new("assets/cotp.png"),
+                        Path::new("target/tiled-export")),
+               PathBuf::from("../../assets/cotp.png"));
+    assert_eq!(relative(Path::new("assets/cotp.png"), Path::new("assets")),
+               PathBuf::from("cotp.png"));
+    assert_eq!(normalize(Path::new("a/./b/../../../c")),
+               PathBuf::from("../c"));
+}
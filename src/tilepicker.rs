@@ -1,30 +1,32 @@
 use scene::{Rectangle, Tex};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
+use stamp::Stamp;
+use tileset::Tileset;
 
 pub struct TilePicker {
-    tileset: String,
-    tile_width: u32,
-    tile_height: u32,
+    tileset: Tileset,
     // XXX: replace with Ratio
     scale: f32,
     // widget has total ownership of its position for now
     rect: Rect,
     offset: u32,
     selected: u32,
+    // The opposite corner of the selection, in the tileset's own layout,
+    // from `selected`. The same tile when just one is selected.
+    selected_to: u32,
 }
 
 impl TilePicker {
-    pub fn new(tileset: &str, tile_width: u32, tile_height: u32,
+    pub fn new(tileset: Tileset,
                x: i32, y: i32, width: u32, height: u32) -> TilePicker {
         TilePicker {
-            tileset: tileset.into(),
-            tile_width: tile_width,
-            tile_height: tile_height,
+            tileset: tileset,
             scale: 4.0,
             rect: Rect::new(x, y, width, height),
             offset: 0,
             selected: 0,
+            selected_to: 0,
         }
     }
 
@@ -32,27 +34,56 @@ impl TilePicker {
         return self.selected;
     }
 
+    pub fn select(&mut self, tile: u32) {
+        self.selected = tile;
+        self.selected_to = tile;
+    }
+
+    /// The selected tiles, as they're laid out in the tileset. None when
+    /// the selection is EMPTY, e.g. after picking up an empty cell.
+    pub fn stamp(&self) -> Option<Stamp> {
+        Stamp::from_tileset(&self.tileset, self.selected, self.selected_to)
+    }
+
     pub fn scroll(&mut self, delta: i32) {
         if delta > 0 || self.offset >= delta.abs() as u32 {
             self.offset = (self.offset as i32 + delta) as u32;
         }
     }
 
-    pub fn click(&mut self, abs_pos: (i32, i32)) -> bool {
+    /// The tile shown at a position on screen, if it's in the picker.
+    fn tile_at(&self, abs_pos: (i32, i32)) -> Option<u32> {
         let (x, y) = abs_pos;
 
         // XXX: our version of sdl2 doesn't have Rect::contains??
         if x < self.rect.x() || x > self.rect.x() + self.rect.width() as i32 ||
             y < self.rect.y() || y > self.rect.y() + self.rect.height() as i32 {
-            return false;
+            return None;
         }
 
-        // Select a new tile
         let x = x - self.rect.x();
         // XXX: there has GOT to be a way to avoid these obnoxious casts
-        let dx = (self.tile_width as f32 * self.scale) as i32 + 1;
-        self.selected = (x / dx) as u32 + self.offset;
-        return true;
+        let (tile_width, _) = self.tileset.tile_size();
+        let dx = (tile_width as f32 * self.scale) as i32 + 1;
+        Some((x / dx) as u32 + self.offset)
+    }
+
+    pub fn click(&mut self, abs_pos: (i32, i32)) -> bool {
+        match self.tile_at(abs_pos) {
+            Some(tile) => {
+                self.select(tile);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Stretch the selection from the tile clicked to the one at
+    /// `abs_pos`, making a rectangle of them in the tileset's layout.
+    pub fn drag(&mut self, abs_pos: (i32, i32)) {
+        if let Some(tile) = self.tile_at(abs_pos) {
+            self.selected_to = tile;
+        }
     }
 
     /// Render the tileset picker.
@@ -62,33 +93,30 @@ impl TilePicker {
         let mut rects = vec![
             Rectangle::filled(self.rect, Color::RGBA(32, 32, 32, 255))];
 
-        // Draw some tiles!
-        // XXX: this duplicates the code in Renderer::draw_tile that determines
-        // tileset layout, but that didn't seem like the appropriate place to
-        // have it anyway. Dedup and move when appropriate.
-
+        // Draw some tiles! They're laid out in a row in index order, however
+        // the tileset itself is packed.
         let mut tiles = Vec::new();
 
         // target render dimensions for each tile
         // XXX: replace this with Ratio scaling
-        let (w, h) = ((self.tile_width as f32 * self.scale) as u32,
-                      (self.tile_height as f32 * self.scale) as u32);
+        let (tile_width, tile_height) = self.tileset.tile_size();
+        let (w, h) = ((tile_width as f32 * self.scale) as u32,
+                      (tile_height as f32 * self.scale) as u32);
         let padding = 1;
 
         let n = self.rect.width() / w;
+        let selection = self.stamp().map_or(Vec::new(), |s| s.cells((0, 0)));
 
         for i in 0..n {
             let tile = i + self.offset;
-            let src = Rect::new(
-                (tile * self.tile_width) as i32, 0,
-                self.tile_width, self.tile_height);
+            let src = self.tileset.src(tile);
             let dst = Rect::new(
                 (i * (w + padding)) as i32, padding as i32, w, h);
 
-            tiles.push(Tex::new(&self.tileset, Some(src), dst));
+            tiles.push(Tex::new(self.tileset.asset(), Some(src), dst));
 
             // Add a rectangle if this tile is selected
-            if tile == self.selected {
+            if selection.iter().any(|&(_, t)| t == tile) {
                 rects.push(
                     Rectangle::filled(
                         Rect::new(
@@ -101,3 +129,26 @@ impl TilePicker {
         (rects, tiles)
     }
 }
+
+#[test]
+fn picking_up_an_empty_cell_selects_nothing() {
+    use history::History;
+    use map::{EMPTY, Map, MapLayer};
+    use tools::{Painter, Tool};
+    let tileset = Tileset::strip("strip", (16, 16));
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(tileset.clone(), 2, vec![3, EMPTY]));
+    let mut picker = TilePicker::new(tileset, 0, 0, 960, 66);
+
+    let mut painter = Painter::new();
+    painter.set_tool(Tool::Eyedropper);
+    let picked = painter.press(&mut map, &mut History::new(10), "ground",
+                               (20, 4), picker.selected());
+    assert_eq!(picked, Some(EMPTY));
+    picker.select(picked.unwrap());
+    assert_eq!(picker.stamp(), None);
+
+    // Nothing in the picker is highlighted, only its background drawn
+    let (rects, _) = picker.render();
+    assert_eq!(rects.len(), 1);
+}
@@ -0,0 +1,517 @@
+use sdl2::rect::Rect;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::io;
+
+/// The layout of a tileset image: a grid of equally sized tiles, optionally
+/// inset by a margin and separated by spacing, numbered left to right and
+/// then top to bottom.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tileset {
+    asset: String,
+    tile_w: u32,
+    tile_h: u32,
+    // None means the tiles sit in a single horizontal strip
+    columns: Option<u32>,
+    spacing: u32,
+    margin: u32,
+    // Only tiles with metadata have an entry
+    properties: HashMap<u32, TileProperties>,
+    // The frames of each animated tile, with how long each is shown for in
+    // milliseconds
+    animations: HashMap<u32, Vec<(u32, u32)>>,
+    terrain_sets: Vec<TerrainSet>,
+}
+
+/// What the game knows about a tile beyond how it looks.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TileProperties {
+    /// Whether the tile blocks movement
+    pub solid: bool,
+    /// The kind of ground the tile is, e.g. "water" or "stairs"
+    pub terrain: Option<String>,
+    /// Anything else, for gameplay code to interpret as it likes
+    pub custom: BTreeMap<String, String>,
+}
+
+/// Tiles that fit together to draw the edges between terrains, so painting
+/// a terrain can pick the right tile for each cell from what's around it.
+/// See `autotile`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TerrainSet {
+    /// Tiles with a terrain at each corner, top-left first and going
+    /// clockwise. Neighbouring cells share corners, so the tiles meet
+    /// wherever the corners match.
+    Wang {
+        name: String,
+        tiles: Vec<(u32, [String; 4])>,
+    },
+    /// Tiles for one terrain against anything else, each for a mask of
+    /// which of the eight cells around are the terrain too: 1 for the cell
+    /// above, then 2, 4, ... going clockwise. Corner cells only count when
+    /// both of the cells beside them do, which leaves 47 masks that matter.
+    Blob {
+        name: String,
+        terrain: String,
+        tiles: Vec<(u32, u8)>,
+    },
+}
+
+impl TerrainSet {
+    pub fn name(&self) -> &str {
+        match *self {
+            TerrainSet::Wang { ref name, .. } => name,
+            TerrainSet::Blob { ref name, .. } => name,
+        }
+    }
+
+    /// The terrains that can be painted with this set, in the order they
+    /// first appear.
+    pub fn terrains(&self) -> Vec<&str> {
+        match *self {
+            TerrainSet::Wang { ref tiles, .. } => {
+                let mut terrains: Vec<&str> = Vec::new();
+                for &(_, ref corners) in tiles {
+                    for corner in corners {
+                        if !terrains.contains(&&corner[..]) {
+                            terrains.push(corner);
+                        }
+                    }
+                }
+                terrains
+            },
+            TerrainSet::Blob { ref terrain, .. } => vec![terrain],
+        }
+    }
+}
+
+/// The on-disk form of a tileset, stored next to its image as
+/// `<asset>.tileset.json`. Only the tile size is required, e.g.
+///
+///     {"tile_w": 16, "tile_h": 16, "columns": 8, "spacing": 1, "margin": 1,
+///      "tiles": [{"id": 3, "solid": true},
+///                {"id": 9, "terrain": "water",
+///                 "properties": {"sound": "splash"},
+///                 "animation": [{"tile": 9, "duration": 250},
+///                               {"tile": 10, "duration": 250}]}],
+///      "terrain_sets": [
+///          {"name": "shore", "type": "wang", "tiles": [
+///              {"id": 16, "corners": ["grass", "grass", "water", "grass"]},
+///              ...]},
+///          {"name": "cliffs", "type": "blob", "terrain": "cliff", "tiles": [
+///              {"id": 40, "mask": 255}, ...]}]}
+#[derive(Deserialize)]
+struct Descriptor {
+    tile_w: u32,
+    tile_h: u32,
+    columns: Option<u32>,
+    spacing: Option<u32>,
+    margin: Option<u32>,
+    tiles: Option<Vec<TileDescriptor>>,
+    terrain_sets: Option<Vec<TerrainSetDescriptor>>,
+}
+
+#[derive(Deserialize)]
+struct TileDescriptor {
+    id: u32,
+    solid: Option<bool>,
+    terrain: Option<String>,
+    properties: Option<BTreeMap<String, String>>,
+    animation: Option<Vec<FrameDescriptor>>,
+}
+
+#[derive(Deserialize)]
+struct FrameDescriptor {
+    tile: u32,
+    duration: u32,
+}
+
+#[derive(Deserialize)]
+struct TerrainSetDescriptor {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    terrain: Option<String>,
+    tiles: Vec<TerrainTileDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct TerrainTileDescriptor {
+    id: u32,
+    corners: Option<Vec<String>>,
+    mask: Option<u8>,
+}
+
+impl TerrainSetDescriptor {
+    fn decode(self) -> io::Result<TerrainSet> {
+        let invalid = |what: String| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("terrain set `{}` {}", self.name, what));
+        match &self.kind[..] {
+            "wang" => {
+                let mut tiles = Vec::new();
+                for tile in &self.tiles {
+                    match tile.corners {
+                        Some(ref c) if c.len() == 4 => tiles.push(
+                            (tile.id, [c[0].clone(), c[1].clone(),
+                                       c[2].clone(), c[3].clone()])),
+                        _ => return Err(invalid(format!(
+                            "needs four corners for tile {}", tile.id))),
+                    }
+                }
+                Ok(TerrainSet::Wang { name: self.name.clone(), tiles: tiles })
+            },
+            "blob" => {
+                let terrain = match self.terrain {
+                    Some(ref terrain) => terrain.clone(),
+                    None => return Err(invalid("has no terrain".into())),
+                };
+                let mut tiles = Vec::new();
+                for tile in &self.tiles {
+                    match tile.mask {
+                        Some(mask) => tiles.push((tile.id, mask)),
+                        None => return Err(invalid(format!(
+                            "needs a mask for tile {}", tile.id))),
+                    }
+                }
+                Ok(TerrainSet::Blob {
+                    name: self.name.clone(),
+                    terrain: terrain,
+                    tiles: tiles,
+                })
+            },
+            kind => Err(invalid(format!("has unknown type `{}`", kind))),
+        }
+    }
+}
+
+impl Tileset {
+    /// A tileset whose tiles sit side by side in a single row.
+    pub fn strip(asset: &str, (tw, th): (u32, u32)) -> Tileset {
+        Tileset {
+            asset: asset.into(),
+            tile_w: tw,
+            tile_h: th,
+            columns: None,
+            spacing: 0,
+            margin: 0,
+            properties: HashMap::new(),
+            animations: HashMap::new(),
+            terrain_sets: Vec::new(),
+        }
+    }
+
+    /// A tileset packed into a grid with the given number of columns.
+    pub fn grid(asset: &str, (tw, th): (u32, u32), columns: u32,
+                spacing: u32, margin: u32) -> Tileset {
+        Tileset {
+            asset: asset.into(),
+            tile_w: tw,
+            tile_h: th,
+            columns: Some(columns),
+            spacing: spacing,
+            margin: margin,
+            properties: HashMap::new(),
+            animations: HashMap::new(),
+            terrain_sets: Vec::new(),
+        }
+    }
+
+    /// Load the descriptor for the tileset image `asset`.
+    pub fn load(asset: &str) -> io::Result<Tileset> {
+        let mut f = try!(File::open(format!("{}.tileset.json", asset)));
+        let mut s = String::new();
+        try!(f.read_to_string(&mut s));
+        Tileset::decode(asset, &s)
+    }
+
+    /// Load the descriptor for `asset` if it has one, otherwise assume a
+    /// strip of tiles of the given size, which is how tilesets were laid out
+    /// before descriptors existed. A descriptor that's there but can't be
+    /// read is an error, rather than quietly laying the tiles out wrong.
+    pub fn load_or_strip(asset: &str, tile_size: (u32, u32))
+            -> io::Result<Tileset> {
+        match Tileset::load(asset) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Ok(Tileset::strip(asset, tile_size))
+            },
+            result => result,
+        }
+    }
+
+    fn decode(asset: &str, s: &str) -> io::Result<Tileset> {
+        let d: Descriptor = try!(serde_json::from_str(s)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        if d.tile_w == 0 || d.tile_h == 0 || d.columns == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "tileset has an empty grid"));
+        }
+        let size = (d.tile_w, d.tile_h);
+        let spacing = d.spacing.unwrap_or(0);
+        let margin = d.margin.unwrap_or(0);
+        let mut tileset = match d.columns {
+            Some(columns) => Tileset::grid(asset, size, columns,
+                                           spacing, margin),
+            None => Tileset {
+                spacing: spacing,
+                margin: margin,
+                ..Tileset::strip(asset, size)
+            },
+        };
+        for tile in d.tiles.unwrap_or(Vec::new()) {
+            if let Some(animation) = tile.animation {
+                if animation.is_empty() ||
+                        animation.iter().any(|f| f.duration == 0) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("tile {} has an animation with no frames or \
+                                 a frame that takes no time", tile.id)));
+                }
+                tileset.set_animation(tile.id, animation.iter()
+                    .map(|f| (f.tile, f.duration)).collect());
+            }
+            // Tiles that are only animated have no properties
+            if tile.solid.is_none() && tile.terrain.is_none() &&
+                    tile.properties.is_none() {
+                continue;
+            }
+            tileset.set_properties(tile.id, TileProperties {
+                solid: tile.solid.unwrap_or(false),
+                terrain: tile.terrain,
+                custom: tile.properties.unwrap_or(BTreeMap::new()),
+            });
+        }
+        for set in d.terrain_sets.unwrap_or(Vec::new()) {
+            tileset.add_terrain_set(try!(set.decode()));
+        }
+        Ok(tileset)
+    }
+
+    /// The name of the image asset the tiles are cut from.
+    pub fn asset(&self) -> &str {
+        &self.asset
+    }
+
+    /// The size in pixels of a single tile.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (self.tile_w, self.tile_h)
+    }
+
+    /// The number of columns in the grid, or None if the tiles are in a
+    /// single strip.
+    pub fn columns(&self) -> Option<u32> {
+        self.columns
+    }
+
+    /// The (spacing, margin) of the grid, in pixels.
+    pub fn spacing_and_margin(&self) -> (u32, u32) {
+        (self.spacing, self.margin)
+    }
+
+    pub fn set_spacing_and_margin(&mut self, spacing: u32, margin: u32) {
+        self.spacing = spacing;
+        self.margin = margin;
+    }
+
+    /// Every tile with metadata, in order.
+    pub fn all_properties(&self) -> Vec<(u32, &TileProperties)> {
+        let mut all: Vec<_> = self.properties.iter()
+            .map(|(&n, p)| (n, p))
+            .collect();
+        all.sort_by_key(|&(n, _)| n);
+        all
+    }
+
+    /// The metadata for tile `n`, if it has any.
+    pub fn properties(&self, n: u32) -> Option<&TileProperties> {
+        self.properties.get(&n)
+    }
+
+    /// Replace the metadata for tile `n`.
+    pub fn set_properties(&mut self, n: u32, properties: TileProperties) {
+        self.properties.insert(n, properties);
+    }
+
+    /// Make tile `n` cycle through `frames`, each a tile and how many
+    /// milliseconds to show it for. There must be at least one frame, and
+    /// they can't all take no time.
+    pub fn set_animation(&mut self, n: u32, frames: Vec<(u32, u32)>) {
+        assert!(frames.iter().any(|&(_, duration)| duration > 0));
+        self.animations.insert(n, frames);
+    }
+
+    /// Whether tile `n` is animated.
+    pub fn is_animated(&self, n: u32) -> bool {
+        self.animations.contains_key(&n)
+    }
+
+    /// The tile to show for tile `n`, `time` seconds into the game. That's
+    /// `n` itself unless it's animated.
+    pub fn frame(&self, n: u32, time: f32) -> u32 {
+        let frames = match self.animations.get(&n) {
+            Some(frames) => frames,
+            None => return n,
+        };
+        let total: u64 = frames.iter().map(|&(_, d)| d as u64).sum();
+        let mut t = (time.max(0.0) as f64 * 1000.0) as u64 % total;
+        for &(tile, duration) in frames {
+            if t < duration as u64 {
+                return tile;
+            }
+            t -= duration as u64;
+        }
+        unreachable!()
+    }
+
+    /// The terrain sets the tileset's tiles make up, for autotiling.
+    pub fn terrain_sets(&self) -> &[TerrainSet] {
+        &self.terrain_sets
+    }
+
+    /// Find a terrain set by name.
+    pub fn terrain_set(&self, name: &str) -> Option<&TerrainSet> {
+        self.terrain_sets.iter().find(|s| s.name() == name)
+    }
+
+    pub fn add_terrain_set(&mut self, set: TerrainSet) {
+        self.terrain_sets.push(set);
+    }
+
+    /// The region of the tileset image that holds tile `n`.
+    pub fn src(&self, n: u32) -> Rect {
+        let (col, row) = match self.columns {
+            Some(columns) => (n % columns, n / columns),
+            None => (n, 0),
+        };
+        Rect::new(
+            (self.margin + col * (self.tile_w + self.spacing)) as i32,
+            (self.margin + row * (self.tile_h + self.spacing)) as i32,
+            self.tile_w, self.tile_h)
+    }
+}
+
+#[test]
+fn strip_indexes_along_one_row() {
+    let tileset = Tileset::strip("strip", (16, 24));
+    assert_eq!(tileset.src(0), Rect::new(0, 0, 16, 24));
+    assert_eq!(tileset.src(5), Rect::new(80, 0, 16, 24));
+}
+
+#[test]
+fn grid_wraps_rows_with_spacing_and_margin() {
+    let tileset = Tileset::grid("grid", (16, 16), 4, 2, 1);
+    assert_eq!(tileset.src(0), Rect::new(1, 1, 16, 16));
+    assert_eq!(tileset.src(3), Rect::new(55, 1, 16, 16));
+    assert_eq!(tileset.src(4), Rect::new(1, 19, 16, 16));
+    assert_eq!(tileset.src(9), Rect::new(19, 37, 16, 16));
+}
+
+#[test]
+fn only_missing_descriptors_mean_strips() {
+    use std::io::Write;
+    let dir = ::std::env::temp_dir().join("tarnished-tileset-test");
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let asset = dir.join("broken");
+    let asset = asset.to_str().unwrap();
+    ::std::fs::File::create(format!("{}.tileset.json", asset)).unwrap()
+        .write_all(b"{\"tile_w\":").unwrap();
+    assert!(Tileset::load_or_strip(asset, (16, 16)).is_err());
+
+    let missing = dir.join("missing");
+    let missing = missing.to_str().unwrap();
+    assert_eq!(Tileset::load_or_strip(missing, (16, 16)).unwrap(),
+               Tileset::strip(missing, (16, 16)));
+}
+
+#[test]
+fn descriptor_fields_are_optional() {
+    let tileset = Tileset::decode("grid", "{\"tile_w\":8,\"tile_h\":8}");
+    assert_eq!(tileset.unwrap(), Tileset::strip("grid", (8, 8)));
+
+    let tileset = Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"columns\":3,\"spacing\":1}");
+    assert_eq!(tileset.unwrap(), Tileset::grid("grid", (8, 8), 3, 1, 0));
+
+    assert!(Tileset::decode("grid", "{\"tile_w\":8}").is_err());
+    assert!(Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"columns\":0}").is_err());
+}
+
+#[test]
+fn descriptor_carries_tile_properties() {
+    let tileset = Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"tiles\":[\
+            {\"id\":3,\"solid\":true},\
+            {\"id\":9,\"terrain\":\"water\",\
+             \"properties\":{\"sound\":\"splash\"}}]}").unwrap();
+
+    assert_eq!(tileset.properties(0), None);
+
+    let wall = tileset.properties(3).unwrap();
+    assert!(wall.solid);
+    assert_eq!(wall.terrain, None);
+
+    let water = tileset.properties(9).unwrap();
+    assert!(!water.solid);
+    assert_eq!(water.terrain, Some("water".into()));
+    assert_eq!(water.custom.get("sound").map(|s| &s[..]), Some("splash"));
+    assert_eq!(water.custom.get("depth"), None);
+}
+
+#[test]
+fn descriptor_carries_terrain_sets() {
+    let tileset = Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"terrain_sets\":[\
+            {\"name\":\"shore\",\"type\":\"wang\",\"tiles\":[\
+                {\"id\":1,\"corners\":[\"grass\",\"grass\",\"water\",\
+                                        \"grass\"]}]},\
+            {\"name\":\"cliffs\",\"type\":\"blob\",\"terrain\":\"cliff\",\
+             \"tiles\":[{\"id\":7,\"mask\":255}]}]}").unwrap();
+
+    let shore = tileset.terrain_set("shore").unwrap();
+    assert_eq!(shore.terrains(), vec!["grass", "water"]);
+    assert_eq!(*shore, TerrainSet::Wang {
+        name: "shore".into(),
+        tiles: vec![(1, ["grass".into(), "grass".into(), "water".into(),
+                         "grass".into()])],
+    });
+    assert_eq!(tileset.terrain_set("cliffs").unwrap().terrains(),
+               vec!["cliff"]);
+    assert_eq!(tileset.terrain_set("sand"), None);
+
+    for bad in &["{\"name\":\"x\",\"type\":\"hex\",\"tiles\":[]}",
+                 "{\"name\":\"x\",\"type\":\"blob\",\"tiles\":[]}",
+                 "{\"name\":\"x\",\"type\":\"wang\",\"tiles\":[\
+                     {\"id\":1,\"corners\":[\"a\"]}]}"] {
+        let json = format!(
+            "{{\"tile_w\":8,\"tile_h\":8,\"terrain_sets\":[{}]}}", bad);
+        assert!(Tileset::decode("grid", &json).is_err());
+    }
+}
+
+#[test]
+fn animated_tiles_cycle_through_their_frames() {
+    let tileset = Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"tiles\":[\
+            {\"id\":4,\"animation\":[{\"tile\":4,\"duration\":100},\
+                                    {\"tile\":5,\"duration\":300}]}]}")
+        .unwrap();
+    assert!(tileset.is_animated(4));
+    assert!(!tileset.is_animated(5));
+    assert_eq!(tileset.properties(4), None);
+
+    let frames: Vec<_> = [0.0, 0.099, 0.1, 0.399, 0.4, 10.05].iter()
+        .map(|&t| tileset.frame(4, t))
+        .collect();
+    assert_eq!(frames, vec![4, 4, 5, 5, 4, 4]);
+    assert_eq!(tileset.frame(5, 0.2), 5);
+
+    assert!(Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"tiles\":[\
+            {\"id\":4,\"animation\":[]}]}").is_err());
+    assert!(Tileset::decode("grid",
+        "{\"tile_w\":8,\"tile_h\":8,\"tiles\":[\
+            {\"id\":4,\"animation\":[{\"tile\":4,\"duration\":0}]}]}")
+        .is_err());
+}
@@ -0,0 +1,607 @@
+//! The map editor's paint tools.
+//!
+//! Each tool turns presses, drags and releases of the mouse into cells to
+//! paint, going through the History so a whole use of a tool is undone at
+//! once.
+use autotile;
+use entity::{self, KINDS};
+use history::History;
+use map::{Map, MapLayer, Object, floor_div};
+use sdl2::keyboard::Keycode;
+use std::cmp::{max, min};
+use stamp::Stamp;
+use std::collections::HashSet;
+use tileset::Tileset;
+
+/// What the mouse does in the editor.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Tool {
+    /// Paints every cell the mouse is dragged over.
+    Brush,
+    /// Fills the rectangle between where the mouse is pressed and released.
+    Rectangle,
+    /// Fills the area around the cell clicked that holds the same tile.
+    Fill,
+    /// Paints a straight line from where the mouse is pressed to where it's
+    /// released.
+    Line,
+    /// Picks up the tile clicked, to paint with.
+    Eyedropper,
+    /// Copies the rectangle between where the mouse is pressed and
+    /// released, then switches to the stamp to paste it.
+    Copy,
+    /// Paints the whole stamp wherever the mouse is dragged, repeating it
+    /// edge to edge.
+    Stamp,
+    /// Paints a terrain wherever the mouse is dragged, picking tiles for
+    /// the edges with the cells around.
+    Terrain,
+    /// Picks up objects and moves them around, or places a new one where
+    /// there isn't one.
+    Object,
+}
+
+impl Tool {
+    /// The tool picked by a key, if any: B, R, G (for bucket), L, I, C and
+    /// S. T and O aren't here, since pressing them again goes on to the
+    /// next terrain or kind of object; they're `Painter::next_terrain` and
+    /// `Painter::next_kind`.
+    pub fn from_key(keycode: Keycode) -> Option<Tool> {
+        match keycode {
+            Keycode::B => Some(Tool::Brush),
+            Keycode::R => Some(Tool::Rectangle),
+            Keycode::G => Some(Tool::Fill),
+            Keycode::L => Some(Tool::Line),
+            Keycode::I => Some(Tool::Eyedropper),
+            Keycode::C => Some(Tool::Copy),
+            Keycode::S => Some(Tool::Stamp),
+            _ => None,
+        }
+    }
+}
+
+/// Every cell of the rectangle with corners `from` and `to`, inclusive.
+pub fn rectangle(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let mut cells = Vec::new();
+    for y in min(from.1, to.1)..max(from.1, to.1) + 1 {
+        for x in min(from.0, to.0)..max(from.0, to.0) + 1 {
+            cells.push((x, y));
+        }
+    }
+    cells
+}
+
+/// The cells on a straight line from `from` to `to`, both included, with
+/// no gaps between them (Bresenham's).
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let sx = if from.0 < to.0 { 1 } else { -1 };
+    let sy = if from.1 < to.1 { 1 } else { -1 };
+    let (mut x, mut y) = from;
+    let mut err = dx + dy;
+    let mut cells = vec![from];
+    while (x, y) != to {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        cells.push((x, y));
+    }
+    cells
+}
+
+/// The cells connected to `start`, across edges, that hold the same tile as
+/// it does. Layers have no edge of their own, so the fill stops at their
+/// bounds; starting outside of them fills nothing.
+pub fn flood(layer: &MapLayer, start: (i32, i32)) -> Vec<(i32, i32)> {
+    let bounds = layer.bounds();
+    let mut cells = Vec::new();
+    if !bounds.contains(start) {
+        return cells;
+    }
+    let tile = layer.tile(start);
+    let mut seen = HashSet::new();
+    let mut todo = vec![start];
+    seen.insert(start);
+    while let Some((x, y)) = todo.pop() {
+        cells.push((x, y));
+        for &next in &[(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            if bounds.contains(next) && layer.tile(next) == tile &&
+                    seen.insert(next) {
+                todo.push(next);
+            }
+        }
+    }
+    cells
+}
+
+/// The object layer new objects are placed in.
+pub const OBJECTS: &'static str = "objects";
+
+/// Whether `point`, in pixels, is inside an object.
+fn contains(object: &Object, (x, y): (i32, i32)) -> bool {
+    x >= object.x && x < object.x + object.width as i32 &&
+        y >= object.y && y < object.y + object.height as i32
+}
+
+/// The editor's current tool, and where the mouse was pressed while it's
+/// held down.
+/// XXX: rectangles and lines aren't shown until the mouse is released.
+pub struct Painter {
+    tool: Tool,
+    start: Option<(i32, i32)>,
+    // What the stamp tool paints, once something has been copied or picked
+    stamp: Option<Stamp>,
+    // The terrain set and terrain the terrain tool paints
+    terrain: Option<(String, String)>,
+    // The kind of object the object tool places, from entity::KINDS
+    kind: usize,
+    // The object picked up by the object tool, by layer and index, and
+    // where on it the mouse took hold
+    selected: Option<(String, usize)>,
+    grab: (i32, i32),
+}
+
+impl Painter {
+    pub fn new() -> Painter {
+        Painter {
+            tool: Tool::Brush,
+            start: None,
+            stamp: None,
+            terrain: None,
+            kind: 0,
+            selected: None,
+            grab: (0, 0),
+        }
+    }
+
+    pub fn stamp(&self) -> Option<&Stamp> {
+        self.stamp.as_ref()
+    }
+
+    /// Pick up a stamp to paint with, switching to the stamp tool.
+    pub fn set_stamp(&mut self, stamp: Stamp) {
+        self.stamp = Some(stamp);
+        self.set_tool(Tool::Stamp);
+    }
+
+    #[cfg(test)]
+    pub fn terrain(&self) -> Option<&(String, String)> {
+        self.terrain.as_ref()
+    }
+
+    /// Switch to the terrain tool, with the next of the tileset's terrains
+    /// after the one picked last time, going round to the first.
+    pub fn next_terrain(&mut self, tileset: &Tileset) {
+        let all: Vec<(String, String)> = tileset.terrain_sets().iter()
+            .flat_map(|set| set.terrains().into_iter()
+                .map(move |t| (set.name().to_string(), t.to_string())))
+            .collect();
+        let next = match self.terrain {
+            Some(ref terrain) => all.iter().position(|t| t == terrain)
+                .map_or(0, |i| i + 1),
+            None => 0,
+        };
+        self.set_tool(Tool::Terrain);
+        self.terrain = all.get(next).or(all.first()).cloned();
+    }
+
+    fn paint_terrain(&self, map: &mut Map, history: &mut History,
+                     layer: &str, cell: (i32, i32)) {
+        if let Some((ref set, ref terrain)) = self.terrain {
+            autotile::paint(map, history, layer, set, terrain, cell).ok();
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        KINDS[self.kind]
+    }
+
+    /// The tool in use, and what it's painting or placing, for the GUI.
+    pub fn status(&self) -> String {
+        match self.tool {
+            Tool::Terrain => match self.terrain {
+                Some((ref set, ref terrain)) =>
+                    format!("Terrain: {} ({})", terrain, set),
+                None => "Terrain: none in this tileset".into(),
+            },
+            Tool::Object => format!("Object: {}", self.kind()),
+            tool => format!("{:?}", tool),
+        }
+    }
+
+    /// Switch to the object tool, or if it's already in use, to placing
+    /// the next of the KINDS.
+    pub fn next_kind(&mut self) {
+        if self.tool == Tool::Object {
+            self.kind = (self.kind + 1) % KINDS.len();
+        }
+        self.set_tool(Tool::Object);
+    }
+
+    /// The object picked up last by the object tool, by layer and index.
+    pub fn selected(&self) -> Option<&(String, usize)> {
+        self.selected.as_ref()
+    }
+
+    /// Let go of the object picked up, e.g. since undoing may have taken
+    /// it away.
+    pub fn deselect(&mut self) {
+        self.selected = None;
+    }
+
+    /// Delete the object picked up, if there is one.
+    pub fn delete(&mut self, map: &mut Map, history: &mut History) {
+        if let Some((layer, index)) = self.selected.take() {
+            history.remove_object(map, &layer, index).ok();
+        }
+    }
+
+    /// Take hold of the topmost object under `point`, in pixels, or if
+    /// there's none, place a new one filling the cell of `layer` there.
+    fn grab_object(&mut self, map: &mut Map, history: &mut History,
+                   layer: &str, point: (i32, i32)) {
+        let found = map.object_layers().iter().rev()
+            .filter_map(|l| l.objects.iter().rposition(|o| contains(o, point))
+                .map(|i| (l.name.clone(), i)))
+            .next();
+        let (layer, index) = match found {
+            Some(found) => found,
+            None => {
+                let (tw, th) = match map.layer(layer) {
+                    Some(l) => l.tileset().tile_size(),
+                    None => return,
+                };
+                let (x, y) = map.layer(layer).unwrap().point_to_cell(point);
+                let count: usize = map.object_layers().iter()
+                    .map(|l| l.objects.len())
+                    .sum();
+                let name = format!("{} {}", self.kind(), count + 1);
+                let object = entity::new_object(self.kind(), &name,
+                    (x * tw as i32, y * th as i32), (tw, th));
+                (OBJECTS.to_string(), history.add_object(map, OBJECTS, object))
+            },
+        };
+        let object = &map.object_layer(&layer).unwrap().objects[index];
+        self.grab = (point.0 - object.x, point.1 - object.y);
+        self.selected = Some((layer, index));
+    }
+
+    #[cfg(test)]
+    pub fn tool(&self) -> Tool {
+        self.tool
+    }
+
+    /// Switch tools, dropping anything the old one was in the middle of.
+    pub fn set_tool(&mut self, tool: Tool) {
+        self.tool = tool;
+        self.start = None;
+        self.selected = None;
+    }
+
+    /// The cell of a layer under a point, in pixels.
+    fn cell(map: &Map, layer: &str, point: (i32, i32))
+            -> Option<(i32, i32)> {
+        map.layer(layer).map(|l| l.point_to_cell(point))
+    }
+
+    fn paint_all(map: &mut Map, history: &mut History, layer: &str,
+                 cells: &[(i32, i32)], tile: u32) {
+        for &cell in cells {
+            history.paint(map, layer, cell, tile).ok();
+        }
+    }
+
+    /// Paint the stamp, lined up with where the mouse was pressed, so that
+    /// `cell` is in it. Stamps from another tileset than the layer's aren't
+    /// painted, since their tiles would mean something else there.
+    fn paint_stamp(&self, map: &mut Map, history: &mut History, layer: &str,
+                   cell: (i32, i32)) {
+        let (stamp, start) = match (self.stamp.as_ref(), self.start) {
+            (Some(stamp), Some(start)) => (stamp, start),
+            _ => return,
+        };
+        match map.layer(layer) {
+            Some(l) if l.tileset().asset() == stamp.asset() => {},
+            _ => return,
+        }
+        let (w, h) = stamp.size();
+        let (w, h) = (w as i32, h as i32);
+        let origin = (start.0 + floor_div(cell.0 - start.0, w) * w,
+                      start.1 + floor_div(cell.1 - start.1, h) * h);
+        for (cell, tile) in stamp.cells(origin) {
+            history.paint(map, layer, cell, tile).ok();
+        }
+    }
+
+    /// The mouse was pressed at `point`, in pixels. Returns the tile picked
+    /// up if this is the eyedropper; that may be EMPTY, which paints
+    /// cells empty again.
+    pub fn press(&mut self, map: &mut Map, history: &mut History,
+                 layer: &str, point: (i32, i32), tile: u32) -> Option<u32> {
+        let cell = match Painter::cell(map, layer, point) {
+            Some(cell) => cell,
+            None => return None,
+        };
+        history.begin();
+        self.start = Some(cell);
+        match self.tool {
+            Tool::Brush => {
+                history.paint(map, layer, cell, tile).ok();
+            },
+            Tool::Fill => {
+                let cells = flood(map.layer(layer).unwrap(), cell);
+                Painter::paint_all(map, history, layer, &cells, tile);
+            },
+            Tool::Eyedropper => {
+                return map.layer(layer).map(|l| l.tile(cell));
+            },
+            Tool::Stamp => self.paint_stamp(map, history, layer, cell),
+            Tool::Terrain => self.paint_terrain(map, history, layer, cell),
+            Tool::Object => self.grab_object(map, history, layer, point),
+            Tool::Rectangle | Tool::Line | Tool::Copy => {},
+        }
+        None
+    }
+
+    /// The mouse moved to `point`, in pixels, while held down.
+    pub fn drag(&mut self, map: &mut Map, history: &mut History,
+                layer: &str, point: (i32, i32), tile: u32) {
+        if self.start.is_none() {
+            return;
+        }
+        if let Some(cell) = Painter::cell(map, layer, point) {
+            match self.tool {
+                Tool::Brush => {
+                    history.paint(map, layer, cell, tile).ok();
+                },
+                Tool::Stamp => self.paint_stamp(map, history, layer, cell),
+                Tool::Terrain =>
+                    self.paint_terrain(map, history, layer, cell),
+                Tool::Object => {
+                    if let Some((ref layer, index)) = self.selected {
+                        let to = (point.0 - self.grab.0,
+                                  point.1 - self.grab.1);
+                        history.move_object(map, layer, index, to).ok();
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// The mouse was released at `point`, in pixels, finishing this use of
+    /// the tool as one step of the history.
+    pub fn release(&mut self, map: &mut Map, history: &mut History,
+                   layer: &str, point: (i32, i32), tile: u32) {
+        let start = match self.start.take() {
+            Some(start) => start,
+            None => return,
+        };
+        if let Some(end) = Painter::cell(map, layer, point) {
+            let cells = match self.tool {
+                Tool::Rectangle => rectangle(start, end),
+                Tool::Line => line(start, end),
+                _ => Vec::new(),
+            };
+            Painter::paint_all(map, history, layer, &cells, tile);
+            if self.tool == Tool::Copy {
+                let copied = Stamp::copy(map.layer(layer).unwrap(),
+                                         start, end);
+                self.set_stamp(copied);
+            }
+        }
+        history.end();
+    }
+}
+
+#[test]
+fn rectangles_work_from_any_corner() {
+    let cells = vec![(1, 2), (2, 2), (1, 3), (2, 3)];
+    assert_eq!(rectangle((1, 2), (2, 3)), cells);
+    assert_eq!(rectangle((2, 3), (1, 2)), cells);
+    assert_eq!(rectangle((-1, 0), (-1, 0)), vec![(-1, 0)]);
+}
+
+#[test]
+fn lines_have_no_gaps() {
+    assert_eq!(line((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+    assert_eq!(line((0, 0), (-2, -2)), vec![(0, 0), (-1, -1), (-2, -2)]);
+    assert_eq!(line((0, 0), (4, 2)),
+               vec![(0, 0), (1, 1), (2, 1), (3, 2), (4, 2)]);
+    for &to in &[(5, -3), (-7, 2), (1, 6)] {
+        let cells = line((0, 0), to);
+        assert_eq!(*cells.last().unwrap(), to);
+        for pair in cells.windows(2) {
+            assert!((pair[0].0 - pair[1].0).abs() <= 1);
+            assert!((pair[0].1 - pair[1].1).abs() <= 1);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_map() -> Map {
+    use tileset::Tileset;
+    // A wall of 1s down the middle, splitting the 0s in two
+    let mut map = Map::new();
+    map.push("ground", -1, MapLayer::new(Tileset::strip("foobar", (16, 16)),
+        4, vec![0, 1, 0, 0,
+                0, 1, 0, 0,
+                0, 1, 0, 0]));
+    map
+}
+
+#[test]
+fn flood_stops_at_other_tiles_and_the_bounds() {
+    let map = test_map();
+    let layer = map.layer("ground").unwrap();
+    let mut cells = flood(layer, (3, 1));
+    cells.sort();
+    assert_eq!(cells, vec![(2, 0), (2, 1), (2, 2), (3, 0), (3, 1), (3, 2)]);
+    assert_eq!(flood(layer, (1, 0)).len(), 3);
+    assert_eq!(flood(layer, (9, 9)), vec![]);
+}
+
+#[test]
+fn each_use_of_a_tool_is_one_step() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    let before = map.layer("ground").unwrap().tiles();
+
+    painter.set_tool(Tool::Fill);
+    painter.press(&mut map, &mut history, "ground", (0, 0), 2);
+    painter.release(&mut map, &mut history, "ground", (0, 0), 2);
+    painter.set_tool(Tool::Line);
+    painter.press(&mut map, &mut history, "ground", (2*16, 0), 3);
+    painter.release(&mut map, &mut history, "ground", (3*16, 2*16), 3);
+    assert_eq!(map.layer("ground").unwrap().tiles(),
+               vec![2, 1, 3, 0,
+                    2, 1, 0, 3,
+                    2, 1, 0, 3]);
+
+    history.undo(&mut map);
+    history.undo(&mut map);
+    assert_eq!(map.layer("ground").unwrap().tiles(), before);
+}
+
+#[test]
+fn rectangles_are_painted_on_release() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    painter.set_tool(Tool::Rectangle);
+    painter.press(&mut map, &mut history, "ground", (3*16 + 5, 2*16), 4);
+    painter.drag(&mut map, &mut history, "ground", (2*16, 16), 4);
+    assert_eq!(map.layer("ground").unwrap().tile((3, 2)), 0);
+    painter.release(&mut map, &mut history, "ground", (2*16, 16), 4);
+    assert_eq!(map.layer("ground").unwrap().tiles(),
+               vec![0, 1, 0, 0,
+                    0, 1, 4, 4,
+                    0, 1, 4, 4]);
+}
+
+#[test]
+fn the_eyedropper_picks_up_tiles() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    painter.set_tool(Tool::Eyedropper);
+    assert_eq!(painter.press(&mut map, &mut history, "ground", (20, 40), 0),
+               Some(1));
+    painter.release(&mut map, &mut history, "ground", (20, 40), 0);
+    assert!(!history.undo(&mut map));
+}
+
+#[test]
+fn copies_are_pasted_edge_to_edge() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    painter.set_tool(Tool::Copy);
+    painter.press(&mut map, &mut history, "ground", (0, 0), 0);
+    painter.release(&mut map, &mut history, "ground", (16, 0), 0);
+    assert_eq!(painter.tool(), Tool::Stamp);
+    assert_eq!(painter.stamp().unwrap().size(), (2, 1));
+
+    // Dragging across five cells paints the stamp three times, the last
+    // one hanging off the right of the layer
+    painter.press(&mut map, &mut history, "ground", (-16, 2*16), 0);
+    for x in 0..5 {
+        painter.drag(&mut map, &mut history, "ground", (x*16, 2*16), 0);
+    }
+    painter.release(&mut map, &mut history, "ground", (4*16, 2*16), 0);
+    let layer = map.layer("ground").unwrap();
+    assert_eq!((-1..5).map(|x| layer.tile((x, 2))).collect::<Vec<_>>(),
+               vec![0, 1, 0, 1, 0, 1]);
+
+    history.undo(&mut map);
+    assert_eq!(map.layer("ground").unwrap().tiles(),
+               test_map().layer("ground").unwrap().tiles());
+}
+
+#[test]
+fn stamps_from_other_tilesets_are_not_painted() {
+    use tileset::Tileset;
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    let other = Tileset::strip("other", (16, 16));
+    painter.set_stamp(Stamp::from_tileset(&other, 5, 5).unwrap());
+    painter.press(&mut map, &mut history, "ground", (0, 0), 0);
+    painter.release(&mut map, &mut history, "ground", (0, 0), 0);
+    assert!(!history.undo(&mut map));
+}
+
+#[test]
+fn terrains_are_picked_in_turn() {
+    use tileset::TerrainSet;
+    let mut tileset = Tileset::strip("foobar", (16, 16));
+    let mut painter = Painter::new();
+    assert_eq!(painter.status(), "Brush");
+    painter.next_terrain(&tileset);
+    assert_eq!(painter.terrain(), None);
+    assert_eq!(painter.status(), "Terrain: none in this tileset");
+
+    tileset.add_terrain_set(TerrainSet::Blob {
+        name: "cliffs".into(),
+        terrain: "cliff".into(),
+        tiles: vec![],
+    });
+    tileset.add_terrain_set(TerrainSet::Blob {
+        name: "hedges".into(),
+        terrain: "hedge".into(),
+        tiles: vec![],
+    });
+    let picked: Vec<_> = (0..3).map(|_| {
+        painter.next_terrain(&tileset);
+        painter.terrain().unwrap().1.clone()
+    }).collect();
+    assert_eq!(picked, vec!["cliff", "hedge", "cliff"]);
+    assert_eq!(painter.tool(), Tool::Terrain);
+    assert_eq!(painter.status(), "Terrain: cliff (cliffs)");
+}
+
+#[test]
+fn objects_are_placed_moved_and_deleted() {
+    let mut map = test_map();
+    let mut history = History::new(10);
+    let mut painter = Painter::new();
+    let objects = |map: &Map| -> Vec<(String, String, i32, i32)> {
+        map.object_layer(OBJECTS).map_or(Vec::new(), |l| l.objects.iter()
+            .map(|o| (o.name.clone(), o.kind.clone(), o.x, o.y))
+            .collect())
+    };
+
+    // Clicking empty space places an object in the cell there
+    painter.next_kind();
+    painter.next_kind();
+    assert_eq!(painter.kind(), "item");
+    painter.press(&mut map, &mut history, "ground", (20, 40), 0);
+    painter.release(&mut map, &mut history, "ground", (20, 40), 0);
+    assert_eq!(objects(&map), vec![("item 1".into(), "item".into(), 16, 32)]);
+
+    // Dragging it keeps hold of it where it was clicked, to the pixel
+    painter.press(&mut map, &mut history, "ground", (30, 40), 0);
+    painter.drag(&mut map, &mut history, "ground", (35, 43), 0);
+    painter.drag(&mut map, &mut history, "ground", (40, 50), 0);
+    painter.release(&mut map, &mut history, "ground", (40, 50), 0);
+    assert_eq!(objects(&map), vec![("item 1".into(), "item".into(), 26, 42)]);
+    assert_eq!(painter.selected(), Some(&(OBJECTS.to_string(), 0)));
+
+    painter.delete(&mut map, &mut history);
+    assert_eq!(objects(&map), vec![]);
+    assert_eq!(painter.selected(), None);
+
+    // Each of those was one step
+    history.undo(&mut map);
+    history.undo(&mut map);
+    assert_eq!(objects(&map), vec![("item 1".into(), "item".into(), 16, 32)]);
+    history.undo(&mut map);
+    assert_eq!(objects(&map), vec![]);
+}
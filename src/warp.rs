@@ -0,0 +1,194 @@
+//! Warps: regions of a map that send whoever walks into them to a place on
+//! another map, e.g. the door of a house and the inside of it.
+//!
+//! A warp is an object of kind `warp` in any of a map's object layers, with
+//! these properties:
+//!
+//! - `map`: the map file to go to, relative to the one the warp is on
+//! - `x`, `y`: where to put the player on it, in pixels
+//! - `facing` (optional): `down`, `left`, `up` or `right`
+//! - `transition` (optional): `fade` to fade in from black, or `cut` (the
+//!   default) to just switch
+use brobot::Direction;
+use collision::Aabb;
+use map::{Map, Object};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How the screen goes from one map to the next.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Transition {
+    Cut,
+    Fade,
+}
+
+/// How long a fade takes, in seconds.
+const FADE_TIME: f32 = 0.4;
+
+impl Transition {
+    /// How opaque the black drawn over the screen should be, `elapsed`
+    /// seconds after the warp, or None once the transition is over.
+    pub fn overlay(&self, elapsed: f32) -> Option<u8> {
+        match *self {
+            Transition::Fade if elapsed < FADE_TIME => {
+                let left = 1.0 - elapsed.max(0.0) / FADE_TIME;
+                Some((left * 255.0).round() as u8)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A region of a map that leads somewhere else.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Warp {
+    /// The region, in pixels
+    pub area: Aabb,
+    /// The map to go to, as named on the warp
+    pub map: String,
+    /// Where on it to put the player, in pixels
+    pub to: (i32, i32),
+    pub facing: Option<Direction>,
+    pub transition: Transition,
+}
+
+fn invalid(object: &Object, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("warp `{}` {}", object.name, what))
+}
+
+impl Warp {
+    /// Read a warp from an object of kind `warp`.
+    pub fn from_object(object: &Object) -> io::Result<Warp> {
+        let map = match object.properties.get("map") {
+            Some(map) => map.clone(),
+            None => return Err(invalid(object, "doesn't say which map")),
+        };
+        let coordinate = |name: &str| -> io::Result<i32> {
+            match object.properties.get(name).map(|v| v.parse()) {
+                Some(Ok(v)) => Ok(v),
+                _ => Err(invalid(object, &format!(
+                    "needs a whole number `{}` to warp to", name))),
+            }
+        };
+        let to = (try!(coordinate("x")), try!(coordinate("y")));
+        let facing = match object.properties.get("facing").map(|f| &f[..]) {
+            None => None,
+            Some("down") => Some(Direction::Down),
+            Some("left") => Some(Direction::Left),
+            Some("up") => Some(Direction::Up),
+            Some("right") => Some(Direction::Right),
+            Some(f) => return Err(invalid(object, &format!(
+                "faces `{}`, which isn't a direction", f))),
+        };
+        let transition = match object.properties.get("transition")
+                .map(|t| &t[..]) {
+            None | Some("cut") => Transition::Cut,
+            Some("fade") => Transition::Fade,
+            Some(t) => return Err(invalid(object, &format!(
+                "has unknown transition `{}`", t))),
+        };
+        Ok(Warp {
+            area: Aabb::new(object.x as f32, object.y as f32,
+                            object.width as f32, object.height as f32),
+            map: map,
+            to: to,
+            facing: facing,
+            transition: transition,
+        })
+    }
+
+    /// The file of the map to go to, for a warp on the map loaded from
+    /// `from`.
+    pub fn path(&self, from: &Path) -> PathBuf {
+        from.parent().unwrap_or(Path::new("")).join(&self.map)
+    }
+}
+
+/// Every warp on a map.
+pub fn warps(map: &Map) -> io::Result<Vec<Warp>> {
+    let mut warps = Vec::new();
+    for layer in map.object_layers() {
+        for object in layer.objects.iter().filter(|o| o.kind == "warp") {
+            warps.push(try!(Warp::from_object(object)));
+        }
+    }
+    Ok(warps)
+}
+
+/// The first of `warps` that `feet`, in pixels, overlap.
+pub fn find(warps: &[Warp], feet: Aabb) -> Option<&Warp> {
+    warps.iter().find(|w| w.area.overlaps(&feet))
+}
+
+#[cfg(test)]
+fn door(properties: &[(&str, &str)]) -> Object {
+    Object {
+        name: "door".into(),
+        kind: "warp".into(),
+        x: 32,
+        y: 48,
+        width: 16,
+        height: 8,
+        properties: properties.iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    }
+}
+
+#[test]
+fn warps_are_read_from_objects() {
+    let warp = Warp::from_object(&door(&[
+        ("map", "house.json"), ("x", "40"), ("y", "-8"), ("facing", "up"),
+        ("transition", "fade")])).unwrap();
+    assert_eq!(warp, Warp {
+        area: Aabb::new(32.0, 48.0, 16.0, 8.0),
+        map: "house.json".into(),
+        to: (40, -8),
+        facing: Some(Direction::Up),
+        transition: Transition::Fade,
+    });
+    assert_eq!(warp.path(Path::new("assets/map.json")),
+               PathBuf::from("assets/house.json"));
+
+    let warp = Warp::from_object(&door(&[
+        ("map", "house.json"), ("x", "40"), ("y", "8")])).unwrap();
+    assert_eq!(warp.facing, None);
+    assert_eq!(warp.transition, Transition::Cut);
+
+    for bad in &[&[("x", "1"), ("y", "2")][..],
+                 &[("map", "a"), ("x", "1")][..],
+                 &[("map", "a"), ("x", "1"), ("y", "two")][..],
+                 &[("map", "a"), ("x", "1"), ("y", "2"), ("facing", "in")][..],
+                 &[("map", "a"), ("x", "1"), ("y", "2"),
+                   ("transition", "swirl")][..]] {
+        assert!(Warp::from_object(&door(bad)).is_err());
+    }
+}
+
+#[test]
+fn warps_are_found_under_feet() {
+    use map::ObjectLayer;
+    let mut map = Map::new();
+    let mut sign = door(&[]);
+    sign.kind = "sign".into();
+    map.push_objects(ObjectLayer {
+        name: "doors".into(),
+        objects: vec![sign, door(&[("map", "a"), ("x", "1"), ("y", "2")])],
+    });
+    let warps = warps(&map).unwrap();
+    assert_eq!(warps.len(), 1);
+
+    assert_eq!(find(&warps, Aabb::new(44.0, 40.0, 12.0, 8.0)), None);
+    assert_eq!(find(&warps, Aabb::new(44.0, 41.0, 12.0, 8.0)),
+               Some(&warps[0]));
+    assert_eq!(find(&warps, Aabb::new(48.0, 50.0, 12.0, 8.0)), None);
+}
+
+#[test]
+fn fades_clear_up() {
+    assert_eq!(Transition::Fade.overlay(0.0), Some(255));
+    assert_eq!(Transition::Fade.overlay(FADE_TIME / 2.0), Some(128));
+    assert_eq!(Transition::Fade.overlay(FADE_TIME), None);
+    assert_eq!(Transition::Cut.overlay(0.0), None);
+}